@@ -0,0 +1,5 @@
+ALTER TABLE dialogue_turn
+    ADD COLUMN cost DOUBLE NOT NULL DEFAULT 0,
+    ADD COLUMN created_day VARCHAR(10) NOT NULL DEFAULT '';
+
+CREATE INDEX idx_dialogue_turn_day ON dialogue_turn (created_day, subscription_id);
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS moderation_audit (
+    id BIGINT NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    msg_id BIGINT NOT NULL,
+    user_id VARCHAR(64) NOT NULL,
+    subscription_id VARCHAR(64) NOT NULL,
+    stage VARCHAR(16) NOT NULL,
+    source VARCHAR(16) NOT NULL,
+    reason TEXT NOT NULL,
+    content TEXT NOT NULL,
+    created_time BIGINT NOT NULL
+) DEFAULT CHARSET = utf8mb4;
+
+CREATE INDEX idx_moderation_audit_user ON moderation_audit (subscription_id, user_id, created_time);
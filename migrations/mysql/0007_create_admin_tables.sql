@@ -0,0 +1,29 @@
+CREATE TABLE IF NOT EXISTS user_list (
+    subscription_id VARCHAR(64) NOT NULL,
+    user_id VARCHAR(64) NOT NULL,
+    list VARCHAR(16) NOT NULL,
+    created_time BIGINT NOT NULL,
+    PRIMARY KEY (subscription_id, user_id, list)
+) DEFAULT CHARSET = utf8mb4;
+
+CREATE TABLE IF NOT EXISTS user_session (
+    subscription_id VARCHAR(64) NOT NULL,
+    user_id VARCHAR(64) NOT NULL,
+    session_id VARCHAR(64) NOT NULL,
+    updated_time BIGINT NOT NULL,
+    PRIMARY KEY (subscription_id, user_id)
+) DEFAULT CHARSET = utf8mb4;
+
+CREATE TABLE IF NOT EXISTS persona (
+    subscription_id VARCHAR(64) NOT NULL PRIMARY KEY,
+    prompt TEXT NOT NULL,
+    updated_time BIGINT NOT NULL
+) DEFAULT CHARSET = utf8mb4;
+
+CREATE TABLE IF NOT EXISTS keyword_rule (
+    subscription_id VARCHAR(64) NOT NULL,
+    keyword VARCHAR(255) NOT NULL,
+    reply TEXT NOT NULL,
+    updated_time BIGINT NOT NULL,
+    PRIMARY KEY (subscription_id, keyword)
+) DEFAULT CHARSET = utf8mb4;
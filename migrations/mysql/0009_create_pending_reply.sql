@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS pending_reply (
+    msg_id BIGINT NOT NULL PRIMARY KEY,
+    subscription_id VARCHAR(64) NOT NULL,
+    user_id VARCHAR(64) NOT NULL,
+    content TEXT NOT NULL,
+    created_time BIGINT NOT NULL
+) DEFAULT CHARSET = utf8mb4;
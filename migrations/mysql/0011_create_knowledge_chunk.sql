@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS knowledge_chunk (
+    base VARCHAR(64) NOT NULL,
+    source VARCHAR(255) NOT NULL,
+    chunk_index BIGINT NOT NULL,
+    content TEXT NOT NULL,
+    embedding MEDIUMTEXT NOT NULL,
+    created_time BIGINT NOT NULL,
+    PRIMARY KEY (base, source, chunk_index)
+) DEFAULT CHARSET = utf8mb4;
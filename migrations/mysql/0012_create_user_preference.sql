@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS user_preference (
+    subscription_id VARCHAR(64) NOT NULL,
+    user_id VARCHAR(64) NOT NULL,
+    reply_mode VARCHAR(16) NOT NULL,
+    updated_time BIGINT NOT NULL,
+    PRIMARY KEY (subscription_id, user_id)
+) DEFAULT CHARSET = utf8mb4;
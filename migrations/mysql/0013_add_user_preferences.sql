@@ -0,0 +1,6 @@
+ALTER TABLE user_preference
+    ADD COLUMN persona TEXT,
+    ADD COLUMN model VARCHAR(64),
+    ADD COLUMN language VARCHAR(32),
+    ADD COLUMN verbosity VARCHAR(16) NOT NULL DEFAULT 'normal',
+    ADD COLUMN history_opt_out BIGINT NOT NULL DEFAULT 0;
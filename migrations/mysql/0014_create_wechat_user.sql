@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS wechat_user (
+    subscription_id VARCHAR(64) NOT NULL,
+    user_id VARCHAR(64) NOT NULL,
+    nickname VARCHAR(255) NOT NULL,
+    language VARCHAR(32) NOT NULL,
+    subscribe_time BIGINT NOT NULL,
+    updated_time BIGINT NOT NULL,
+    PRIMARY KEY (subscription_id, user_id)
+) DEFAULT CHARSET = utf8mb4;
@@ -0,0 +1 @@
+ALTER TABLE pending_reply DROP PRIMARY KEY, ADD PRIMARY KEY (subscription_id, user_id, msg_id);
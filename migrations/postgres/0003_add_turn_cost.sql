@@ -0,0 +1,5 @@
+ALTER TABLE dialogue_turn ADD COLUMN cost DOUBLE PRECISION NOT NULL DEFAULT 0;
+
+ALTER TABLE dialogue_turn ADD COLUMN created_day VARCHAR(10) NOT NULL DEFAULT '';
+
+CREATE INDEX IF NOT EXISTS idx_dialogue_turn_day ON dialogue_turn (created_day, subscription_id);
@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS user_tier (
+    subscription_id VARCHAR(64) NOT NULL,
+    user_id VARCHAR(64) NOT NULL,
+    tier VARCHAR(32) NOT NULL,
+    updated_time BIGINT NOT NULL,
+    PRIMARY KEY (subscription_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS daily_usage (
+    subscription_id VARCHAR(64) NOT NULL,
+    user_id VARCHAR(64) NOT NULL,
+    day VARCHAR(10) NOT NULL,
+    messages BIGINT NOT NULL DEFAULT 0,
+    tokens BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (subscription_id, user_id, day)
+);
+
+CREATE INDEX IF NOT EXISTS idx_daily_usage_day ON daily_usage (subscription_id, day);
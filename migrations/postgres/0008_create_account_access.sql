@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS account_access (
+    subscription_id VARCHAR(64) NOT NULL PRIMARY KEY,
+    mode VARCHAR(16) NOT NULL,
+    updated_time BIGINT NOT NULL
+);
@@ -0,0 +1,9 @@
+ALTER TABLE user_preference ADD COLUMN persona TEXT;
+
+ALTER TABLE user_preference ADD COLUMN model VARCHAR(64);
+
+ALTER TABLE user_preference ADD COLUMN language VARCHAR(32);
+
+ALTER TABLE user_preference ADD COLUMN verbosity VARCHAR(16) NOT NULL DEFAULT 'normal';
+
+ALTER TABLE user_preference ADD COLUMN history_opt_out BIGINT NOT NULL DEFAULT 0;
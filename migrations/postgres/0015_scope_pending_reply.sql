@@ -0,0 +1,3 @@
+ALTER TABLE pending_reply DROP CONSTRAINT pending_reply_pkey;
+
+ALTER TABLE pending_reply ADD PRIMARY KEY (subscription_id, user_id, msg_id);
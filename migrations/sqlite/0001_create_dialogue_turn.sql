@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS dialogue_turn (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    msg_id BIGINT NOT NULL,
+    user_id VARCHAR(64) NOT NULL,
+    subscription_id VARCHAR(64) NOT NULL,
+    session_id VARCHAR(64) NOT NULL DEFAULT '',
+    model VARCHAR(64) NOT NULL DEFAULT '',
+    user_message TEXT NOT NULL,
+    reply_message TEXT NOT NULL,
+    prompt_tokens BIGINT NOT NULL DEFAULT 0,
+    completion_tokens BIGINT NOT NULL DEFAULT 0,
+    total_tokens BIGINT NOT NULL DEFAULT 0,
+    status VARCHAR(16) NOT NULL DEFAULT 'ok',
+    error TEXT NULL,
+    elapsed BIGINT NOT NULL DEFAULT 0,
+    created_time BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_dialogue_turn_user ON dialogue_turn (user_id, subscription_id, created_time);
+
+CREATE INDEX IF NOT EXISTS idx_dialogue_turn_msg_id ON dialogue_turn (msg_id);
@@ -0,0 +1 @@
+ALTER TABLE pending_reply ADD COLUMN account VARCHAR(64) NOT NULL DEFAULT 'default';
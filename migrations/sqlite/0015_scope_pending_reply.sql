@@ -0,0 +1,16 @@
+CREATE TABLE pending_reply_scoped (
+    msg_id BIGINT NOT NULL,
+    subscription_id VARCHAR(64) NOT NULL,
+    user_id VARCHAR(64) NOT NULL,
+    content TEXT NOT NULL,
+    created_time BIGINT NOT NULL,
+    account VARCHAR(64) NOT NULL DEFAULT 'default',
+    PRIMARY KEY (subscription_id, user_id, msg_id)
+);
+
+INSERT INTO pending_reply_scoped(msg_id, subscription_id, user_id, content, created_time, account)
+SELECT msg_id, subscription_id, user_id, content, created_time, account FROM pending_reply;
+
+DROP TABLE pending_reply;
+
+ALTER TABLE pending_reply_scoped RENAME TO pending_reply;
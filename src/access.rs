@@ -0,0 +1,78 @@
+use crate::{
+    database::{ConversationStore, UserList},
+    error::Result,
+    settings::{Access, AccessMode},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Admitted,
+    Blocked,
+    NotAllowed,
+}
+
+/// Runs a follower through the blocklist and, for accounts in allowlist
+/// mode, the allowlist. `admins`, those of the account, are always let in so
+/// they can manage both.
+pub async fn admit(
+    store: &dyn ConversationStore,
+    access: &Access,
+    admins: &[String],
+    subscription_id: &str,
+    user_id: &str,
+) -> Result<Admission> {
+    if admins.iter().any(|admin| admin == user_id) {
+        return Ok(Admission::Admitted);
+    }
+    if store
+        .is_in_user_list(UserList::Blocked, subscription_id, user_id)
+        .await?
+    {
+        return Ok(Admission::Blocked);
+    }
+    let mode = store
+        .get_access_mode(subscription_id)
+        .await?
+        .unwrap_or(access.mode);
+    if mode == AccessMode::Allowlist
+        && !store
+            .is_in_user_list(UserList::Allowed, subscription_id, user_id)
+            .await?
+    {
+        return Ok(Admission::NotAllowed);
+    }
+    Ok(Admission::Admitted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::memory::MemoryStore;
+
+    #[tokio::test]
+    async fn test_admit() {
+        let store = MemoryStore::new(8);
+        let access = Access::default();
+        let admins = ["admin".to_string()];
+        let admit = |user: &'static str| admit(&store, &access, &admins, "sub", user);
+
+        assert_eq!(admit("user").await.unwrap(), Admission::Admitted);
+        store
+            .add_to_user_list(UserList::Blocked, "sub", "user")
+            .await
+            .unwrap();
+        assert_eq!(admit("user").await.unwrap(), Admission::Blocked);
+
+        store
+            .set_access_mode("sub", AccessMode::Allowlist)
+            .await
+            .unwrap();
+        assert_eq!(admit("friend").await.unwrap(), Admission::NotAllowed);
+        assert_eq!(admit("admin").await.unwrap(), Admission::Admitted);
+        store
+            .add_to_user_list(UserList::Allowed, "sub", "friend")
+            .await
+            .unwrap();
+        assert_eq!(admit("friend").await.unwrap(), Admission::Admitted);
+    }
+}
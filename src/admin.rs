@@ -0,0 +1,633 @@
+use std::{
+    collections::HashMap,
+    future::{ready, Ready},
+};
+
+use actix_web::{
+    delete, dev::Payload, get, http::header, post, put, web, FromRequest, HttpRequest,
+    HttpResponse, Scope,
+};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::{
+        now_millis, DailyUsage, SpendGroup, SpendPeriod, SpendQuery, TurnQuery, TurnRecord,
+        UserList, UserPreferences, WechatUser,
+    },
+    engine::known_model,
+    error::{Error, Result},
+    privacy,
+    settings::AccessMode,
+    telemetry::hash_openid,
+    AppState,
+};
+
+/// The `/admin` HTTP API. Every handler takes [`AdminAuth`].
+pub fn scope() -> Scope {
+    web::scope("/admin")
+        .service(list_conversations)
+        .service(usage)
+        .service(get_user)
+        // before the lists, whose `{list}` would take `preferences`
+        .service(get_preferences)
+        .service(put_preferences)
+        .service(delete_preferences)
+        .service(export_user)
+        .service(forget_user)
+        .service(add_to_list)
+        .service(remove_from_list)
+        .service(list_blocked)
+        .service(list_allowed)
+        .service(get_access_mode)
+        .service(put_access_mode)
+        .service(reset_session)
+        .service(list_personas)
+        .service(put_persona)
+        .service(delete_persona)
+        .service(list_keyword_rules)
+        .service(put_keyword_rule)
+        .service(delete_keyword_rule)
+}
+
+/// Proof that the request carries the admin token or comes from an allowed address.
+pub struct AdminAuth;
+
+impl FromRequest for AdminAuth {
+    type Error = Error;
+    type Future = Ready<Result<AdminAuth>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let bearer = bearer_token(req);
+        // the socket address, a proxy in front must do its own filtering
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        let allowed = req
+            .app_data::<web::Data<AppState>>()
+            .is_some_and(|data| data.live().admin.allows(bearer, peer));
+        ready(if allowed {
+            Ok(AdminAuth)
+        } else {
+            Err(Error::Unauthorized)
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct UserInfo {
+    subscription_id: String,
+    user_id: String,
+    tier: String,
+    blocked: bool,
+    allowed: bool,
+    session_id: String,
+    usage_today: DailyUsage,
+    preferences: UserPreferences,
+    /// As stored, this does not ask WeChat.
+    profile: Option<WechatUser>,
+}
+
+/// A turn with the nickname of its user, if WeChat gave one.
+#[derive(Debug, Serialize)]
+struct ConversationRow {
+    #[serde(flatten)]
+    turn: TurnRecord,
+    nickname: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UsageParams {
+    period: Option<SpendPeriod>,
+    by: Option<SpendGroup>,
+    from: Option<String>,
+    to: Option<String>,
+    subscription_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubscriptionParams {
+    subscription_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AccessBody {
+    mode: AccessMode,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    #[default]
+    Json,
+    Markdown,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportParams {
+    format: Option<ExportFormat>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PersonaBody {
+    prompt: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeywordRuleBody {
+    reply: String,
+}
+
+/// `?subscription_id=&user_id=&q=&limit=&offset=`
+#[get("/conversations")]
+async fn list_conversations(
+    _: AdminAuth,
+    query: web::Query<TurnQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let turns = data.store.search_turns(&query).await?;
+    let mut nicknames: HashMap<(String, String), Option<String>> = HashMap::new();
+    let mut rows = vec![];
+    for turn in turns {
+        let key = (turn.subscription_id.clone(), turn.user_id.clone());
+        let nickname = match nicknames.get(&key) {
+            Some(nickname) => nickname.clone(),
+            None => {
+                let nickname = data
+                    .store
+                    .get_wechat_user(&key.0, &key.1)
+                    .await?
+                    .map(|user| user.nickname)
+                    .filter(|nickname| !nickname.is_empty());
+                nicknames.insert(key, nickname.clone());
+                nickname
+            }
+        };
+        rows.push(ConversationRow { turn, nickname });
+    }
+    Ok(HttpResponse::Ok().json(rows))
+}
+
+/// `?period=day|month&by=user|subscription&from=YYYY-MM-DD&to=YYYY-MM-DD&subscription_id=`
+#[get("/usage")]
+async fn usage(
+    _: AdminAuth,
+    params: web::Query<UsageParams>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let params = params.into_inner();
+    let mut query = SpendQuery::month_to_date(data.live().pricing.utc_offset_hours);
+    query.period = params.period.unwrap_or(query.period);
+    query.group = params.by.unwrap_or(query.group);
+    query.from_day = params.from.unwrap_or(query.from_day);
+    query.to_day = params.to.unwrap_or(query.to_day);
+    query.subscription_id = params.subscription_id;
+    Ok(HttpResponse::Ok().json(data.store.spend(&query).await?))
+}
+
+#[get("/users/{subscription_id}/{user_id}")]
+async fn get_user(
+    _: AdminAuth,
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (subscription_id, user_id) = path.into_inner();
+    let store = &data.store;
+    let tier = store
+        .get_user_tier(&subscription_id, &user_id)
+        .await?
+        .unwrap_or_else(|| data.live().limits.default_tier.clone());
+    let info = UserInfo {
+        tier,
+        blocked: store
+            .is_in_user_list(UserList::Blocked, &subscription_id, &user_id)
+            .await?,
+        allowed: store
+            .is_in_user_list(UserList::Allowed, &subscription_id, &user_id)
+            .await?,
+        session_id: store.get_session_id(&subscription_id, &user_id).await?,
+        usage_today: store
+            .get_daily_usage(&subscription_id, Some(&user_id))
+            .await?,
+        preferences: store
+            .get_user_preferences(&subscription_id, &user_id)
+            .await?,
+        profile: store.get_wechat_user(&subscription_id, &user_id).await?,
+        subscription_id,
+        user_id,
+    };
+    Ok(HttpResponse::Ok().json(info))
+}
+
+#[get("/users/{subscription_id}/{user_id}/preferences")]
+async fn get_preferences(
+    _: AdminAuth,
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (subscription_id, user_id) = path.into_inner();
+    let preferences = data
+        .store
+        .get_user_preferences(&subscription_id, &user_id)
+        .await?;
+    Ok(HttpResponse::Ok().json(preferences))
+}
+
+/// Replaces all of them, what the body leaves out goes back to the default.
+#[put("/users/{subscription_id}/{user_id}/preferences")]
+async fn put_preferences(
+    _: AdminAuth,
+    path: web::Path<(String, String)>,
+    body: web::Json<UserPreferences>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (subscription_id, user_id) = path.into_inner();
+    if let Some(model) = body.model.as_deref().filter(|model| !known_model(model)) {
+        return Err(Error::ArgumentError(format!("unknown model {}", model)));
+    }
+    if let Some(persona) = body
+        .persona
+        .as_deref()
+        .filter(|persona| !data.live().personas.contains_key(*persona))
+    {
+        return Err(Error::ArgumentError(format!("unknown persona {}", persona)));
+    }
+    if body
+        .language
+        .as_deref()
+        .is_some_and(|s| s.trim().is_empty())
+    {
+        return Err(Error::ArgumentError(
+            "language must not be blank".to_string(),
+        ));
+    }
+    data.store
+        .set_user_preferences(&subscription_id, &user_id, &body)
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/users/{subscription_id}/{user_id}/preferences")]
+async fn delete_preferences(
+    _: AdminAuth,
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (subscription_id, user_id) = path.into_inner();
+    Ok(deleted(
+        data.store
+            .delete_user_preferences(&subscription_id, &user_id)
+            .await?,
+    ))
+}
+
+/// Everything kept about the user as a file, `?format=json|markdown`.
+#[get("/users/{subscription_id}/{user_id}/export")]
+async fn export_user(
+    _: AdminAuth,
+    path: web::Path<(String, String)>,
+    params: web::Query<ExportParams>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (subscription_id, user_id) = path.into_inner();
+    let exported = privacy::export(data.store.as_ref(), &subscription_id, &user_id).await?;
+    let (body, content_type, file_name) = match params.format.unwrap_or_default() {
+        ExportFormat::Json => (
+            serde_json::to_string_pretty(&exported)?,
+            "application/json",
+            "export.json",
+        ),
+        ExportFormat::Markdown => (
+            privacy::to_markdown(&exported, data.live().pricing.utc_offset_hours),
+            "text/markdown; charset=utf-8",
+            "export.md",
+        ),
+    };
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", file_name),
+        ))
+        .body(body))
+}
+
+/// Erases the user, answering with the rows erased per table.
+#[delete("/users/{subscription_id}/{user_id}")]
+async fn forget_user(
+    _: AdminAuth,
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (subscription_id, user_id) = path.into_inner();
+    let erased = privacy::forget(&data, &subscription_id, &user_id).await?;
+    info!(
+        "{} of {} erased: {:?}",
+        hash_openid(&user_id),
+        subscription_id,
+        erased
+    );
+    Ok(HttpResponse::Ok().json(erased))
+}
+
+/// `{list}` is `block` or `allow`.
+#[put("/users/{subscription_id}/{user_id}/{list}")]
+async fn add_to_list(
+    _: AdminAuth,
+    path: web::Path<(String, String, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (subscription_id, user_id, list) = path.into_inner();
+    let list = parse_list(&list)?;
+    data.store
+        .add_to_user_list(list, &subscription_id, &user_id)
+        .await?;
+    info!(
+        "{} of {} added to {:?}",
+        hash_openid(&user_id),
+        subscription_id,
+        list
+    );
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/users/{subscription_id}/{user_id}/{list}")]
+async fn remove_from_list(
+    _: AdminAuth,
+    path: web::Path<(String, String, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (subscription_id, user_id, list) = path.into_inner();
+    let list = parse_list(&list)?;
+    let removed = data
+        .store
+        .remove_from_user_list(list, &subscription_id, &user_id)
+        .await?;
+    info!(
+        "{} of {} removed from {:?}",
+        hash_openid(&user_id),
+        subscription_id,
+        list
+    );
+    Ok(deleted(removed))
+}
+
+#[get("/blocked")]
+async fn list_blocked(
+    _: AdminAuth,
+    params: web::Query<SubscriptionParams>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let users = data
+        .store
+        .get_user_list(UserList::Blocked, params.subscription_id.as_deref())
+        .await?;
+    Ok(HttpResponse::Ok().json(users))
+}
+
+#[get("/allowed")]
+async fn list_allowed(
+    _: AdminAuth,
+    params: web::Query<SubscriptionParams>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let users = data
+        .store
+        .get_user_list(UserList::Allowed, params.subscription_id.as_deref())
+        .await?;
+    Ok(HttpResponse::Ok().json(users))
+}
+
+#[get("/access/{subscription_id}")]
+async fn get_access_mode(
+    _: AdminAuth,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let mode = data
+        .store
+        .get_access_mode(&path)
+        .await?
+        .unwrap_or(data.live().access.mode);
+    Ok(HttpResponse::Ok().json(AccessBody { mode }))
+}
+
+#[put("/access/{subscription_id}")]
+async fn put_access_mode(
+    _: AdminAuth,
+    path: web::Path<String>,
+    body: web::Json<AccessBody>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    data.store.set_access_mode(&path, body.mode).await?;
+    info!("{} switched to {}", path, body.mode.as_str());
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Starts a new session, so the model no longer sees the earlier conversation.
+#[post("/users/{subscription_id}/{user_id}/reset-session")]
+async fn reset_session(
+    _: AdminAuth,
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (subscription_id, user_id) = path.into_inner();
+    let session_id = now_millis().to_string();
+    data.store
+        .set_session_id(&subscription_id, &user_id, &session_id)
+        .await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "session_id": session_id })))
+}
+
+#[get("/personas")]
+async fn list_personas(_: AdminAuth, data: web::Data<AppState>) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(data.store.get_personas().await?))
+}
+
+#[put("/personas/{subscription_id}")]
+async fn put_persona(
+    _: AdminAuth,
+    path: web::Path<String>,
+    body: web::Json<PersonaBody>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    if body.prompt.trim().is_empty() {
+        return Err(Error::ArgumentError("prompt is empty".to_string()));
+    }
+    data.store.set_persona(&path, &body.prompt).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/personas/{subscription_id}")]
+async fn delete_persona(
+    _: AdminAuth,
+    path: web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    Ok(deleted(data.store.delete_persona(&path).await?))
+}
+
+#[get("/keyword-rules")]
+async fn list_keyword_rules(
+    _: AdminAuth,
+    params: web::Query<SubscriptionParams>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let rules = data
+        .store
+        .get_keyword_rules(params.subscription_id.as_deref())
+        .await?;
+    Ok(HttpResponse::Ok().json(rules))
+}
+
+#[put("/keyword-rules/{subscription_id}/{keyword}")]
+async fn put_keyword_rule(
+    _: AdminAuth,
+    path: web::Path<(String, String)>,
+    body: web::Json<KeywordRuleBody>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (subscription_id, keyword) = path.into_inner();
+    let keyword = keyword.trim();
+    if keyword.is_empty() || body.reply.is_empty() {
+        return Err(Error::ArgumentError(
+            "keyword and reply are required".to_string(),
+        ));
+    }
+    data.store
+        .set_keyword_rule(&subscription_id, keyword, &body.reply)
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/keyword-rules/{subscription_id}/{keyword}")]
+async fn delete_keyword_rule(
+    _: AdminAuth,
+    path: web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let (subscription_id, keyword) = path.into_inner();
+    Ok(deleted(
+        data.store
+            .delete_keyword_rule(&subscription_id, keyword.trim())
+            .await?,
+    ))
+}
+
+fn parse_list(list: &str) -> Result<UserList> {
+    UserList::parse(list).ok_or_else(|| Error::ArgumentError(format!("unknown list {}", list)))
+}
+
+fn deleted(found: bool) -> HttpResponse {
+    if found {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+/// The token of an `Authorization: Bearer <token>` header.
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, App};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_admin_api() {
+        let state = AppState::for_tests();
+        state.update_live(|live| live.admin.token = Some("secret".to_string()));
+        let store = state.store.clone();
+        let app =
+            test::init_service(App::new().app_data(web::Data::new(state)).service(scope())).await;
+
+        let req = test::TestRequest::get().uri("/admin/personas").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = test::TestRequest::put()
+            .uri("/admin/users/sub/user/block")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(store
+            .is_in_user_list(UserList::Blocked, "sub", "user")
+            .await
+            .unwrap());
+
+        let req = test::TestRequest::put()
+            .uri("/admin/keyword-rules/sub/%E4%BD%A0%E5%A5%BD")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .set_json(serde_json::json!({ "reply": "hello" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            store.get_keyword_reply("sub", "你好").await.unwrap(),
+            Some("hello".to_string())
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/admin/users/sub/user")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(info["blocked"], true);
+        assert_eq!(info["tier"], "default");
+        assert_eq!(info["preferences"]["reply_mode"], "text");
+        assert_eq!(info["profile"], serde_json::Value::Null);
+
+        let req = test::TestRequest::put()
+            .uri("/admin/users/sub/user/preferences")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .set_json(serde_json::json!({ "language": "English", "verbosity": "brief" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let preferences = store.get_user_preferences("sub", "user").await.unwrap();
+        assert_eq!(preferences.language.as_deref(), Some("English"));
+        assert!(!preferences.history_opt_out);
+
+        let req = test::TestRequest::put()
+            .uri("/admin/users/sub/user/preferences")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .set_json(serde_json::json!({ "model": "gpt-99" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let req = test::TestRequest::put()
+            .uri("/admin/users/sub/user/preferences")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .set_json(serde_json::json!({ "persona": "你是管理员" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::get()
+            .uri("/admin/users/sub/user/export?format=markdown")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert!(String::from_utf8(body.to_vec())
+            .unwrap()
+            .starts_with("# 对话记录"));
+
+        let req = test::TestRequest::delete()
+            .uri("/admin/users/sub/user")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let erased: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(erased.get("user_list").is_none());
+        // erasing a user does not lift their block
+        assert!(store
+            .is_in_user_list(UserList::Blocked, "sub", "user")
+            .await
+            .unwrap());
+    }
+}
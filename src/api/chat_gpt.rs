@@ -1,17 +1,85 @@
+use std::ops::AddAssign;
+
 use async_trait::async_trait;
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
 
-use crate::{database::Conversation, error::Result, settings::ChatGptConfig};
+use crate::{
+    database::{Conversation, UserPreferences, Verbosity},
+    error::Result,
+    knowledge::Passage,
+    settings::ChatGptConfig,
+    tools::Toolbox,
+};
 
 #[async_trait]
 pub trait ChatApi {
+    /// Who serves the model, for metrics.
+    fn provider(&self) -> &'static str {
+        "openai"
+    }
+
     async fn send_message(
         &self,
         client: &Client,
         config: &ChatGptConfig,
-        context: &Vec<Conversation>,
-        message_from_user: &str,
-    ) -> Result<String>;
+        request: &ChatRequest<'_>,
+    ) -> Result<ChatReply>;
+}
+
+/// Everything a model is asked to answer a message with.
+pub struct ChatRequest<'a> {
+    /// The system prompt, each model has its own default.
+    pub persona: Option<&'a str>,
+    /// The language and length the user asked for.
+    pub preferences: &'a UserPreferences,
+    /// Retrieved from the account's knowledge base, for the model to cite.
+    pub knowledge: &'a [Passage],
+    /// Offered to the model, which may call them before it answers.
+    pub tools: Option<&'a Toolbox<'a>>,
+    pub context: &'a [Conversation],
+    pub message_from_user: &'a str,
+}
+
+/// What the system prompt has to add for the user's `language` and
+/// `verbosity`, `None` when they left both alone.
+pub fn style_instructions(preferences: &UserPreferences) -> Option<String> {
+    let mut instructions = String::new();
+    if let Some(language) = &preferences.language {
+        instructions.push_str(&format!("请用{}回答。", language));
+    }
+    match preferences.verbosity {
+        Verbosity::Brief => instructions.push_str("回答尽量简短，几句话以内。"),
+        Verbosity::Normal => {}
+        Verbosity::Detailed => instructions.push_str("回答尽量详细，把细节和理由讲清楚。"),
+    }
+    Some(instructions).filter(|s| !s.is_empty())
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatReply {
+    pub content: String,
+    /// The model that actually answered, e.g. `gpt-3.5-turbo-0301`.
+    pub model: String,
+    pub usage: TokenUsage,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenUsage {
+    #[serde(default)]
+    pub prompt_tokens: i64,
+    #[serde(default)]
+    pub completion_tokens: i64,
+    #[serde(default)]
+    pub total_tokens: i64,
+}
+
+impl AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: TokenUsage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
 }
 
 #[cfg(test)]
@@ -34,7 +102,18 @@ mod tests {
         let message_from_user = "Hi, there!";
 
         let result = api
-            .send_message(&client, &config, &context, message_from_user)
+            .send_message(
+                &client,
+                &config,
+                &ChatRequest {
+                    persona: None,
+                    preferences: &UserPreferences::default(),
+                    knowledge: &[],
+                    tools: None,
+                    context: &context,
+                    message_from_user,
+                },
+            )
             .await;
 
         // Check if the result is a string
@@ -4,11 +4,20 @@ use Debug;
 use async_trait::async_trait;
 use log::debug;
 use reqwest::Client;
+use serde_json::Value;
+use tracing::{info_span, Instrument};
 
-use crate::{database::Conversation, error::Result, settings::ChatGptConfig};
+use crate::{
+    database::{Conversation, UserPreferences},
+    error::{Error, Result},
+    knowledge::{reference_text, Passage},
+    settings::ChatGptConfig,
+    telemetry::redact,
+    tools::Toolbox,
+};
 use serde::{Deserialize, Serialize};
 
-use super::chat_gpt::ChatApi;
+use super::chat_gpt::{style_instructions, ChatApi, ChatReply, ChatRequest, TokenUsage};
 
 #[derive(Debug, Deserialize, Serialize)]
 struct ChatCompletion {
@@ -16,17 +25,10 @@ struct ChatCompletion {
     object: String,
     created: i64,
     model: String,
-    usage: Usage,
+    usage: TokenUsage,
     choices: Vec<Choice>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Usage {
-    prompt_tokens: i64,
-    completion_tokens: i64,
-    total_tokens: i64,
-}
-
 #[derive(Debug, Deserialize, Serialize)]
 struct Choice {
     message: Message,
@@ -37,7 +39,39 @@ struct Choice {
 #[derive(Debug, Deserialize, Serialize)]
 struct Message {
     role: String,
-    content: String,
+    /// `null` when the model calls tools instead of answering.
+    content: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    /// The call a `tool` message answers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+}
+
+impl Message {
+    fn new(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: Some(content.to_string()),
+            tool_calls: vec![],
+            tool_call_id: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ToolCall {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    function: FunctionCall,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct FunctionCall {
+    name: String,
+    /// JSON, as the model wrote it.
+    arguments: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -45,6 +79,8 @@ struct Request {
     model: String,
     // top_p: f32,
     messages: Vec<Message>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Value>,
 }
 
 impl Display for Request {
@@ -77,16 +113,13 @@ impl Display for Choice {
             f,
             "{{\n\tmessage: {}\n\tfinish_reason: {:?}\n\tindex: {}\n}}",
             self.message,
-            self.finish_reason
-                .as_ref()
-                .map(|s| s.as_str())
-                .unwrap_or("<None>"),
+            self.finish_reason.as_deref().unwrap_or("<None>"),
             self.index
         )
     }
 }
 
-impl Display for Usage {
+impl Display for TokenUsage {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         write!(
             f,
@@ -98,7 +131,20 @@ impl Display for Usage {
 
 impl Display for Message {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        write!(f, "role: {}\ncontent: {}", self.role, self.content)
+        write!(
+            f,
+            "role: {}\ncontent: {}",
+            self.role,
+            self.content.as_deref().unwrap_or("<None>")
+        )?;
+        for call in &self.tool_calls {
+            write!(
+                f,
+                "\ntool_call: {}({})",
+                call.function.name, call.function.arguments
+            )?;
+        }
+        Ok(())
     }
 }
 const URL: &str = "https://api.openai.com/v1/chat/completions";
@@ -108,6 +154,7 @@ const PREP_PROMPT: &str =
 const ROLE_USER: &str = "user";
 const ROLE_SYSTEM: &str = "system";
 const ROLE_ASSISTANT: &str = "assistant";
+const ROLE_TOOL: &str = "tool";
 
 pub struct ChatGpt35Turbo;
 
@@ -117,38 +164,88 @@ impl ChatApi for ChatGpt35Turbo {
         &self,
         client: &Client,
         config: &ChatGptConfig,
-        context: &Vec<Conversation>,
-        message_from_user: &str,
-    ) -> Result<String> {
-        let messages = create_full_message(context, message_from_user);
-
-        let request = Request {
-            model: MODEL.to_string(),
-            // top_p: 1.0,
-            messages: messages,
-        };
-
-        debug!("request is {}", &request);
-        let body = serde_json::to_string(&request)?;
-        let response = client
-            .post(URL)
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("Bearer {}", config.api))
-            .body(body)
-            .send()
-            .await?;
-        let text = &response.text().await?; // 获取响应文本
-        debug!("response text: {}", &text);
-
-        let response = serde_json::from_str::<ChatCompletion>(&text)?;
-        let content = response.choices[0].message.content.clone();
-        debug!("response is {}", &response);
-        Ok(content)
+        request: &ChatRequest<'_>,
+    ) -> Result<ChatReply> {
+        let mut messages = create_full_message(
+            request.persona.unwrap_or(PREP_PROMPT),
+            request.preferences,
+            request.knowledge,
+            request.context,
+            request.message_from_user,
+        );
+        // every round is billed
+        let mut usage = TokenUsage::default();
+        let mut round = 1;
+        loop {
+            // the last round has to answer
+            let tools = request
+                .tools
+                .filter(|tools| !tools.is_empty() && round < tools.max_rounds);
+            let body = Request {
+                model: MODEL.to_string(),
+                // top_p: 1.0,
+                messages,
+                tools: tools.map(Toolbox::definitions).unwrap_or_default(),
+            };
+
+            debug!("request is {}", redact(&body));
+            let response = client
+                .post(URL)
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", config.api))
+                .body(serde_json::to_string(&body)?)
+                .send()
+                .await?;
+            let text = &response.text().await?; // 获取响应文本
+            debug!("response text: {}", redact(text));
+
+            let mut response = serde_json::from_str::<ChatCompletion>(text)?;
+            debug!("response is {}", redact(&response));
+            usage += response.usage;
+            if response.choices.is_empty() {
+                return Err(Error::ArgumentError(
+                    "the model answered without choices".to_string(),
+                ));
+            }
+            let message = response.choices.swap_remove(0).message;
+            let tools = match tools {
+                Some(tools) if !message.tool_calls.is_empty() => tools,
+                _ => {
+                    return Ok(ChatReply {
+                        content: message.content.unwrap_or_default(),
+                        model: response.model,
+                        usage,
+                    })
+                }
+            };
+
+            messages = body.messages;
+            let mut results = vec![];
+            for call in &message.tool_calls {
+                let result = tools
+                    .call(&call.function.name, &call.function.arguments)
+                    .instrument(info_span!("tool", name = %call.function.name))
+                    .await;
+                results.push(Message {
+                    tool_call_id: Some(call.id.clone()),
+                    ..Message::new(ROLE_TOOL, &result)
+                });
+            }
+            messages.push(message);
+            messages.extend(results);
+            round += 1;
+        }
     }
 }
 
-fn create_full_message(context: &Vec<Conversation>, message_from_user: &str) -> Vec<Message> {
-    let system = get_system_messages();
+fn create_full_message(
+    persona: &str,
+    preferences: &UserPreferences,
+    knowledge: &[Passage],
+    context: &[Conversation],
+    message_from_user: &str,
+) -> Vec<Message> {
+    let system = get_system_messages(persona, preferences, knowledge);
 
     let content = get_content_messages(context);
 
@@ -161,38 +258,37 @@ fn create_full_message(context: &Vec<Conversation>, message_from_user: &str) ->
     merged_vec
 }
 
-fn get_system_messages() -> Vec<Message> {
-    vec![Message {
-        role: ROLE_SYSTEM.to_string(),
-        content: PREP_PROMPT.to_string(),
-    }]
+fn get_system_messages(
+    persona: &str,
+    preferences: &UserPreferences,
+    knowledge: &[Passage],
+) -> Vec<Message> {
+    let mut messages = vec![Message::new(ROLE_SYSTEM, persona)];
+    if let Some(style) = style_instructions(preferences) {
+        messages.push(Message::new(ROLE_SYSTEM, &style));
+    }
+    if !knowledge.is_empty() {
+        messages.push(Message::new(ROLE_SYSTEM, &reference_text(knowledge)));
+    }
+    messages
 }
 
-fn get_content_messages(context: &Vec<Conversation>) -> Vec<Message> {
+fn get_content_messages(context: &[Conversation]) -> Vec<Message> {
     convert2prompts(context)
 }
 
 fn get_user_new_message(message_from_user: &str) -> Vec<Message> {
-    let new_message = Message {
-        role: ROLE_USER.to_string(),
-        content: message_from_user.to_string(),
-    };
+    let new_message = Message::new(ROLE_USER, message_from_user);
     vec![new_message]
 }
 
-fn convert2prompts(context: &Vec<Conversation>) -> Vec<Message> {
-    context.iter().flat_map(|c| convert2prompt(c)).collect()
+fn convert2prompts(context: &[Conversation]) -> Vec<Message> {
+    context.iter().flat_map(convert2prompt).collect()
 }
 
 fn convert2prompt(context: &Conversation) -> Vec<Message> {
-    let user_msg = Message {
-        role: ROLE_USER.to_string(),
-        content: context.req_message.to_string(),
-    };
-    let assistant_msg = Message {
-        role: ROLE_ASSISTANT.to_string(),
-        content: context.resp_message.to_string(),
-    };
+    let user_msg = Message::new(ROLE_USER, &context.req_message);
+    let assistant_msg = Message::new(ROLE_ASSISTANT, &context.resp_message);
     vec![user_msg, assistant_msg]
 }
 
@@ -203,5 +299,49 @@ fn test_chat_completion_from_json() {
     assert_eq!(chat_completion.id, "chatcmpl-6rQVJZd1SxFrmOTEg6b4MEWT7w2eF");
     assert_eq!(chat_completion.object, "chat.completion");
     assert_eq!(chat_completion.created, 1678191285);
+    assert_eq!(chat_completion.usage.total_tokens, 783);
     // 验证其他字段...
 }
+
+#[test]
+fn test_tool_calls_from_json() {
+    let json_str = r#"{"id":"chatcmpl-1","object":"chat.completion","created":1700000000,"model":"gpt-3.5-turbo-1106","usage":{"prompt_tokens":80,"completion_tokens":18,"total_tokens":98},"choices":[{"message":{"role":"assistant","content":null,"tool_calls":[{"id":"call_1","type":"function","function":{"name":"calculator","arguments":"{\"expression\":\"6*7\"}"}}]},"finish_reason":"tool_calls","index":0}]}"#;
+    let chat_completion = serde_json::from_str::<ChatCompletion>(json_str).unwrap();
+    let message = &chat_completion.choices[0].message;
+    assert_eq!(message.content, None);
+    assert_eq!(message.tool_calls[0].function.name, "calculator");
+
+    // sent back as it came, followed by the result
+    let result = Message {
+        tool_call_id: Some("call_1".to_string()),
+        ..Message::new(ROLE_TOOL, "42")
+    };
+    let json = serde_json::to_value(&result).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({"role": "tool", "content": "42", "tool_call_id": "call_1"})
+    );
+}
+
+#[test]
+fn test_create_full_message_with_preferences() {
+    let context = vec![Conversation {
+        req_message: "hi".to_string(),
+        resp_message: "hello".to_string(),
+    }];
+    let messages = create_full_message("persona", &UserPreferences::default(), &[], &context, "?");
+    let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+    assert_eq!(roles, [ROLE_SYSTEM, ROLE_USER, ROLE_ASSISTANT, ROLE_USER]);
+
+    let preferences = UserPreferences {
+        language: Some("English".to_string()),
+        verbosity: crate::database::Verbosity::Brief,
+        ..Default::default()
+    };
+    let messages = create_full_message("persona", &preferences, &[], &context, "?");
+    assert_eq!(messages[1].role, ROLE_SYSTEM);
+    assert_eq!(
+        messages[1].content.as_deref(),
+        Some("请用English回答。回答尽量简短，几句话以内。")
+    );
+}
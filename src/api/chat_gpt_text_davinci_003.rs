@@ -1,13 +1,19 @@
 use std::fmt::{self, Debug};
 
 use async_trait::async_trait;
-use log::{debug, info};
+use log::debug;
 use reqwest::Client;
 
-use crate::{database::Conversation, error::Result, settings::ChatGptConfig};
+use crate::{
+    database::Conversation,
+    error::{Error, Result},
+    knowledge::reference_text,
+    settings::ChatGptConfig,
+    telemetry::redact,
+};
 use serde::{Deserialize, Serialize};
 
-use super::chat_gpt::ChatApi;
+use super::chat_gpt::{style_instructions, ChatApi, ChatReply, ChatRequest, TokenUsage};
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Choice {
@@ -24,23 +30,23 @@ struct ChatGptResponse {
     created: usize,
     model: String,
     choices: Vec<Choice>,
-    usage: serde_json::Value,
+    usage: TokenUsage,
 }
 
 impl fmt::Display for ChatGptResponse {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut s = format!("ChatGptResponse:\n");
+        let mut s = "ChatGptResponse:\n".to_string();
         s += &format!("  id: {}\n", self.id);
         s += &format!("  object: {}\n", self.object);
         s += &format!("  created: {}\n", self.created);
         s += &format!("  model: {}\n", self.model);
-        s += &format!("  choices:\n");
+        s += "  choices:\n";
 
         for choice in &self.choices {
             s += &format!("    {}\n", choice.text);
         }
 
-        s += &format!("  usage: {}\n", self.usage);
+        s += &format!("  usage: {:?}\n", self.usage);
 
         write!(f, "{}", s)
     }
@@ -61,24 +67,39 @@ impl ChatApi for ChatGptTextDavinci003 {
         &self,
         client: &Client,
         config: &ChatGptConfig,
-        context: &Vec<Conversation>,
-        message_from_user: &str,
-    ) -> Result<String> {
+        request: &ChatRequest<'_>,
+    ) -> Result<ChatReply> {
+        let ChatRequest {
+            persona,
+            preferences,
+            knowledge,
+            context,
+            message_from_user,
+            // the completions API cannot call tools
+            tools: _,
+        } = *request;
         debug!(
             "send_message with context: {} message form user: {}",
-            convert2prompts(context),
-            message_from_user
+            redact(convert2prompts(context)),
+            redact(message_from_user)
         );
 
+        let references = if knowledge.is_empty() {
+            String::new()
+        } else {
+            reference_text(knowledge)
+        };
         let prompt = format!(
-            "{}{}{}{}{}",
-            PREP_PROMPT,
+            "{}{}{}{}{}{}{}",
+            persona.unwrap_or(PREP_PROMPT),
+            style_instructions(preferences).unwrap_or_default(),
+            references,
             convert2prompts(context),
             QUESTION_MARK,
             message_from_user,
             ANSWER_MARK
         );
-        info!("prompt is {}", prompt.clone());
+        debug!("prompt is {}", redact(&prompt));
         let request_body = format!(
             r#"{{"model": "{}", "prompt": "{}", "temperature": 0, "max_tokens": 100,"stop": [
             "{}",
@@ -96,14 +117,20 @@ impl ChatApi for ChatGptTextDavinci003 {
             .await?
             .json::<ChatGptResponse>()
             .await?;
-        let text = response.choices[0].text.clone();
-        info!("response is {}", &response);
-        Ok(text)
+        debug!("response is {}", redact(&response));
+        let choice = response.choices.into_iter().next().ok_or_else(|| {
+            Error::ArgumentError("the model answered without choices".to_string())
+        })?;
+        Ok(ChatReply {
+            content: choice.text,
+            model: response.model,
+            usage: response.usage,
+        })
     }
 }
 
-fn convert2prompts(context: &Vec<Conversation>) -> String {
-    context.iter().map(|c| convert2prompt(c)).collect()
+fn convert2prompts(context: &[Conversation]) -> String {
+    context.iter().map(convert2prompt).collect()
 }
 
 fn convert2prompt(context: &Conversation) -> String {
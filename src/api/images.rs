@@ -0,0 +1,121 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    settings::Images,
+};
+
+/// The code the provider refuses a prompt with.
+const CONTENT_POLICY_VIOLATION: &str = "content_policy_violation";
+
+#[derive(Serialize)]
+struct ImageRequest<'a> {
+    model: &'a str,
+    prompt: &'a str,
+    n: u8,
+    size: &'a str,
+    response_format: &'a str,
+}
+
+#[derive(Deserialize)]
+struct ImageResponse {
+    data: Vec<ImageData>,
+}
+
+#[derive(Deserialize)]
+struct ImageData {
+    b64_json: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: ApiError,
+}
+
+#[derive(Deserialize)]
+struct ApiError {
+    code: Option<String>,
+    message: String,
+}
+
+/// What the images endpoint made of a prompt.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Drawing {
+    Png(Vec<u8>),
+    /// The provider would not draw it, for the reason given.
+    Refused(String),
+}
+
+pub async fn generate(
+    client: &Client,
+    images: &Images,
+    api_key: &str,
+    prompt: &str,
+) -> Result<Drawing> {
+    let response = client
+        .post(&images.url)
+        .bearer_auth(images.api_key.as_deref().unwrap_or(api_key))
+        .json(&ImageRequest {
+            model: &images.model,
+            prompt,
+            n: 1,
+            size: &images.size,
+            response_format: "b64_json",
+        })
+        .send()
+        .await?;
+    let success = response.status().is_success();
+    parse_drawing(success, &response.text().await?)
+}
+
+fn parse_drawing(success: bool, text: &str) -> Result<Drawing> {
+    if !success {
+        let error = serde_json::from_str::<ErrorResponse>(text)?.error;
+        if error.code.as_deref() == Some(CONTENT_POLICY_VIOLATION) {
+            return Ok(Drawing::Refused(error.message));
+        }
+        return Err(Error::ArgumentError(format!(
+            "images endpoint: {}",
+            error.message
+        )));
+    }
+    let image = serde_json::from_str::<ImageResponse>(text)?
+        .data
+        .pop()
+        .ok_or_else(|| Error::ArgumentError("images endpoint sent no image".to_string()))?;
+    STANDARD
+        .decode(image.b64_json)
+        .map(Drawing::Png)
+        .map_err(|e| Error::ArgumentError(format!("images endpoint: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_drawing() {
+        let png = parse_drawing(
+            true,
+            r#"{"created":1700000000,"data":[{"b64_json":"iVBORw=="}]}"#,
+        );
+        assert_eq!(png.unwrap(), Drawing::Png(vec![0x89, b'P', b'N', b'G']));
+
+        let refused = parse_drawing(
+            false,
+            r#"{"error":{"code":"content_policy_violation","message":"Your request was rejected","type":"invalid_request_error"}}"#,
+        );
+        assert_eq!(
+            refused.unwrap(),
+            Drawing::Refused("Your request was rejected".to_string())
+        );
+
+        let failed = parse_drawing(
+            false,
+            r#"{"error":{"code":null,"message":"Rate limit reached","type":"requests"}}"#,
+        );
+        assert!(failed.is_err());
+    }
+}
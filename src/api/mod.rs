@@ -1,4 +1,8 @@
 pub mod wechat;
+pub mod wecom;
+pub mod msg_crypt;
 pub mod chat_gpt_text_davinci_003;
 pub mod chat_gpt_35_turbo;
 pub mod chat_gpt;
+pub mod images;
+pub mod speech;
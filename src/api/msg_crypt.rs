@@ -0,0 +1,234 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use aes::{
+    cipher::{block_padding::NoPadding, BlockDecryptMut, BlockEncryptMut, KeyIvInit},
+    Aes256,
+};
+use base64::{
+    alphabet,
+    engine::{GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::error::{Error, Result};
+
+/// How far the timestamp of a callback may be from the clock here, so that a
+/// captured callback cannot be replayed later.
+const MAX_CLOCK_SKEW_SECS: u64 = 300;
+/// Messages are padded to 32 bytes rather than to the AES block size.
+const PAD_BLOCK: usize = 32;
+// the EncodingAESKey is base64 without its padding and is not always canonical
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_allow_trailing_bits(true),
+);
+
+type Aes256CbcDec = cbc::Decryptor<Aes256>;
+type Aes256CbcEnc = cbc::Encryptor<Aes256>;
+
+/// The body of an encrypted callback, the message itself being in `Encrypt`.
+/// Official accounts in safe mode send `ToUserName` along, which is ignored.
+#[derive(Debug, Deserialize)]
+pub struct EncryptedMessage {
+    #[serde(rename = "Encrypt")]
+    pub encrypt: String,
+}
+
+/// A passive reply sealed with [`MsgCrypt::seal`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename = "xml")]
+pub struct EncryptedReply {
+    #[serde(rename = "Encrypt")]
+    pub encrypt: String,
+    #[serde(rename = "MsgSignature")]
+    pub msg_signature: String,
+    #[serde(rename = "TimeStamp")]
+    pub timestamp: String,
+    #[serde(rename = "Nonce")]
+    pub nonce: String,
+}
+
+/// Checks, opens and seals messages the way `WXBizMsgCrypt` does, for the
+/// callbacks of WeCom and of official accounts in safe mode. `receive_id` is
+/// the corp id of WeCom and the app id of an official account.
+pub struct MsgCrypt {
+    token: String,
+    receive_id: String,
+    key: [u8; 32],
+}
+
+impl MsgCrypt {
+    pub fn new(token: &str, encoding_aes_key: &str, receive_id: &str) -> Result<MsgCrypt> {
+        let key = BASE64
+            .decode(format!("{}=", encoding_aes_key))
+            .ok()
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .ok_or_else(|| {
+                Error::ArgumentError("encoding_aes_key is not 43 base64 characters".into())
+            })?;
+        Ok(MsgCrypt {
+            token: token.to_string(),
+            receive_id: receive_id.to_string(),
+            key,
+        })
+    }
+
+    /// Verifies the timestamp and the signature over `encrypted` and returns
+    /// the plain text.
+    pub fn open(
+        &self,
+        msg_signature: &str,
+        timestamp: &str,
+        nonce: &str,
+        encrypted: &str,
+    ) -> Result<String> {
+        check_timestamp(timestamp)?;
+        if !signatures_match(&self.signature(timestamp, nonce, encrypted), msg_signature) {
+            return Err(Error::InvalidSignature);
+        }
+        self.decrypt(encrypted)
+    }
+
+    /// Encrypts and signs a passive reply.
+    pub fn seal(&self, message: &str, timestamp: &str, nonce: &str) -> EncryptedReply {
+        let encrypt = self.encrypt(message, rand::random());
+        EncryptedReply {
+            msg_signature: self.signature(timestamp, nonce, &encrypt),
+            encrypt,
+            timestamp: timestamp.to_string(),
+            nonce: nonce.to_string(),
+        }
+    }
+
+    fn signature(&self, timestamp: &str, nonce: &str, encrypted: &str) -> String {
+        let mut values = [self.token.as_str(), timestamp, nonce, encrypted];
+        values.sort();
+        let mut hasher = Sha1::new();
+        hasher.update(values.concat().as_bytes());
+        hex::encode(hasher.finalize())
+    }
+
+    // random(16) | length(4, big endian) | message | receive id, padded to 32 bytes
+    fn encrypt(&self, message: &str, random: [u8; 16]) -> String {
+        let mut plain = random.to_vec();
+        plain.extend((message.len() as u32).to_be_bytes());
+        plain.extend(message.as_bytes());
+        plain.extend(self.receive_id.as_bytes());
+        let pad = PAD_BLOCK - plain.len() % PAD_BLOCK;
+        plain.resize(plain.len() + pad, pad as u8);
+        let length = plain.len();
+        let sealed = Aes256CbcEnc::new(&self.key.into(), self.key[..16].into())
+            .encrypt_padded_mut::<NoPadding>(&mut plain, length)
+            .unwrap();
+        BASE64.encode(sealed)
+    }
+
+    fn decrypt(&self, encrypted: &str) -> Result<String> {
+        let invalid = || Error::ArgumentError("malformed encrypted message".into());
+        let mut buffer = BASE64.decode(encrypted).map_err(|_| invalid())?;
+        let plain = Aes256CbcDec::new(&self.key.into(), self.key[..16].into())
+            .decrypt_padded_mut::<NoPadding>(&mut buffer)
+            .map_err(|_| invalid())?;
+        let pad = *plain.last().ok_or_else(invalid)? as usize;
+        if pad == 0 || pad > PAD_BLOCK || pad > plain.len() {
+            return Err(invalid());
+        }
+        let plain = &plain[..plain.len() - pad];
+        let header = plain.get(16..20).ok_or_else(invalid)?;
+        let length = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+        let message = plain.get(20..20 + length).ok_or_else(invalid)?;
+        if &plain[20 + length..] != self.receive_id.as_bytes() {
+            return Err(Error::InvalidSignature);
+        }
+        String::from_utf8(message.to_vec()).map_err(|_| invalid())
+    }
+}
+
+/// Compares a computed signature with the one a request carries, without
+/// giving away through timing how much of it is right.
+pub fn signatures_match(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Rejects a callback whose timestamp is more than [`MAX_CLOCK_SKEW_SECS`]
+/// away from now.
+pub fn check_timestamp(timestamp: &str) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    match timestamp.parse::<u64>() {
+        Ok(timestamp) if timestamp.abs_diff(now) <= MAX_CLOCK_SKEW_SECS => Ok(()),
+        _ => Err(Error::InvalidSignature),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "jWmYm7qr5nMoAUwZRjGtBxmz3KA1tkAj3ykkR6q2B2C";
+
+    // the callback address check published with WXBizMsgCrypt
+    #[test]
+    fn test_published_vector() {
+        let crypt = MsgCrypt::new("QDG6eK", KEY, "wx5823bf96d3bd56c7").unwrap();
+        let echostr = "P9nAzCzyDtyTWESHep1vC5X9xho/qYX3Zpb4yKa9SKld1DsH3Iyt3tP3zNdtp+4RPcs8TgAE7OaBO+FZXvnaqQ==";
+        assert_eq!(
+            crypt.signature("1409659589", "263014780", echostr),
+            "5c45ff5e21c57e6ad56bac8758b79b1d9ac89fd3"
+        );
+        assert_eq!(crypt.decrypt(echostr).unwrap(), "1616140317555161061");
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let crypt = MsgCrypt::new("QDG6eK", KEY, "wx5823bf96d3bd56c7").unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .to_string();
+        let message = "<xml><Content>你好</Content></xml>";
+        let reply = crypt.seal(message, &now, "1372623149");
+        assert_eq!(
+            crypt
+                .open(&reply.msg_signature, &now, "1372623149", &reply.encrypt)
+                .unwrap(),
+            message
+        );
+        assert!(matches!(
+            crypt.open(&reply.msg_signature, &now, "1372623150", &reply.encrypt),
+            Err(Error::InvalidSignature)
+        ));
+
+        let other = MsgCrypt::new("QDG6eK", KEY, "another_corp").unwrap();
+        let sealed = other.seal(message, &now, "1372623149");
+        assert!(matches!(
+            crypt.decrypt(&sealed.encrypt),
+            Err(Error::InvalidSignature)
+        ));
+        // a callback replayed long after it was captured
+        let stale = crypt.seal(message, "1409659813", "1372623149");
+        assert!(matches!(
+            crypt.open(
+                &stale.msg_signature,
+                "1409659813",
+                "1372623149",
+                &stale.encrypt
+            ),
+            Err(Error::InvalidSignature)
+        ));
+        assert!(signatures_match("abc", "abc"));
+        assert!(!signatures_match("abc", "abd"));
+        assert!(!signatures_match("abc", "ab"));
+        assert!(MsgCrypt::new("QDG6eK", "short", "wx5823bf96d3bd56c7").is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use std::time::Duration;
+
+use reqwest::Client;
+use serde::Serialize;
+
+use crate::{error::Result, settings::Speech};
+
+#[derive(Serialize)]
+struct SpeechRequest<'a> {
+    model: &'a str,
+    input: &'a str,
+    voice: &'a str,
+    response_format: &'a str,
+}
+
+/// `text` read out as MP3, which WeChat takes for a voice message as it is.
+pub async fn synthesize(
+    client: &Client,
+    speech: &Speech,
+    api_key: &str,
+    text: &str,
+) -> Result<Vec<u8>> {
+    let audio = client
+        .post(&speech.url)
+        .bearer_auth(speech.api_key.as_deref().unwrap_or(api_key))
+        .json(&SpeechRequest {
+            model: &speech.model,
+            input: text,
+            voice: &speech.voice,
+            response_format: "mp3",
+        })
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    Ok(audio.to_vec())
+}
+
+/// How long `audio` plays, summed over its MPEG layer III frames. `None`
+/// when it is not an MP3 the frames of which can be walked.
+pub fn mp3_duration(audio: &[u8]) -> Option<Duration> {
+    // kbit/s by bitrate index, for MPEG-1 and for MPEG-2 and 2.5
+    const BITRATES: [[u32; 15]; 2] = [
+        [
+            0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+        ],
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+    ];
+    const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+    let mut position = 0;
+    // an ID3v2 tag: "ID3", version, flags, then its size in 7-bit bytes
+    if audio.len() >= 10 && &audio[..3] == b"ID3" {
+        let size = audio[6..10]
+            .iter()
+            .fold(0, |size, byte| (size << 7) | (*byte as usize & 0x7f));
+        position = 10 + size;
+    }
+    let mut micros = 0u64;
+    let mut frames = 0;
+    while position + 4 <= audio.len() {
+        let header = &audio[position..position + 4];
+        // a trailing ID3v1 tag
+        if &header[..3] == b"TAG" {
+            break;
+        }
+        let version = (header[1] >> 3) & 0b11;
+        let layer = (header[1] >> 1) & 0b11;
+        let bitrate_index = (header[2] >> 4) as usize;
+        let rate_index = ((header[2] >> 2) & 0b11) as usize;
+        if header[0] != 0xff
+            || header[1] & 0xe0 != 0xe0
+            || version == 1
+            || layer != 1
+            || bitrate_index == 0
+            || bitrate_index == 15
+            || rate_index == 3
+        {
+            return None;
+        }
+        let mpeg1 = version == 3;
+        // MPEG-2 halves the sample rate, MPEG-2.5 quarters it
+        let sample_rate = SAMPLE_RATES[rate_index] >> (3 - version.max(1)).min(2);
+        let bitrate = BITRATES[usize::from(!mpeg1)][bitrate_index] * 1000;
+        let samples = if mpeg1 { 1152 } else { 576 };
+        let padding = ((header[2] >> 1) & 1) as u32;
+        position += (samples / 8 * bitrate / sample_rate + padding) as usize;
+        micros += samples as u64 * 1_000_000 / sample_rate as u64;
+        frames += 1;
+    }
+    (frames > 0).then(|| Duration::from_micros(micros))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mp3_duration() {
+        // MPEG-1 layer III at 128 kbit/s and 44.1 kHz, 417 bytes a frame
+        let mut frame = vec![0xff, 0xfb, 0x90, 0x00];
+        frame.resize(417, 0);
+        let mut audio = b"ID3\x04\x00\x00\x00\x00\x00\x02\x00\x00".to_vec();
+        for _ in 0..100 {
+            audio.extend(&frame);
+        }
+        let duration = mp3_duration(&audio).unwrap();
+        assert_eq!(duration.as_millis(), 2612);
+
+        // MPEG-2 at 64 kbit/s and 24 kHz, 192 bytes a frame of 24 ms
+        let mut frame = vec![0xff, 0xf3, 0x84, 0x00];
+        frame.resize(192, 0);
+        assert_eq!(
+            mp3_duration(&frame.repeat(50)).unwrap(),
+            Duration::from_millis(1200)
+        );
+
+        assert_eq!(mp3_duration(b"RIFF....WAVEfmt "), None);
+        assert_eq!(mp3_duration(&[]), None);
+    }
+}
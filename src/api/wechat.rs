@@ -1,8 +1,28 @@
+use reqwest::{
+    multipart::{Form, Part},
+    Client, RequestBuilder,
+};
 use serde::{Deserialize, Serialize};
 
-use crate::error::{Error, Result};
+use crate::{
+    api::msg_crypt::{check_timestamp, signatures_match},
+    cache::CacheStore,
+    error::{Error, Result},
+    settings::WechatConfig,
+};
 use sha1::{Digest, Sha1};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const TOKEN_URL: &str = "https://api.weixin.qq.com/cgi-bin/token";
+const CUSTOM_SEND_URL: &str = "https://api.weixin.qq.com/cgi-bin/message/custom/send";
+const MEDIA_UPLOAD_URL: &str = "https://api.weixin.qq.com/cgi-bin/media/upload";
+const USER_INFO_URL: &str = "https://api.weixin.qq.com/cgi-bin/user/info";
+const ACCESS_TOKEN_KEY: &str = "WECHAT_ACCESS_TOKEN";
+// refresh a little before WeChat expires the token
+const ACCESS_TOKEN_MARGIN_SECS: u64 = 300;
 
 #[derive(Serialize, Debug)]
 #[serde(rename = "xml")]
@@ -34,6 +54,72 @@ impl TextMessage {
     }
 }
 
+/// The kinds of temporary media a reply can carry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaType {
+    Image,
+    /// AMR or MP3, 60 seconds at most.
+    Voice,
+}
+
+impl MediaType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MediaType::Image => "image",
+            MediaType::Voice => "voice",
+        }
+    }
+}
+
+/// A passive reply carrying media uploaded with [`upload_media`].
+#[derive(Serialize, Debug)]
+#[serde(rename = "xml")]
+pub struct MediaMessage {
+    #[serde(rename = "ToUserName")]
+    pub to_user_name: String,
+    #[serde(rename = "FromUserName")]
+    pub from_user_name: String,
+    #[serde(rename = "CreateTime")]
+    pub create_time: i64,
+    #[serde(rename = "MsgType")]
+    pub msg_type: String,
+    #[serde(rename = "Image", skip_serializing_if = "Option::is_none")]
+    pub image: Option<MediaId>,
+    #[serde(rename = "Voice", skip_serializing_if = "Option::is_none")]
+    pub voice: Option<MediaId>,
+}
+
+/// `<MediaId>` in the element named after the media type. A map, as
+/// serde-xml-rs would wrap a struct in another element named after it.
+pub type MediaId = BTreeMap<&'static str, String>;
+
+impl MediaMessage {
+    pub fn new(
+        to_user_name: String,
+        from_user_name: String,
+        media_type: MediaType,
+        media_id: String,
+    ) -> Self {
+        let mut message = Self {
+            to_user_name,
+            from_user_name,
+            create_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64,
+            msg_type: media_type.as_str().to_owned(),
+            image: None,
+            voice: None,
+        };
+        let media = Some(MediaId::from([("MediaId", media_id)]));
+        match media_type {
+            MediaType::Image => message.image = media,
+            MediaType::Voice => message.voice = media,
+        }
+        message
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct WeChatRequest {
     #[serde(rename = "signature")]
@@ -44,37 +130,287 @@ pub struct WeChatRequest {
     pub nonce: String,
     #[serde(rename = "echostr")]
     pub echostr: Option<String>,
-    #[serde(flatten)]
-    pub message: Option<Message>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Message {
-    #[serde(rename = "ToUserName")]
-    to_user_name: String,
-    #[serde(rename = "FromUserName")]
-    from_user_name: String,
-    #[serde(rename = "CreateTime")]
-    create_time: i64,
-    #[serde(rename = "MsgType")]
-    msg_type: String,
-    #[serde(rename = "Content")]
-    content: Option<String>,
-    #[serde(rename = "MsgId")]
-    msg_id: Option<i64>,
+    /// `aes` for messages of an account in safe mode.
+    pub encrypt_type: Option<String>,
+    /// The signature over the encrypted message in safe mode.
+    pub msg_signature: Option<String>,
 }
 
+/// Checks that a request comes from WeChat: its signature over the token of
+/// the account, and a timestamp close to now so that it cannot be replayed.
 pub fn verify_signature(info: &WeChatRequest, token: &str) -> Result<()> {
-    let mut values = vec![token, &info.timestamp, &info.nonce];
+    check_timestamp(&info.timestamp)?;
+    let mut values = [token, &info.timestamp, &info.nonce];
     values.sort();
     let input = values.join("");
     let mut hasher = Sha1::new();
     hasher.update(input.as_bytes());
     let result = hex::encode(hasher.finalize());
 
-    if result != *&info.signature {
+    if !signatures_match(&result, &info.signature) {
         return Err(Error::InvalidSignature);
     }
 
     Ok(())
 }
+
+#[derive(Debug, Deserialize)]
+struct AccessToken {
+    access_token: String,
+    expires_in: u64,
+}
+
+/// The `errcode`/`errmsg` pair every API call answers with, `0` on success.
+#[derive(Debug, Deserialize)]
+pub struct ApiStatus {
+    #[serde(default)]
+    errcode: i64,
+    #[serde(default)]
+    errmsg: String,
+}
+
+impl ApiStatus {
+    pub fn into_result(self) -> Result<()> {
+        match self.errcode {
+            0 => Ok(()),
+            code => Err(Error::WechatError(code, self.errmsg)),
+        }
+    }
+}
+
+/// The access token for the server APIs, shared through the cache so that
+/// instances don't invalidate each other's token.
+pub async fn get_access_token(
+    client: &Client,
+    cache: &dyn CacheStore,
+    config: &WechatConfig,
+) -> Result<String> {
+    // one token per app, as several accounts can share the cache
+    let key = format!("{}_{}", ACCESS_TOKEN_KEY, config.app_id);
+    let request = client.get(TOKEN_URL).query(&[
+        ("grant_type", "client_credential"),
+        ("appid", &config.app_id),
+        ("secret", &config.app_secret),
+    ]);
+    cached_access_token(cache, &key, request).await
+}
+
+/// The token cached under `key`, or the one `request` fetches, which is then
+/// cached until shortly before it expires. Official accounts and WeCom hand
+/// out tokens the same way.
+pub async fn cached_access_token(
+    cache: &dyn CacheStore,
+    key: &str,
+    request: RequestBuilder,
+) -> Result<String> {
+    if let Some(token) = cache.get(key).await? {
+        return Ok(token);
+    }
+    let text = request.send().await?.text().await?;
+    // WeCom sends errcode 0 along with the token, official accounts no errcode
+    serde_json::from_str::<ApiStatus>(&text)?.into_result()?;
+    let token: AccessToken = serde_json::from_str(&text)?;
+    let ttl = token
+        .expires_in
+        .saturating_sub(ACCESS_TOKEN_MARGIN_SECS)
+        .max(60);
+    cache
+        .set(key, &token.access_token, Duration::from_secs(ttl))
+        .await?;
+    Ok(token.access_token)
+}
+
+/// Sends a customer service text message, allowed within 48 hours of the
+/// follower's last message.
+pub async fn send_text(
+    client: &Client,
+    access_token: &str,
+    to_user: &str,
+    content: &str,
+) -> Result<()> {
+    let body = serde_json::json!({
+        "touser": to_user,
+        "msgtype": "text",
+        "text": { "content": content },
+    });
+    client
+        .post(CUSTOM_SEND_URL)
+        .query(&[("access_token", access_token)])
+        .json(&body)
+        .send()
+        .await?
+        .json::<ApiStatus>()
+        .await?
+        .into_result()
+}
+
+/// Sends a customer service message carrying media uploaded with
+/// [`upload_media`], within the same 48 hours as [`send_text`].
+pub async fn send_media(
+    client: &Client,
+    access_token: &str,
+    to_user: &str,
+    media_type: MediaType,
+    media_id: &str,
+) -> Result<()> {
+    let mut body = serde_json::json!({
+        "touser": to_user,
+        "msgtype": media_type.as_str(),
+    });
+    body[media_type.as_str()] = serde_json::json!({ "media_id": media_id });
+    client
+        .post(CUSTOM_SEND_URL)
+        .query(&[("access_token", access_token)])
+        .json(&body)
+        .send()
+        .await?
+        .json::<ApiStatus>()
+        .await?
+        .into_result()
+}
+
+/// A follower as `user/info` describes them.
+#[derive(Debug, Deserialize)]
+pub struct UserInfo {
+    #[serde(default)]
+    pub nickname: String,
+    #[serde(default)]
+    pub language: String,
+    /// `0` if they do not follow the account, nothing else is filled in then.
+    #[serde(default)]
+    pub subscribe_time: i64,
+}
+
+pub async fn get_user_info(client: &Client, access_token: &str, openid: &str) -> Result<UserInfo> {
+    let text = client
+        .get(USER_INFO_URL)
+        .query(&[
+            ("access_token", access_token),
+            ("openid", openid),
+            ("lang", "zh_CN"),
+        ])
+        .send()
+        .await?
+        .text()
+        .await?;
+    parse_user_info(&text)
+}
+
+fn parse_user_info(text: &str) -> Result<UserInfo> {
+    serde_json::from_str::<ApiStatus>(text)?.into_result()?;
+    Ok(serde_json::from_str(text)?)
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadedMedia {
+    media_id: String,
+}
+
+/// Uploads temporary media, kept by WeChat for three days, and returns its
+/// media id. WeChat goes by the extension of `file_name`.
+pub async fn upload_media(
+    client: &Client,
+    access_token: &str,
+    media_type: MediaType,
+    file_name: &str,
+    data: Vec<u8>,
+) -> Result<String> {
+    upload_media_to(
+        client,
+        MEDIA_UPLOAD_URL,
+        access_token,
+        media_type,
+        file_name,
+        data,
+    )
+    .await
+}
+
+/// [`upload_media`] against `url`, WeCom takes the same form.
+pub async fn upload_media_to(
+    client: &Client,
+    url: &str,
+    access_token: &str,
+    media_type: MediaType,
+    file_name: &str,
+    data: Vec<u8>,
+) -> Result<String> {
+    let form = Form::new().part("media", Part::bytes(data).file_name(file_name.to_string()));
+    let text = client
+        .post(url)
+        .query(&[
+            ("access_token", access_token),
+            ("type", media_type.as_str()),
+        ])
+        .multipart(form)
+        .send()
+        .await?
+        .text()
+        .await?;
+    match serde_json::from_str::<UploadedMedia>(&text) {
+        Ok(media) => Ok(media.media_id),
+        Err(_) => {
+            serde_json::from_str::<ApiStatus>(&text)?.into_result()?;
+            Err(Error::WechatError(-1, text))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_media_message_to_xml() {
+        let message = MediaMessage::new(
+            "user".to_string(),
+            "account".to_string(),
+            MediaType::Image,
+            "media".to_string(),
+        );
+        let xml = serde_xml_rs::to_string(&message).unwrap();
+        assert!(
+            xml.contains("<MsgType>image</MsgType><Image><MediaId>media</MediaId></Image></xml>")
+        );
+
+        let message = MediaMessage::new(
+            "user".to_string(),
+            "account".to_string(),
+            MediaType::Voice,
+            "media".to_string(),
+        );
+        let xml = serde_xml_rs::to_string(&message).unwrap();
+        assert!(
+            xml.contains("<MsgType>voice</MsgType><Voice><MediaId>media</MediaId></Voice></xml>")
+        );
+    }
+
+    #[test]
+    fn test_parse_user_info() {
+        let info = parse_user_info(
+            r#"{"subscribe":1,"openid":"o6_bmjrPTlm6_2sgVt7hMZOPfL2M","nickname":"","sex":0,"language":"zh_CN","city":"","province":"","country":"","headimgurl":"","subscribe_time":1382694957,"remark":"","groupid":0,"tagid_list":[],"subscribe_scene":"ADD_SCENE_QR_CODE","qr_scene":0,"qr_scene_str":""}"#,
+        )
+        .unwrap();
+        assert_eq!(info.language, "zh_CN");
+        assert_eq!(info.subscribe_time, 1382694957);
+
+        let gone = parse_user_info(r#"{"subscribe":0,"openid":"o6_bmjrPTlm6_2sgVt7hMZOPfL2M"}"#);
+        assert_eq!(gone.unwrap().subscribe_time, 0);
+
+        let failed = parse_user_info(r#"{"errcode":40003,"errmsg":"invalid openid"}"#);
+        assert!(matches!(failed, Err(Error::WechatError(40003, _))));
+    }
+
+    #[test]
+    fn test_api_status_from_json() {
+        let ok: ApiStatus = serde_json::from_str(r#"{"errcode":0,"errmsg":"ok"}"#).unwrap();
+        assert!(ok.into_result().is_ok());
+        let expired: ApiStatus =
+            serde_json::from_str(r#"{"errcode":45015,"errmsg":"response out of time limit"}"#)
+                .unwrap();
+        assert!(matches!(
+            expired.into_result(),
+            Err(Error::WechatError(45015, _))
+        ));
+    }
+}
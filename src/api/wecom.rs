@@ -0,0 +1,121 @@
+use reqwest::Client;
+use serde::Deserialize;
+
+use crate::{
+    api::{
+        msg_crypt::MsgCrypt,
+        wechat::{self, ApiStatus, MediaType},
+    },
+    cache::CacheStore,
+    error::Result,
+    settings::WecomConfig,
+};
+
+const TOKEN_URL: &str = "https://qyapi.weixin.qq.com/cgi-bin/gettoken";
+const SEND_URL: &str = "https://qyapi.weixin.qq.com/cgi-bin/message/send";
+const MEDIA_UPLOAD_URL: &str = "https://qyapi.weixin.qq.com/cgi-bin/media/upload";
+const ACCESS_TOKEN_KEY: &str = "WECOM_ACCESS_TOKEN";
+
+/// The query of every callback. `echostr` is only sent to check the
+/// callback address.
+#[derive(Debug, Deserialize)]
+pub struct WecomRequest {
+    pub msg_signature: String,
+    pub timestamp: String,
+    pub nonce: String,
+    pub echostr: Option<String>,
+}
+
+/// Opens the callbacks of the application.
+pub fn msg_crypt(config: &WecomConfig) -> Result<MsgCrypt> {
+    MsgCrypt::new(&config.token, &config.encoding_aes_key, &config.corp_id)
+}
+
+/// The access token of the application, shared through the cache like the
+/// one of official accounts.
+pub async fn get_access_token(
+    client: &Client,
+    cache: &dyn CacheStore,
+    config: &WecomConfig,
+) -> Result<String> {
+    let key = format!(
+        "{}_{}_{}",
+        ACCESS_TOKEN_KEY, config.corp_id, config.agent_id
+    );
+    let request = client
+        .get(TOKEN_URL)
+        .query(&[("corpid", &config.corp_id), ("corpsecret", &config.secret)]);
+    wechat::cached_access_token(cache, &key, request).await
+}
+
+/// Sends a text message from the application to one member.
+pub async fn send_text(
+    client: &Client,
+    access_token: &str,
+    agent_id: i64,
+    to_user: &str,
+    content: &str,
+) -> Result<()> {
+    let body = serde_json::json!({
+        "touser": to_user,
+        "msgtype": "text",
+        "agentid": agent_id,
+        "text": { "content": content },
+    });
+    client
+        .post(SEND_URL)
+        .query(&[("access_token", access_token)])
+        .json(&body)
+        .send()
+        .await?
+        .json::<ApiStatus>()
+        .await?
+        .into_result()
+}
+
+/// Sends media uploaded with [`upload_media`] from the application to one
+/// member.
+pub async fn send_media(
+    client: &Client,
+    access_token: &str,
+    agent_id: i64,
+    to_user: &str,
+    media_type: MediaType,
+    media_id: &str,
+) -> Result<()> {
+    let mut body = serde_json::json!({
+        "touser": to_user,
+        "msgtype": media_type.as_str(),
+        "agentid": agent_id,
+    });
+    body[media_type.as_str()] = serde_json::json!({ "media_id": media_id });
+    client
+        .post(SEND_URL)
+        .query(&[("access_token", access_token)])
+        .json(&body)
+        .send()
+        .await?
+        .json::<ApiStatus>()
+        .await?
+        .into_result()
+}
+
+/// Uploads temporary media, kept by WeCom for three days, and returns its
+/// media id.
+pub async fn upload_media(
+    client: &Client,
+    access_token: &str,
+    media_type: MediaType,
+    file_name: &str,
+    data: Vec<u8>,
+) -> Result<String> {
+    wechat::upload_media_to(
+        client,
+        MEDIA_UPLOAD_URL,
+        access_token,
+        media_type,
+        file_name,
+        data,
+    )
+    .await
+}
@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::error::Result;
+
+use super::{Cache, CacheStore};
+
+/// The default, in-process backend. Only suitable for a single instance.
+pub struct MemoryStore {
+    cache: Cache<String, String>,
+}
+
+impl MemoryStore {
+    pub fn new(max_entries: usize) -> MemoryStore {
+        MemoryStore {
+            cache: Cache::new(max_entries),
+        }
+    }
+
+    pub async fn cleanup(&self, period: Duration) {
+        self.cache.cleanup(period).await;
+    }
+}
+
+#[async_trait]
+impl CacheStore for MemoryStore {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.cache.get(key))
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Duration) -> Result<()> {
+        self.cache
+            .insert_with_ttl(key.to_string(), value.to_string(), ttl);
+        Ok(())
+    }
+
+    async fn set_nx(&self, key: &str, value: &str, ttl: Duration) -> Result<bool> {
+        Ok(self
+            .cache
+            .insert_if_absent(key.to_string(), value.to_string(), ttl))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.cache.remove(key);
+        Ok(())
+    }
+
+    async fn incr(&self, key: &str, delta: i64, ttl: Duration) -> Result<i64> {
+        let value = self.cache.update(key.to_string(), ttl, |current| {
+            let current = current.and_then(|v| v.parse::<i64>().ok()).unwrap_or(0);
+            (current + delta).to_string()
+        });
+        Ok(value.parse().unwrap_or(0))
+    }
+
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        let store = MemoryStore::new(100);
+        let ttl = Duration::from_secs(60);
+
+        assert!(store.set_nx("dedup", "1", ttl).await.unwrap());
+        assert!(!store.set_nx("dedup", "1", ttl).await.unwrap());
+        assert_eq!(store.get("dedup").await.unwrap(), Some("1".to_string()));
+
+        assert_eq!(store.incr("counter", 1, ttl).await.unwrap(), 1);
+        assert_eq!(store.incr("counter", 2, ttl).await.unwrap(), 3);
+
+        store.delete("dedup").await.unwrap();
+        assert_eq!(store.get("dedup").await.unwrap(), None);
+    }
+}
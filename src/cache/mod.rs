@@ -0,0 +1,373 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use log::debug;
+use tokio::time::interval;
+
+use crate::{
+    error::Result,
+    settings::{CacheBackend, CacheConfig},
+};
+
+pub mod memory;
+pub mod redis;
+
+const DEFAULT_SHARDS: usize = 16;
+
+/// Shared key/value storage for state that has to be visible to every
+/// instance: retry dedup keys, rate-limit counters, access tokens and reply
+/// chunks waiting to be picked up. Values are plain strings, callers
+/// serialize anything richer themselves.
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<String>>;
+
+    async fn set(&self, key: &str, value: &str, ttl: Duration) -> Result<()>;
+
+    /// Stores the value only if the key does not exist yet. Returns `true`
+    /// when this call claimed the key.
+    async fn set_nx(&self, key: &str, value: &str, ttl: Duration) -> Result<bool>;
+
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Adds `delta` to a counter and returns the new value. The TTL starts
+    /// when the counter is created and is not extended by later increments.
+    async fn incr(&self, key: &str, delta: i64, ttl: Duration) -> Result<i64>;
+
+    /// A round trip to the backend, for readiness checks.
+    async fn ping(&self) -> Result<()>;
+}
+
+pub async fn connect(config: &CacheConfig) -> Result<Arc<dyn CacheStore>> {
+    match config.backend {
+        CacheBackend::Memory => {
+            let store = Arc::new(memory::MemoryStore::new(config.max_entries));
+            let store_clone = Arc::clone(&store);
+            let cleanup_interval = config.cleanup_interval();
+            tokio::spawn(async move {
+                store_clone.cleanup(cleanup_interval).await;
+            });
+            Ok(store)
+        }
+        CacheBackend::Redis => {
+            let store = redis::RedisStore::connect(config.redis_url(), &config.key_prefix).await?;
+            Ok(Arc::new(store))
+        }
+    }
+}
+
+/// A sharded, size-bounded cache with a TTL per entry.
+///
+/// Lookups only take a shard's read lock; recency for LRU eviction is tracked
+/// with an atomic tick on each entry. Every shard keeps its keys ordered by
+/// expiry as well, so purging expired entries only touches what has expired.
+pub struct Cache<K, V> {
+    shards: Vec<RwLock<Shard<K, V>>>,
+    hasher: RandomState,
+    shard_capacity: usize,
+    tick: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+}
+
+struct Shard<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    expiry: BTreeMap<(Instant, u64), K>,
+}
+
+struct Entry<V> {
+    value: V,
+    expire_key: (Instant, u64),
+    last_access: AtomicU64,
+}
+
+impl<V> Entry<V> {
+    fn is_alive(&self, now: Instant) -> bool {
+        now < self.expire_key.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub expirations: u64,
+    pub entries: usize,
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    pub fn new(max_entries: usize) -> Cache<K, V> {
+        Cache::with_shards(max_entries, DEFAULT_SHARDS)
+    }
+
+    pub fn with_shards(max_entries: usize, shards: usize) -> Cache<K, V> {
+        let shards = shards.clamp(1, max_entries.max(1));
+        Cache {
+            shards: (0..shards)
+                .map(|_| {
+                    RwLock::new(Shard {
+                        entries: HashMap::new(),
+                        expiry: BTreeMap::new(),
+                    })
+                })
+                .collect(),
+            hasher: RandomState::new(),
+            shard_capacity: (max_entries / shards).max(1),
+            tick: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            expirations: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = Instant::now();
+        let shard = self.shard(key);
+        {
+            let data = shard.read().unwrap();
+            match data.entries.get(key) {
+                Some(entry) if entry.is_alive(now) => {
+                    entry.last_access.store(self.next_tick(), Ordering::Relaxed);
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Some(entry.value.clone());
+                }
+                Some(_) => {}
+                None => {
+                    self.misses.fetch_add(1, Ordering::Relaxed);
+                    return None;
+                }
+            }
+        }
+        // the key has expired, drop it while we are here
+        let mut data = shard.write().unwrap();
+        if let Some(entry) = data.entries.get(key) {
+            if !entry.is_alive(now) {
+                let expire_key = entry.expire_key;
+                data.entries.remove(key);
+                data.expiry.remove(&expire_key);
+                self.expirations.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        None
+    }
+
+    pub fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) {
+        let mut data = self.shard(&key).write().unwrap();
+        self.store(&mut data, key, value, ttl);
+    }
+
+    /// Inserts the value only when the key is absent or expired, returning
+    /// whether this call stored it.
+    pub fn insert_if_absent(&self, key: K, value: V, ttl: Duration) -> bool {
+        let now = Instant::now();
+        let mut data = self.shard(&key).write().unwrap();
+        if matches!(data.entries.get(&key), Some(entry) if entry.is_alive(now)) {
+            return false;
+        }
+        self.store(&mut data, key, value, ttl);
+        true
+    }
+
+    /// Replaces the value under the shard lock. An entry that is still alive
+    /// keeps its expiry, a new one lives for `ttl`.
+    pub fn update<F>(&self, key: K, ttl: Duration, f: F) -> V
+    where
+        F: FnOnce(Option<&V>) -> V,
+    {
+        let now = Instant::now();
+        let mut data = self.shard(&key).write().unwrap();
+        if let Some(entry) = data.entries.get_mut(&key) {
+            if entry.is_alive(now) {
+                entry.value = f(Some(&entry.value));
+                entry.last_access.store(self.next_tick(), Ordering::Relaxed);
+                return entry.value.clone();
+            }
+        }
+        let value = f(None);
+        self.store(&mut data, key, value.clone(), ttl);
+        value
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let mut data = self.shard(key).write().unwrap();
+        let entry = data.entries.remove(key)?;
+        data.expiry.remove(&entry.expire_key);
+        Some(entry.value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap().entries.len())
+            .sum()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            expirations: self.expirations.load(Ordering::Relaxed),
+            entries: self.len(),
+        }
+    }
+
+    /// Drops every expired entry and returns how many were removed.
+    pub fn purge_expired(&self) -> usize {
+        let now = Instant::now();
+        self.shards
+            .iter()
+            .map(|shard| self.purge_shard(&mut shard.write().unwrap(), now))
+            .sum()
+    }
+
+    pub async fn cleanup(&self, period: Duration) {
+        let mut interval = interval(period);
+        loop {
+            interval.tick().await;
+            let purged = self.purge_expired();
+            debug!(
+                "cache purged {} expired keys, stats: {:?}",
+                purged,
+                self.stats()
+            );
+        }
+    }
+
+    fn store(&self, data: &mut Shard<K, V>, key: K, value: V, ttl: Duration) {
+        let now = Instant::now();
+        let tick = self.next_tick();
+        let expire_key = (now + ttl, tick);
+
+        if let Some(old) = data.entries.remove(&key) {
+            data.expiry.remove(&old.expire_key);
+        } else if data.entries.len() >= self.shard_capacity {
+            self.purge_shard(data, now);
+            if data.entries.len() >= self.shard_capacity {
+                self.evict_lru(data);
+            }
+        }
+
+        data.expiry.insert(expire_key, key.clone());
+        data.entries.insert(
+            key,
+            Entry {
+                value,
+                expire_key,
+                last_access: AtomicU64::new(tick),
+            },
+        );
+    }
+
+    fn purge_shard(&self, data: &mut Shard<K, V>, now: Instant) -> usize {
+        let mut removed = 0;
+        while let Some(entry) = data.expiry.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let key = entry.remove();
+            data.entries.remove(&key);
+            removed += 1;
+        }
+        self.expirations
+            .fetch_add(removed as u64, Ordering::Relaxed);
+        removed
+    }
+
+    fn evict_lru(&self, data: &mut Shard<K, V>) {
+        let oldest = data
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_access.load(Ordering::Relaxed))
+            .map(|(key, entry)| (key.clone(), entry.expire_key));
+        if let Some((key, expire_key)) = oldest {
+            data.entries.remove(&key);
+            data.expiry.remove(&expire_key);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn shard<Q>(&self, key: &Q) -> &RwLock<Shard<K, V>>
+    where
+        Q: Hash + ?Sized,
+    {
+        &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()]
+    }
+
+    fn next_tick(&self) -> u64 {
+        self.tick.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::sleep;
+
+    #[tokio::test]
+    async fn test_cache() {
+        let cache: Cache<String, Instant> = Cache::new(100);
+        let key = "test_key";
+        let value = Instant::now();
+
+        // Test insert and get methods
+        cache.insert_with_ttl(key.to_string(), value, Duration::from_secs(1));
+        assert_eq!(cache.get(key), Some(value));
+        assert_eq!(cache.get("nonexistent_key"), None);
+
+        // Test remove method
+        assert_eq!(cache.remove(key), Some(value));
+        assert_eq!(cache.get(key), None);
+
+        // Test expiry and cleanup
+        cache.insert_with_ttl(key.to_string(), value, Duration::from_millis(50));
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(cache.purge_expired(), 1);
+        assert_eq!(cache.get(key), None);
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 3);
+        assert_eq!(stats.expirations, 1);
+        assert_eq!(stats.entries, 0);
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let cache: Cache<&str, u32> = Cache::with_shards(2, 1);
+        let ttl = Duration::from_secs(60);
+        cache.insert_with_ttl("a", 1, ttl);
+        cache.insert_with_ttl("b", 2, ttl);
+        // touch "a" so that "b" becomes the eviction candidate
+        assert_eq!(cache.get("a"), Some(1));
+        cache.insert_with_ttl("c", 3, ttl);
+
+        assert_eq!(cache.get("a"), Some(1));
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("c"), Some(3));
+        assert_eq!(cache.stats().evictions, 1);
+    }
+}
@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use ::redis::{aio::ConnectionManager, AsyncCommands, Client, Script};
+use async_trait::async_trait;
+
+use crate::error::Result;
+
+use super::CacheStore;
+
+// INCRBY only starts the TTL on the first increment, like the memory backend.
+const INCR_SCRIPT: &str = r#"
+local value = redis.call('INCRBY', KEYS[1], ARGV[1])
+if redis.call('PTTL', KEYS[1]) < 0 then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return value
+"#;
+
+/// Backend shared by every instance behind the load balancer.
+pub struct RedisStore {
+    connection: ConnectionManager,
+    key_prefix: String,
+    incr_script: Script,
+}
+
+impl RedisStore {
+    pub async fn connect(url: &str, key_prefix: &str) -> Result<RedisStore> {
+        let client = Client::open(url)?;
+        let connection = ConnectionManager::new(client).await?;
+        Ok(RedisStore {
+            connection,
+            key_prefix: key_prefix.to_string(),
+            incr_script: Script::new(INCR_SCRIPT),
+        })
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.key_prefix, key)
+    }
+}
+
+fn ttl_millis(ttl: Duration) -> usize {
+    (ttl.as_millis() as usize).max(1)
+}
+
+#[async_trait]
+impl CacheStore for RedisStore {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        let mut connection = self.connection.clone();
+        Ok(connection.get(self.key(key)).await?)
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Duration) -> Result<()> {
+        let mut connection = self.connection.clone();
+        ::redis::cmd("SET")
+            .arg(self.key(key))
+            .arg(value)
+            .arg("PX")
+            .arg(ttl_millis(ttl))
+            .query_async::<_, ()>(&mut connection)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_nx(&self, key: &str, value: &str, ttl: Duration) -> Result<bool> {
+        let mut connection = self.connection.clone();
+        let reply: Option<String> = ::redis::cmd("SET")
+            .arg(self.key(key))
+            .arg(value)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl_millis(ttl))
+            .query_async(&mut connection)
+            .await?;
+        Ok(reply.is_some())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let mut connection = self.connection.clone();
+        connection.del::<_, ()>(self.key(key)).await?;
+        Ok(())
+    }
+
+    async fn incr(&self, key: &str, delta: i64, ttl: Duration) -> Result<i64> {
+        let mut connection = self.connection.clone();
+        Ok(self
+            .incr_script
+            .key(self.key(key))
+            .arg(delta)
+            .arg(ttl_millis(ttl))
+            .invoke_async(&mut connection)
+            .await?)
+    }
+
+    async fn ping(&self) -> Result<()> {
+        let mut connection = self.connection.clone();
+        ::redis::cmd("PING")
+            .query_async::<_, ()>(&mut connection)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs against the redis-server given by REDIS_URL, e.g. redis://127.0.0.1/
+    #[tokio::test]
+    async fn test_redis_store() {
+        let url = match std::env::var("REDIS_URL") {
+            Ok(url) => url,
+            Err(_) => return,
+        };
+        let store = RedisStore::connect(&url, "we_chat_gpt_test:")
+            .await
+            .unwrap();
+        let ttl = Duration::from_secs(5);
+        store.delete("dedup").await.unwrap();
+        store.delete("counter").await.unwrap();
+
+        assert!(store.set_nx("dedup", "1", ttl).await.unwrap());
+        assert!(!store.set_nx("dedup", "1", ttl).await.unwrap());
+        assert_eq!(store.get("dedup").await.unwrap(), Some("1".to_string()));
+
+        assert_eq!(store.incr("counter", 1, ttl).await.unwrap(), 1);
+        assert_eq!(store.incr("counter", 2, ttl).await.unwrap(), 3);
+
+        store.delete("dedup").await.unwrap();
+        assert_eq!(store.get("dedup").await.unwrap(), None);
+    }
+}
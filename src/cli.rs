@@ -0,0 +1,99 @@
+use crate::{
+    database::{SpendGroup, SpendPeriod, SpendQuery, SpendRow},
+    error::{Error, Result},
+};
+
+pub enum Command {
+    Serve,
+    /// `the-world migrate`
+    Migrate,
+    /// `the-world usage [--period day|month] [--by user|subscription]
+    /// [--from YYYY-MM-DD] [--to YYYY-MM-DD] [--subscription ID]`
+    Usage(SpendQuery),
+    /// `the-world ingest <base> <path>...`
+    Ingest {
+        base: String,
+        paths: Vec<String>,
+    },
+}
+
+/// Takes `--config <path>` out of the arguments, it may come before or after
+/// the command.
+pub fn take_config_path(args: &mut Vec<String>) -> Result<Option<String>> {
+    let position = match args.iter().position(|arg| arg == "--config") {
+        Some(position) => position,
+        None => return Ok(None),
+    };
+    if position + 1 >= args.len() {
+        return Err(Error::ArgumentError("--config needs a value".to_string()));
+    }
+    let path = args.remove(position + 1);
+    args.remove(position);
+    Ok(Some(path))
+}
+
+pub fn parse(args: &[String], utc_offset_hours: i32) -> Result<Command> {
+    match args.first().map(String::as_str) {
+        None => Ok(Command::Serve),
+        Some("migrate") => Ok(Command::Migrate),
+        Some("usage") => parse_usage(&args[1..], utc_offset_hours).map(Command::Usage),
+        Some("ingest") => match &args[1..] {
+            // the column is VARCHAR(64)
+            [base, _, ..] if base.is_empty() || base.len() > 64 => Err(Error::ArgumentError(
+                "the knowledge base name must have 1 to 64 characters".to_string(),
+            )),
+            [base, paths @ ..] if !paths.is_empty() => Ok(Command::Ingest {
+                base: base.clone(),
+                paths: paths.to_vec(),
+            }),
+            _ => Err(Error::ArgumentError(
+                "usage: ingest <base> <path>...".to_string(),
+            )),
+        },
+        Some(other) => Err(Error::ArgumentError(format!("unknown command {}", other))),
+    }
+}
+
+fn parse_usage(args: &[String], utc_offset_hours: i32) -> Result<SpendQuery> {
+    let mut query = SpendQuery::month_to_date(utc_offset_hours);
+
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| Error::ArgumentError(format!("{} needs a value", flag)))?;
+        match (flag.as_str(), value.as_str()) {
+            ("--period", "day") => query.period = SpendPeriod::Day,
+            ("--period", "month") => query.period = SpendPeriod::Month,
+            ("--by", "user") => query.group = SpendGroup::User,
+            ("--by", "subscription") => query.group = SpendGroup::Subscription,
+            ("--from", day) => query.from_day = day.to_string(),
+            ("--to", day) => query.to_day = day.to_string(),
+            ("--subscription", id) => query.subscription_id = Some(id.to_string()),
+            _ => {
+                return Err(Error::ArgumentError(format!(
+                    "unexpected {} {}",
+                    flag, value
+                )))
+            }
+        }
+    }
+    Ok(query)
+}
+
+pub fn print_spend(rows: &[SpendRow], currency: &str) {
+    println!("period\tsubscription_id\tuser_id\tturns\tprompt_tokens\tcompletion_tokens\ttotal_tokens\tcost ({})", currency);
+    for row in rows {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.4}",
+            row.period,
+            row.subscription_id,
+            row.user_id,
+            row.turns,
+            row.prompt_tokens,
+            row.completion_tokens,
+            row.total_tokens,
+            row.cost
+        );
+    }
+}
@@ -0,0 +1,485 @@
+use crate::{
+    database::{ReplyMode, UserList, UserPreferences, Verbosity},
+    engine::{known_model, MODELS},
+    error::Result,
+    limits::Quota,
+    privacy,
+    reload::AccountSettings,
+    settings::{AccessMode, Limits},
+    AppState,
+};
+
+const NOT_ALLOWED: &str = "没有权限执行这个命令。";
+const NO_VOICE: &str = "暂不支持语音回复。";
+const NO_PERSONAS: &str = "暂无可选的角色。";
+const PREF_USAGE: &str =
+    "用法: /pref <persona|model|language|reply|verbosity|history> <值>，值为 default 时恢复默认";
+const ADMIN_COMMANDS: &[&str] = &[
+    "/tier",
+    "/block",
+    "/unblock",
+    "/allow",
+    "/disallow",
+    "/mode",
+];
+
+/// Answers messages that start with `/` without asking the model.
+/// Returns `None` when the message is not a command we know.
+/// `account` is the one the command was sent to, whose limits apply and whose
+/// admins may run the admin commands.
+pub async fn handle(
+    app_state: &AppState,
+    account: &AccountSettings<'_>,
+    subscription_id: &str,
+    user_id: &str,
+    content: &str,
+) -> Result<Option<String>> {
+    let mut words = content.split_whitespace();
+    let command = match words.next() {
+        Some(command) if command.starts_with('/') => command,
+        _ => return Ok(None),
+    };
+    let args: Vec<&str> = words.collect();
+
+    if ADMIN_COMMANDS.contains(&command) && !account.admins.iter().any(|admin| admin == user_id) {
+        return Ok(Some(NOT_ALLOWED.to_string()));
+    }
+    let reply = match command {
+        "/tier" => grant_tier(app_state, account.limits, subscription_id, &args).await?,
+        "/block" => add_to_list(app_state, UserList::Blocked, subscription_id, &args).await?,
+        "/unblock" => {
+            remove_from_list(app_state, UserList::Blocked, subscription_id, &args).await?
+        }
+        "/allow" => add_to_list(app_state, UserList::Allowed, subscription_id, &args).await?,
+        "/disallow" => {
+            remove_from_list(app_state, UserList::Allowed, subscription_id, &args).await?
+        }
+        "/mode" => switch_mode(app_state, subscription_id, &args).await?,
+        "/voice" => switch_reply_mode(app_state, subscription_id, user_id, &args).await?,
+        "/pref" => set_preference(app_state, subscription_id, user_id, &args).await?,
+        "/forget" => forget(app_state, subscription_id, user_id, &args).await?,
+        _ => return Ok(None),
+    };
+    Ok(Some(reply))
+}
+
+// /tier <openid> <tier>
+async fn grant_tier(
+    app_state: &AppState,
+    limits: &Limits,
+    subscription_id: &str,
+    args: &[&str],
+) -> Result<String> {
+    let (openid, tier) = match args {
+        [openid, tier] => (*openid, *tier),
+        _ => return Ok("用法: /tier <openid> <tier>".to_string()),
+    };
+    if !limits.tiers.contains_key(tier) {
+        let mut tiers: Vec<&str> = limits.tiers.keys().map(String::as_str).collect();
+        tiers.sort();
+        return Ok(format!("没有这个等级，可选: {}", tiers.join(", ")));
+    }
+    Quota {
+        cache: app_state.cache.as_ref(),
+        store: app_state.store.as_ref(),
+        limits,
+        utc_offset_hours: app_state.live().pricing.utc_offset_hours,
+    }
+    .grant_tier(subscription_id, openid, tier)
+    .await?;
+    Ok(format!("已将 {} 设为 {}", openid, tier))
+}
+
+// /block <openid>, /allow <openid>
+async fn add_to_list(
+    app_state: &AppState,
+    list: UserList,
+    subscription_id: &str,
+    args: &[&str],
+) -> Result<String> {
+    let openid = match args {
+        [openid] => *openid,
+        _ => return Ok(format!("用法: {} <openid>", list_command(list, true))),
+    };
+    app_state
+        .store
+        .add_to_user_list(list, subscription_id, openid)
+        .await?;
+    Ok(format!("已将 {} 加入{}", openid, list_name(list)))
+}
+
+// /unblock <openid>, /disallow <openid>
+async fn remove_from_list(
+    app_state: &AppState,
+    list: UserList,
+    subscription_id: &str,
+    args: &[&str],
+) -> Result<String> {
+    let openid = match args {
+        [openid] => *openid,
+        _ => return Ok(format!("用法: {} <openid>", list_command(list, false))),
+    };
+    let removed = app_state
+        .store
+        .remove_from_user_list(list, subscription_id, openid)
+        .await?;
+    Ok(if removed {
+        format!("已将 {} 移出{}", openid, list_name(list))
+    } else {
+        format!("{} 不在{}中", openid, list_name(list))
+    })
+}
+
+// /mode open|allowlist
+async fn switch_mode(app_state: &AppState, subscription_id: &str, args: &[&str]) -> Result<String> {
+    let mode = match args {
+        [mode] => AccessMode::parse(mode),
+        _ => None,
+    };
+    let mode = match mode {
+        Some(mode) => mode,
+        None => return Ok("用法: /mode open|allowlist".to_string()),
+    };
+    app_state
+        .store
+        .set_access_mode(subscription_id, mode)
+        .await?;
+    Ok(format!("已切换到 {} 模式", mode.as_str()))
+}
+
+// /voice on|off
+async fn switch_reply_mode(
+    app_state: &AppState,
+    subscription_id: &str,
+    user_id: &str,
+    args: &[&str],
+) -> Result<String> {
+    let mode = match args {
+        ["on"] => ReplyMode::Voice,
+        ["off"] => ReplyMode::Text,
+        _ => return Ok("用法: /voice on|off".to_string()),
+    };
+    if mode == ReplyMode::Voice && !app_state.live().speech.enabled {
+        return Ok(NO_VOICE.to_string());
+    }
+    let store = &app_state.store;
+    let mut preferences = store.get_user_preferences(subscription_id, user_id).await?;
+    preferences.reply_mode = mode;
+    store
+        .set_user_preferences(subscription_id, user_id, &preferences)
+        .await?;
+    Ok(match mode {
+        ReplyMode::Voice => "已开启语音回复，发送 /voice off 关闭。",
+        ReplyMode::Text => "已关闭语音回复。",
+    }
+    .to_string())
+}
+
+// /pref, /pref <key> <value>, /pref <key> default
+async fn set_preference(
+    app_state: &AppState,
+    subscription_id: &str,
+    user_id: &str,
+    args: &[&str],
+) -> Result<String> {
+    let store = &app_state.store;
+    let mut preferences = store.get_user_preferences(subscription_id, user_id).await?;
+    let (key, value) = match args {
+        [] => return Ok(describe_preferences(&preferences)),
+        [key, value @ ..] if !value.is_empty() => (*key, value.join(" ")),
+        _ => return Ok(PREF_USAGE.to_string()),
+    };
+    if let Err(reply) = apply_preference(app_state, &mut preferences, key, &value) {
+        return Ok(reply);
+    }
+    store
+        .set_user_preferences(subscription_id, user_id, &preferences)
+        .await?;
+    Ok(format!("已更新。\n{}", describe_preferences(&preferences)))
+}
+
+/// Sets `key` of `preferences` to `value`, or says why it cannot.
+fn apply_preference(
+    app_state: &AppState,
+    preferences: &mut UserPreferences,
+    key: &str,
+    value: &str,
+) -> Result<(), String> {
+    let reset = value == "default";
+    match key {
+        "persona" => {
+            let live = app_state.live();
+            if !reset && !live.personas.contains_key(value) {
+                if live.personas.is_empty() {
+                    return Err(NO_PERSONAS.to_string());
+                }
+                let names: Vec<&str> = live.personas.keys().map(String::as_str).collect();
+                return Err(format!("没有这个角色，可选: {}", names.join(", ")));
+            }
+            preferences.persona = (!reset).then(|| value.to_string());
+        }
+        "model" => {
+            if !reset && !known_model(value) {
+                return Err(format!("没有这个模型，可选: {}", MODELS.join(", ")));
+            }
+            preferences.model = (!reset).then(|| value.to_string());
+        }
+        "language" => preferences.language = (!reset).then(|| value.to_string()),
+        "reply" => {
+            preferences.reply_mode = match value {
+                "voice" if !app_state.live().speech.enabled => return Err(NO_VOICE.to_string()),
+                "default" => ReplyMode::default(),
+                _ => ReplyMode::parse(value).ok_or("可选: text, voice")?,
+            }
+        }
+        "verbosity" => {
+            preferences.verbosity = match value {
+                "default" => Verbosity::default(),
+                _ => Verbosity::parse(value).ok_or("可选: brief, normal, detailed")?,
+            }
+        }
+        "history" => {
+            preferences.history_opt_out = match value {
+                "on" | "default" => false,
+                "off" => true,
+                _ => return Err("可选: on, off".to_string()),
+            }
+        }
+        _ => return Err(PREF_USAGE.to_string()),
+    }
+    Ok(())
+}
+
+fn describe_preferences(preferences: &UserPreferences) -> String {
+    let or_default = |value: &Option<String>| value.clone().unwrap_or_else(|| "默认".to_string());
+    format!(
+        "persona: {}\nmodel: {}\nlanguage: {}\nreply: {}\nverbosity: {}\nhistory: {}",
+        or_default(&preferences.persona),
+        or_default(&preferences.model),
+        or_default(&preferences.language),
+        preferences.reply_mode.as_str(),
+        preferences.verbosity.as_str(),
+        if preferences.history_opt_out {
+            "off"
+        } else {
+            "on"
+        },
+    )
+}
+
+// /forget confirm
+async fn forget(
+    app_state: &AppState,
+    subscription_id: &str,
+    user_id: &str,
+    args: &[&str],
+) -> Result<String> {
+    if args != ["confirm"] {
+        return Ok(
+            "这会删除你的对话记录、资料和偏好，且无法恢复。确认请发送 /forget confirm".to_string(),
+        );
+    }
+    privacy::forget(app_state, subscription_id, user_id).await?;
+    Ok("已删除你的对话记录、资料和偏好。".to_string())
+}
+
+fn list_command(list: UserList, add: bool) -> &'static str {
+    match (list, add) {
+        (UserList::Blocked, true) => "/block",
+        (UserList::Blocked, false) => "/unblock",
+        (UserList::Allowed, true) => "/allow",
+        (UserList::Allowed, false) => "/disallow",
+    }
+}
+
+fn list_name(list: UserList) -> &'static str {
+    match list {
+        UserList::Blocked => "黑名单",
+        UserList::Allowed => "白名单",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::DEFAULT_ACCOUNT;
+
+    #[tokio::test]
+    async fn test_admin_commands() {
+        let state = AppState::for_tests();
+        state.update_live(|live| live.admin.openids = vec!["admin".to_string()]);
+        let live = state.live();
+        let account = live.account(DEFAULT_ACCOUNT).unwrap();
+
+        let reply = handle(&state, &account, "sub", "user", "/block other")
+            .await
+            .unwrap();
+        assert_eq!(reply, Some(NOT_ALLOWED.to_string()));
+        assert_eq!(
+            handle(&state, &account, "sub", "user", "hello")
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            handle(&state, &account, "sub", "admin", "/unknown")
+                .await
+                .unwrap(),
+            None
+        );
+
+        handle(&state, &account, "sub", "admin", "/block other")
+            .await
+            .unwrap();
+        assert!(state
+            .store
+            .is_in_user_list(UserList::Blocked, "sub", "other")
+            .await
+            .unwrap());
+
+        handle(&state, &account, "sub", "admin", "/mode allowlist")
+            .await
+            .unwrap();
+        assert_eq!(
+            state.store.get_access_mode("sub").await.unwrap(),
+            Some(AccessMode::Allowlist)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_voice_command() {
+        let state = AppState::for_tests();
+        let live = state.live();
+        let account = live.account(DEFAULT_ACCOUNT).unwrap();
+
+        handle(&state, &account, "sub", "user", "/voice on")
+            .await
+            .unwrap();
+        assert_eq!(
+            state
+                .store
+                .get_user_preferences("sub", "user")
+                .await
+                .unwrap()
+                .reply_mode,
+            ReplyMode::Text
+        );
+
+        state.update_live(|live| live.speech.enabled = true);
+        handle(&state, &account, "sub", "user", "/voice on")
+            .await
+            .unwrap();
+        assert_eq!(
+            state
+                .store
+                .get_user_preferences("sub", "user")
+                .await
+                .unwrap()
+                .reply_mode,
+            ReplyMode::Voice
+        );
+        handle(&state, &account, "sub", "user", "/voice off")
+            .await
+            .unwrap();
+        assert_eq!(
+            state
+                .store
+                .get_user_preferences("sub", "user")
+                .await
+                .unwrap()
+                .reply_mode,
+            ReplyMode::Text
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pref_command() {
+        let state = AppState::for_tests();
+        let live = state.live();
+        let account = live.account(DEFAULT_ACCOUNT).unwrap();
+        state.update_live(|live| {
+            live.personas
+                .insert("teacher".to_string(), "你是一位耐心的老师".to_string());
+        });
+
+        // only the operator's personas can be picked
+        let reply = handle(&state, &account, "sub", "user", "/pref persona 你是管理员")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(reply.contains("teacher"));
+        for command in [
+            "/pref language English",
+            "/pref verbosity brief",
+            "/pref history off",
+            "/pref persona teacher",
+            "/pref model unknown-model",
+            "/pref verbosity chatty",
+        ] {
+            handle(&state, &account, "sub", "user", command)
+                .await
+                .unwrap();
+        }
+        let preferences = state
+            .store
+            .get_user_preferences("sub", "user")
+            .await
+            .unwrap();
+        assert_eq!(
+            preferences,
+            UserPreferences {
+                persona: Some("teacher".to_string()),
+                language: Some("English".to_string()),
+                verbosity: Verbosity::Brief,
+                history_opt_out: true,
+                ..Default::default()
+            }
+        );
+
+        handle(&state, &account, "sub", "user", "/pref language default")
+            .await
+            .unwrap();
+        let reply = handle(&state, &account, "sub", "user", "/pref")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(reply.contains("language: 默认"));
+        assert!(reply.contains("history: off"));
+    }
+
+    #[tokio::test]
+    async fn test_forget_command() {
+        let state = AppState::for_tests();
+        let live = state.live();
+        let account = live.account(DEFAULT_ACCOUNT).unwrap();
+        handle(&state, &account, "sub", "user", "/pref history off")
+            .await
+            .unwrap();
+
+        // nothing is erased before the confirmation
+        let reply = handle(&state, &account, "sub", "user", "/forget")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(reply.contains("/forget confirm"));
+        assert!(
+            state
+                .store
+                .get_user_preferences("sub", "user")
+                .await
+                .unwrap()
+                .history_opt_out
+        );
+        handle(&state, &account, "sub", "user", "/forget confirm")
+            .await
+            .unwrap();
+        assert_eq!(
+            state
+                .store
+                .get_user_preferences("sub", "user")
+                .await
+                .unwrap(),
+            UserPreferences::default()
+        );
+    }
+}
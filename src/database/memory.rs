@@ -0,0 +1,640 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+
+use crate::{error::Result, settings::AccessMode};
+
+use super::{
+    day_of, now_millis, Conversation, ConversationStore, DailyUsage, ErasedRows, KeywordRule,
+    KnowledgeChunk, KnowledgeVersion, ListedUser, ModerationAudit, PendingReply, Persona,
+    SpendGroup, SpendPeriod, SpendQuery, SpendRow, Turn, TurnQuery, TurnRecord, TurnStatus,
+    UserList, UserPreferences, WechatUser, ERASED_USER, LIMIT_COUNT, MAX_TURNS,
+};
+
+/// The chunks of a source with the time they were ingested.
+type IngestedChunks = (Vec<KnowledgeChunk>, i64);
+
+/// Keeps everything in process memory, for tests and throwaway runs.
+pub struct MemoryStore {
+    utc_offset_hours: i32,
+    /// Turns with their created time.
+    turns: Mutex<Vec<(Turn, i64)>>,
+    tiers: Mutex<HashMap<(String, String), String>>,
+    usage: Mutex<HashMap<(String, String, String), DailyUsage>>,
+    audits: Mutex<Vec<ModerationAudit>>,
+    user_lists: Mutex<BTreeMap<(&'static str, String, String), i64>>,
+    sessions: Mutex<HashMap<(String, String), String>>,
+    access_modes: Mutex<HashMap<String, AccessMode>>,
+    personas: Mutex<BTreeMap<String, Persona>>,
+    keyword_rules: Mutex<BTreeMap<(String, String), KeywordRule>>,
+    pending_replies: Mutex<BTreeMap<(String, String, i64), PendingReply>>,
+    /// Chunks by base and source.
+    knowledge: Mutex<BTreeMap<(String, String), IngestedChunks>>,
+    preferences: Mutex<HashMap<(String, String), UserPreferences>>,
+    wechat_users: Mutex<HashMap<(String, String), WechatUser>>,
+}
+
+impl MemoryStore {
+    pub fn new(utc_offset_hours: i32) -> MemoryStore {
+        MemoryStore {
+            utc_offset_hours,
+            turns: Mutex::new(vec![]),
+            tiers: Mutex::new(HashMap::new()),
+            usage: Mutex::new(HashMap::new()),
+            audits: Mutex::new(vec![]),
+            user_lists: Mutex::new(BTreeMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            access_modes: Mutex::new(HashMap::new()),
+            personas: Mutex::new(BTreeMap::new()),
+            keyword_rules: Mutex::new(BTreeMap::new()),
+            pending_replies: Mutex::new(BTreeMap::new()),
+            knowledge: Mutex::new(BTreeMap::new()),
+            preferences: Mutex::new(HashMap::new()),
+            wechat_users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn today(&self) -> String {
+        day_of(now_millis(), self.utc_offset_hours)
+    }
+}
+
+#[async_trait]
+impl ConversationStore for MemoryStore {
+    async fn migrate(&self) -> Result<Vec<&'static str>> {
+        Ok(vec![])
+    }
+
+    async fn save_turn(&self, turn: &Turn) -> Result<()> {
+        let mut turns = self.turns.lock().unwrap();
+        self.pending_replies.lock().unwrap().remove(&(
+            turn.subscription_id.clone(),
+            turn.user_id.clone(),
+            turn.msg_id,
+        ));
+        turns.push((turn.clone(), now_millis()));
+        Ok(())
+    }
+
+    async fn get_reply_by_msg_id(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        msg_id: i64,
+    ) -> Result<Option<String>> {
+        let turns = self.turns.lock().unwrap();
+        Ok(turns
+            .iter()
+            .map(|(t, _)| t)
+            .find(|t| {
+                t.subscription_id == subscription_id
+                    && t.user_id == user_id
+                    && t.msg_id == msg_id
+                    && t.status == TurnStatus::Ok
+            })
+            .map(|t| t.reply_message.clone()))
+    }
+
+    async fn get_conversations(
+        &self,
+        user_id: &str,
+        subscription_id: &str,
+        session_id: &str,
+    ) -> Result<Vec<Conversation>> {
+        let turns = self.turns.lock().unwrap();
+        let mut conversations: Vec<Conversation> = turns
+            .iter()
+            .rev()
+            .map(|(t, _)| t)
+            .filter(|t| {
+                t.user_id == user_id
+                    && t.subscription_id == subscription_id
+                    && t.session_id == session_id
+                    && t.status == TurnStatus::Ok
+            })
+            .take(LIMIT_COUNT as usize)
+            .map(|t| Conversation {
+                req_message: t.user_message.clone(),
+                resp_message: t.reply_message.clone(),
+            })
+            .collect();
+        conversations.reverse();
+        Ok(conversations)
+    }
+
+    async fn search_turns(&self, query: &TurnQuery) -> Result<Vec<TurnRecord>> {
+        let turns = self.turns.lock().unwrap();
+        Ok(turns
+            .iter()
+            .rev()
+            .filter(|(t, _)| {
+                query
+                    .subscription_id
+                    .as_deref()
+                    .is_none_or(|id| id == t.subscription_id)
+                    && query.user_id.as_deref().is_none_or(|id| id == t.user_id)
+                    && query
+                        .q
+                        .as_deref()
+                        .is_none_or(|q| t.user_message.contains(q) || t.reply_message.contains(q))
+            })
+            .skip(query.offset.max(0) as usize)
+            .take(query.limit.clamp(0, MAX_TURNS) as usize)
+            .map(|(t, created_time)| TurnRecord {
+                msg_id: t.msg_id,
+                user_id: t.user_id.clone(),
+                subscription_id: t.subscription_id.clone(),
+                session_id: t.session_id.clone(),
+                model: t.model.clone(),
+                user_message: t.user_message.clone(),
+                reply_message: t.reply_message.clone(),
+                status: t.status.as_str().to_string(),
+                error: t.error.clone(),
+                total_tokens: t.usage.total_tokens,
+                cost: t.cost,
+                created_time: *created_time,
+            })
+            .collect())
+    }
+
+    async fn spend(&self, query: &SpendQuery) -> Result<Vec<SpendRow>> {
+        let turns = self.turns.lock().unwrap();
+        let mut rows: BTreeMap<(String, String, String), SpendRow> = BTreeMap::new();
+        for (turn, created_time) in turns.iter() {
+            let day = day_of(*created_time, self.utc_offset_hours);
+            if day < query.from_day || day > query.to_day {
+                continue;
+            }
+            if matches!(&query.subscription_id, Some(id) if *id != turn.subscription_id) {
+                continue;
+            }
+            let period = match query.period {
+                SpendPeriod::Day => day.clone(),
+                SpendPeriod::Month => day[..7].to_string(),
+            };
+            let user_id = match query.group {
+                SpendGroup::User => turn.user_id.clone(),
+                SpendGroup::Subscription => String::new(),
+            };
+            let row = rows
+                .entry((
+                    period.clone(),
+                    turn.subscription_id.clone(),
+                    user_id.clone(),
+                ))
+                .or_insert_with(|| SpendRow {
+                    period,
+                    subscription_id: turn.subscription_id.clone(),
+                    user_id,
+                    turns: 0,
+                    prompt_tokens: 0,
+                    completion_tokens: 0,
+                    total_tokens: 0,
+                    cost: 0.0,
+                });
+            row.turns += 1;
+            row.prompt_tokens += turn.usage.prompt_tokens;
+            row.completion_tokens += turn.usage.completion_tokens;
+            row.total_tokens += turn.usage.total_tokens;
+            row.cost += turn.cost;
+        }
+        Ok(rows.into_values().collect())
+    }
+
+    async fn get_user_tier(&self, subscription_id: &str, user_id: &str) -> Result<Option<String>> {
+        let tiers = self.tiers.lock().unwrap();
+        Ok(tiers
+            .get(&(subscription_id.to_string(), user_id.to_string()))
+            .cloned())
+    }
+
+    async fn set_user_tier(&self, subscription_id: &str, user_id: &str, tier: &str) -> Result<()> {
+        self.tiers.lock().unwrap().insert(
+            (subscription_id.to_string(), user_id.to_string()),
+            tier.to_string(),
+        );
+        Ok(())
+    }
+
+    async fn record_usage(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        messages: i64,
+        tokens: i64,
+    ) -> Result<()> {
+        let key = (
+            subscription_id.to_string(),
+            user_id.to_string(),
+            self.today(),
+        );
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(key).or_default();
+        entry.messages += messages;
+        entry.tokens += tokens;
+        Ok(())
+    }
+
+    async fn get_daily_usage(
+        &self,
+        subscription_id: &str,
+        user_id: Option<&str>,
+    ) -> Result<DailyUsage> {
+        let today = self.today();
+        let usage = self.usage.lock().unwrap();
+        Ok(usage
+            .iter()
+            .filter(|((sub, user, day), _)| {
+                sub == subscription_id && *day == today && user_id.is_none_or(|id| id == user)
+            })
+            .fold(DailyUsage::default(), |total, (_, u)| DailyUsage {
+                messages: total.messages + u.messages,
+                tokens: total.tokens + u.tokens,
+            }))
+    }
+
+    async fn save_moderation_audit(&self, audit: &ModerationAudit) -> Result<()> {
+        self.audits.lock().unwrap().push(audit.clone());
+        Ok(())
+    }
+
+    async fn add_to_user_list(
+        &self,
+        list: UserList,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<()> {
+        self.user_lists.lock().unwrap().insert(
+            (
+                list.as_str(),
+                subscription_id.to_string(),
+                user_id.to_string(),
+            ),
+            now_millis(),
+        );
+        Ok(())
+    }
+
+    async fn remove_from_user_list(
+        &self,
+        list: UserList,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<bool> {
+        Ok(self
+            .user_lists
+            .lock()
+            .unwrap()
+            .remove(&(
+                list.as_str(),
+                subscription_id.to_string(),
+                user_id.to_string(),
+            ))
+            .is_some())
+    }
+
+    async fn is_in_user_list(
+        &self,
+        list: UserList,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<bool> {
+        Ok(self.user_lists.lock().unwrap().contains_key(&(
+            list.as_str(),
+            subscription_id.to_string(),
+            user_id.to_string(),
+        )))
+    }
+
+    async fn get_user_list(
+        &self,
+        list: UserList,
+        subscription_id: Option<&str>,
+    ) -> Result<Vec<ListedUser>> {
+        let user_lists = self.user_lists.lock().unwrap();
+        Ok(user_lists
+            .iter()
+            .filter(|((name, sub, _), _)| {
+                *name == list.as_str() && subscription_id.is_none_or(|id| id == sub)
+            })
+            .map(|((_, sub, user), created_time)| ListedUser {
+                subscription_id: sub.clone(),
+                user_id: user.clone(),
+                created_time: *created_time,
+            })
+            .collect())
+    }
+
+    async fn get_session_id(&self, subscription_id: &str, user_id: &str) -> Result<String> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(sessions
+            .get(&(subscription_id.to_string(), user_id.to_string()))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn set_session_id(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        session_id: &str,
+    ) -> Result<()> {
+        self.sessions.lock().unwrap().insert(
+            (subscription_id.to_string(), user_id.to_string()),
+            session_id.to_string(),
+        );
+        Ok(())
+    }
+
+    async fn get_access_mode(&self, subscription_id: &str) -> Result<Option<AccessMode>> {
+        Ok(self
+            .access_modes
+            .lock()
+            .unwrap()
+            .get(subscription_id)
+            .copied())
+    }
+
+    async fn set_access_mode(&self, subscription_id: &str, mode: AccessMode) -> Result<()> {
+        self.access_modes
+            .lock()
+            .unwrap()
+            .insert(subscription_id.to_string(), mode);
+        Ok(())
+    }
+
+    async fn get_personas(&self) -> Result<Vec<Persona>> {
+        Ok(self.personas.lock().unwrap().values().cloned().collect())
+    }
+
+    async fn get_persona(&self, subscription_id: &str) -> Result<Option<String>> {
+        let personas = self.personas.lock().unwrap();
+        Ok(personas.get(subscription_id).map(|p| p.prompt.clone()))
+    }
+
+    async fn set_persona(&self, subscription_id: &str, prompt: &str) -> Result<()> {
+        self.personas.lock().unwrap().insert(
+            subscription_id.to_string(),
+            Persona {
+                subscription_id: subscription_id.to_string(),
+                prompt: prompt.to_string(),
+                updated_time: now_millis(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn delete_persona(&self, subscription_id: &str) -> Result<bool> {
+        Ok(self
+            .personas
+            .lock()
+            .unwrap()
+            .remove(subscription_id)
+            .is_some())
+    }
+
+    async fn get_keyword_rules(&self, subscription_id: Option<&str>) -> Result<Vec<KeywordRule>> {
+        let rules = self.keyword_rules.lock().unwrap();
+        Ok(rules
+            .values()
+            .filter(|r| subscription_id.is_none_or(|id| id == r.subscription_id))
+            .cloned()
+            .collect())
+    }
+
+    async fn get_keyword_reply(
+        &self,
+        subscription_id: &str,
+        keyword: &str,
+    ) -> Result<Option<String>> {
+        let rules = self.keyword_rules.lock().unwrap();
+        Ok(rules
+            .get(&(subscription_id.to_string(), keyword.to_string()))
+            .map(|r| r.reply.clone()))
+    }
+
+    async fn set_keyword_rule(
+        &self,
+        subscription_id: &str,
+        keyword: &str,
+        reply: &str,
+    ) -> Result<()> {
+        self.keyword_rules.lock().unwrap().insert(
+            (subscription_id.to_string(), keyword.to_string()),
+            KeywordRule {
+                subscription_id: subscription_id.to_string(),
+                keyword: keyword.to_string(),
+                reply: reply.to_string(),
+                updated_time: now_millis(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn delete_keyword_rule(&self, subscription_id: &str, keyword: &str) -> Result<bool> {
+        Ok(self
+            .keyword_rules
+            .lock()
+            .unwrap()
+            .remove(&(subscription_id.to_string(), keyword.to_string()))
+            .is_some())
+    }
+
+    async fn save_pending_reply(&self, pending: &PendingReply) -> Result<()> {
+        self.pending_replies.lock().unwrap().insert(
+            (
+                pending.subscription_id.clone(),
+                pending.user_id.clone(),
+                pending.msg_id,
+            ),
+            pending.clone(),
+        );
+        Ok(())
+    }
+
+    async fn delete_pending_reply(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        msg_id: i64,
+    ) -> Result<()> {
+        self.pending_replies.lock().unwrap().remove(&(
+            subscription_id.to_string(),
+            user_id.to_string(),
+            msg_id,
+        ));
+        Ok(())
+    }
+
+    async fn get_pending_replies(&self) -> Result<Vec<PendingReply>> {
+        let mut pendings: Vec<_> = self
+            .pending_replies
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        pendings.sort_by_key(|pending| pending.created_time);
+        Ok(pendings)
+    }
+
+    async fn replace_knowledge_source(
+        &self,
+        base: &str,
+        source: &str,
+        chunks: &[KnowledgeChunk],
+    ) -> Result<()> {
+        self.knowledge.lock().unwrap().insert(
+            (base.to_string(), source.to_string()),
+            (chunks.to_vec(), now_millis()),
+        );
+        Ok(())
+    }
+
+    async fn get_knowledge_chunks(&self, base: &str) -> Result<Vec<KnowledgeChunk>> {
+        Ok(self
+            .knowledge
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((chunk_base, _), _)| chunk_base == base)
+            .flat_map(|(_, (chunks, _))| chunks.iter().cloned())
+            .collect())
+    }
+
+    async fn knowledge_version(&self, base: &str) -> Result<KnowledgeVersion> {
+        Ok(self
+            .knowledge
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|((chunk_base, _), _)| chunk_base == base)
+            .fold((0, 0), |(count, latest), (_, (chunks, created_time))| {
+                (count + chunks.len() as i64, latest.max(*created_time))
+            }))
+    }
+
+    async fn get_user_preferences(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<UserPreferences> {
+        Ok(self
+            .preferences
+            .lock()
+            .unwrap()
+            .get(&(subscription_id.to_string(), user_id.to_string()))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn set_user_preferences(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        preferences: &UserPreferences,
+    ) -> Result<()> {
+        self.preferences.lock().unwrap().insert(
+            (subscription_id.to_string(), user_id.to_string()),
+            preferences.clone(),
+        );
+        Ok(())
+    }
+
+    async fn delete_user_preferences(&self, subscription_id: &str, user_id: &str) -> Result<bool> {
+        Ok(self
+            .preferences
+            .lock()
+            .unwrap()
+            .remove(&(subscription_id.to_string(), user_id.to_string()))
+            .is_some())
+    }
+
+    async fn get_wechat_user(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<Option<WechatUser>> {
+        Ok(self
+            .wechat_users
+            .lock()
+            .unwrap()
+            .get(&(subscription_id.to_string(), user_id.to_string()))
+            .cloned())
+    }
+
+    async fn save_wechat_user(&self, user: &WechatUser) -> Result<()> {
+        self.wechat_users.lock().unwrap().insert(
+            (user.subscription_id.clone(), user.user_id.clone()),
+            user.clone(),
+        );
+        Ok(())
+    }
+
+    async fn forget_user(&self, subscription_id: &str, user_id: &str) -> Result<ErasedRows> {
+        let is_user = |s: &str, u: &str| s == subscription_id && u == user_id;
+        let key = (subscription_id.to_string(), user_id.to_string());
+        let mut erased = ErasedRows::new();
+
+        let mut turns = 0;
+        for (turn, _) in self.turns.lock().unwrap().iter_mut() {
+            if is_user(&turn.subscription_id, &turn.user_id) {
+                turn.user_id = ERASED_USER.to_string();
+                turn.session_id.clear();
+                turn.user_message.clear();
+                turn.reply_message.clear();
+                turn.error = None;
+                turns += 1;
+            }
+        }
+        erased.insert("dialogue_turn", turns);
+
+        let mut audited = 0;
+        for audit in self.audits.lock().unwrap().iter_mut() {
+            if is_user(&audit.subscription_id, &audit.user_id) {
+                audit.user_id = ERASED_USER.to_string();
+                audit.content.clear();
+                audited += 1;
+            }
+        }
+        erased.insert("moderation_audit", audited);
+
+        let mut usage = self.usage.lock().unwrap();
+        let days: Vec<_> = usage
+            .keys()
+            .filter(|(s, u, _)| is_user(s, u))
+            .cloned()
+            .collect();
+        for (s, u, day) in &days {
+            let theirs = usage.remove(&(s.clone(), u.clone(), day.clone())).unwrap();
+            let erased_usage = usage
+                .entry((s.clone(), ERASED_USER.to_string(), day.clone()))
+                .or_default();
+            erased_usage.messages += theirs.messages;
+            erased_usage.tokens += theirs.tokens;
+        }
+        erased.insert("daily_usage", days.len() as u64);
+        let mut pending = self.pending_replies.lock().unwrap();
+        let before = pending.len();
+        pending.retain(|_, reply| !is_user(&reply.subscription_id, &reply.user_id));
+        erased.insert("pending_reply", (before - pending.len()) as u64);
+
+        let removed = |found: bool| u64::from(found);
+        erased.insert(
+            "user_preference",
+            removed(self.preferences.lock().unwrap().remove(&key).is_some()),
+        );
+        erased.insert(
+            "user_session",
+            removed(self.sessions.lock().unwrap().remove(&key).is_some()),
+        );
+        erased.insert(
+            "wechat_user",
+            removed(self.wechat_users.lock().unwrap().remove(&key).is_some()),
+        );
+        Ok(erased)
+    }
+}
@@ -0,0 +1,407 @@
+use log::{info, warn};
+use sqlx::AnyPool;
+
+use crate::error::Result;
+
+use super::{day_of, now_millis, sql::Dialect, Conversation};
+
+struct Migration {
+    version: i64,
+    name: &'static str,
+    step: Step,
+}
+
+enum Step {
+    Sql {
+        mysql: &'static str,
+        postgres: &'static str,
+        sqlite: &'static str,
+    },
+    /// Copies the JSON rows of the old `wechat_dialogue_record` table into `dialogue_turn`.
+    ImportDialogueRecords,
+    /// Fills `dialogue_turn.created_day` for rows written before it existed.
+    BackfillCreatedDay,
+}
+
+macro_rules! sql_step {
+    ($name:literal) => {
+        Step::Sql {
+            mysql: include_str!(concat!("../../migrations/mysql/", $name, ".sql")),
+            postgres: include_str!(concat!("../../migrations/postgres/", $name, ".sql")),
+            sqlite: include_str!(concat!("../../migrations/sqlite/", $name, ".sql")),
+        }
+    };
+}
+
+// Append only: a released migration must never change.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_dialogue_turn",
+        step: sql_step!("0001_create_dialogue_turn"),
+    },
+    Migration {
+        version: 2,
+        name: "import_wechat_dialogue_record",
+        step: Step::ImportDialogueRecords,
+    },
+    Migration {
+        version: 3,
+        name: "add_turn_cost",
+        step: sql_step!("0003_add_turn_cost"),
+    },
+    Migration {
+        version: 4,
+        name: "backfill_created_day",
+        step: Step::BackfillCreatedDay,
+    },
+    Migration {
+        version: 5,
+        name: "create_limits",
+        step: sql_step!("0005_create_limits"),
+    },
+    Migration {
+        version: 6,
+        name: "create_moderation_audit",
+        step: sql_step!("0006_create_moderation_audit"),
+    },
+    Migration {
+        version: 7,
+        name: "create_admin_tables",
+        step: sql_step!("0007_create_admin_tables"),
+    },
+    Migration {
+        version: 8,
+        name: "create_account_access",
+        step: sql_step!("0008_create_account_access"),
+    },
+    Migration {
+        version: 9,
+        name: "create_pending_reply",
+        step: sql_step!("0009_create_pending_reply"),
+    },
+    Migration {
+        version: 10,
+        name: "add_pending_reply_account",
+        step: sql_step!("0010_add_pending_reply_account"),
+    },
+    Migration {
+        version: 11,
+        name: "create_knowledge_chunk",
+        step: sql_step!("0011_create_knowledge_chunk"),
+    },
+    Migration {
+        version: 12,
+        name: "create_user_preference",
+        step: sql_step!("0012_create_user_preference"),
+    },
+    Migration {
+        version: 13,
+        name: "add_user_preferences",
+        step: sql_step!("0013_add_user_preferences"),
+    },
+    Migration {
+        version: 14,
+        name: "create_wechat_user",
+        step: sql_step!("0014_create_wechat_user"),
+    },
+    Migration {
+        version: 15,
+        name: "scope_pending_reply",
+        step: sql_step!("0015_scope_pending_reply"),
+    },
+];
+
+/// Where turns were kept as JSON before `dialogue_turn`, left in place after
+/// the import.
+pub const LEGACY_TABLE: &str = "wechat_dialogue_record";
+const IMPORT_BATCH: i64 = 500;
+
+/// Applies every migration that is not recorded in `schema_migrations` yet,
+/// returning the names of the ones that ran.
+pub async fn run(
+    pool: &AnyPool,
+    dialect: Dialect,
+    utc_offset_hours: i32,
+) -> Result<Vec<&'static str>> {
+    sqlx::query("CREATE TABLE IF NOT EXISTS schema_migrations (version BIGINT NOT NULL PRIMARY KEY, name VARCHAR(255) NOT NULL, applied_time BIGINT NOT NULL)")
+        .execute(pool)
+        .await?;
+    let applied: Vec<(i64,)> = sqlx::query_as("SELECT version FROM schema_migrations")
+        .fetch_all(pool)
+        .await?;
+
+    let mut ran = vec![];
+    for migration in MIGRATIONS {
+        if applied
+            .iter()
+            .any(|(version,)| *version == migration.version)
+        {
+            continue;
+        }
+        info!(
+            "applying migration {} {}",
+            migration.version, migration.name
+        );
+        match &migration.step {
+            Step::Sql {
+                mysql,
+                postgres,
+                sqlite,
+            } => {
+                let script = match dialect {
+                    Dialect::MySql => mysql,
+                    Dialect::Postgres => postgres,
+                    Dialect::Sqlite => sqlite,
+                };
+                for statement in statements(script) {
+                    if dialect == Dialect::MySql && mysql_index_exists(pool, statement).await? {
+                        continue;
+                    }
+                    sqlx::query(statement).execute(pool).await?;
+                }
+            }
+            Step::ImportDialogueRecords => import_dialogue_records(pool, dialect).await?,
+            Step::BackfillCreatedDay => {
+                backfill_created_day(pool, dialect, utc_offset_hours).await?
+            }
+        }
+        sqlx::query(
+            &dialect
+                .sql("INSERT INTO schema_migrations(version, name, applied_time) VALUES (?, ?, ?)"),
+        )
+        .bind(migration.version)
+        .bind(migration.name)
+        .bind(now_millis())
+        .execute(pool)
+        .await?;
+        ran.push(migration.name);
+    }
+    Ok(ran)
+}
+
+/// Splits a script on the `;` that end statements, leaving the ones inside
+/// quoted literals, quoted identifiers and `--` comments alone.
+fn statements(script: &str) -> Vec<&str> {
+    let mut statements = vec![];
+    let mut start = 0;
+    let mut quote = None;
+    let mut comment = false;
+    let mut chars = script.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if comment {
+            comment = c != '\n';
+            continue;
+        }
+        match (quote, c) {
+            (Some(q), c) if c == q => {
+                // a doubled quote is an escaped one
+                if chars.peek().map(|(_, next)| *next) == Some(q) {
+                    chars.next();
+                } else {
+                    quote = None;
+                }
+            }
+            (Some(_), '\\') => {
+                chars.next();
+            }
+            (Some(_), _) => {}
+            (None, '\'' | '"' | '`') => quote = Some(c),
+            (None, '-') if chars.peek().map(|(_, next)| *next) == Some('-') => comment = true,
+            (None, ';') => {
+                statements.push(&script[start..i]);
+                start = i + 1;
+            }
+            (None, _) => {}
+        }
+    }
+    statements.push(&script[start..]);
+    statements
+        .into_iter()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// MySQL has no `CREATE INDEX IF NOT EXISTS`, so a migration that failed
+/// half way would trip over its own indexes when it runs again.
+async fn mysql_index_exists(pool: &AnyPool, statement: &str) -> Result<bool> {
+    let Some((index, table)) = created_index(statement) else {
+        return Ok(false);
+    };
+    let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM information_schema.statistics WHERE table_schema = DATABASE() AND table_name = ? AND index_name = ?")
+        .bind(table)
+        .bind(index)
+        .fetch_one(pool)
+        .await?;
+    Ok(count > 0)
+}
+
+/// The index and table names of a `CREATE [UNIQUE] INDEX name ON table` statement.
+fn created_index(statement: &str) -> Option<(&str, &str)> {
+    let mut words = statement.split_whitespace();
+    if !words.next()?.eq_ignore_ascii_case("CREATE") {
+        return None;
+    }
+    let mut word = words.next()?;
+    if word.eq_ignore_ascii_case("UNIQUE") {
+        word = words.next()?;
+    }
+    if !word.eq_ignore_ascii_case("INDEX") {
+        return None;
+    }
+    let index = words.next()?;
+    if !words.next()?.eq_ignore_ascii_case("ON") {
+        return None;
+    }
+    let table = words.next()?.split('(').next()?;
+    Some((index, table))
+}
+
+async fn import_dialogue_records(pool: &AnyPool, dialect: Dialect) -> Result<()> {
+    let (exists,): (i64,) = sqlx::query_as(&dialect.sql(dialect.table_exists_sql()))
+        .bind(LEGACY_TABLE)
+        .fetch_one(pool)
+        .await?;
+    if exists == 0 {
+        return Ok(());
+    }
+
+    let select = format!(
+        "SELECT {}, user_id, subscription_id, message, {}, {} FROM {} ORDER BY created_time, msg_id LIMIT ? OFFSET ?",
+        dialect.cast_int("msg_id"),
+        dialect.cast_int("elapsed"),
+        dialect.epoch_millis("created_time"),
+        LEGACY_TABLE
+    );
+    let insert = dialect.sql("INSERT INTO dialogue_turn(msg_id, user_id, subscription_id, user_message, reply_message, elapsed, created_time) VALUES (?, ?, ?, ?, ?, ?, ?)");
+
+    let mut tx = pool.begin().await?;
+    let mut offset = 0;
+    let mut imported = 0;
+    loop {
+        let rows: Vec<(i64, String, String, String, i64, i64)> =
+            sqlx::query_as(&dialect.sql(&select))
+                .bind(IMPORT_BATCH)
+                .bind(offset)
+                .fetch_all(&mut tx)
+                .await?;
+        if rows.is_empty() {
+            break;
+        }
+        offset += rows.len() as i64;
+        for (msg_id, user_id, subscription_id, message, elapsed, created_time) in rows {
+            let conversation: Conversation = match serde_json::from_str(&message) {
+                Ok(conversation) => conversation,
+                Err(e) => {
+                    warn!("skip unreadable dialogue record {}: {}", msg_id, e);
+                    continue;
+                }
+            };
+            sqlx::query(&insert)
+                .bind(msg_id)
+                .bind(user_id)
+                .bind(subscription_id)
+                .bind(conversation.req_message)
+                .bind(conversation.resp_message)
+                .bind(elapsed)
+                .bind(created_time)
+                .execute(&mut tx)
+                .await?;
+            imported += 1;
+        }
+    }
+    tx.commit().await?;
+    info!("imported {} rows from {}", imported, LEGACY_TABLE);
+    Ok(())
+}
+
+async fn backfill_created_day(
+    pool: &AnyPool,
+    dialect: Dialect,
+    utc_offset_hours: i32,
+) -> Result<()> {
+    let select = dialect.sql(
+        "SELECT id, created_time FROM dialogue_turn WHERE created_day = '' ORDER BY id LIMIT ?",
+    );
+    let update = dialect.sql("UPDATE dialogue_turn SET created_day = ? WHERE id = ?");
+    loop {
+        let rows: Vec<(i64, i64)> = sqlx::query_as(&select)
+            .bind(IMPORT_BATCH)
+            .fetch_all(pool)
+            .await?;
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let mut tx = pool.begin().await?;
+        for (id, created_time) in rows {
+            sqlx::query(&update)
+                .bind(day_of(created_time, utc_offset_hours))
+                .bind(id)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::any::AnyPoolOptions;
+
+    use super::*;
+
+    #[test]
+    fn test_statements() {
+        let script = "INSERT INTO t VALUES ('a;b', \"c;d\");\n-- a comment; with a semicolon\nINSERT INTO t VALUES ('it''s; fine');\n\n";
+        assert_eq!(
+            statements(script),
+            vec![
+                "INSERT INTO t VALUES ('a;b', \"c;d\")",
+                "-- a comment; with a semicolon\nINSERT INTO t VALUES ('it''s; fine')",
+            ]
+        );
+        assert_eq!(
+            created_index("CREATE INDEX idx_t ON t (a, b)"),
+            Some(("idx_t", "t"))
+        );
+        assert_eq!(
+            created_index("create unique index idx_t on t(a)"),
+            Some(("idx_t", "t"))
+        );
+        assert_eq!(created_index("CREATE TABLE t (a INT)"), None);
+    }
+
+    #[tokio::test]
+    async fn test_import_dialogue_records() {
+        let pool = AnyPoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::query("CREATE TABLE wechat_dialogue_record (msg_id BIGINT, user_id TEXT, subscription_id TEXT, type_id TEXT, message TEXT, elapsed BIGINT, created_time TIMESTAMP)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO wechat_dialogue_record VALUES (1, 'user', 'sub', 'message', '{\"req_message\":\"hi\",\"resp_message\":\"hello\"}', 10, '2023-03-07 12:00:00')")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let ran = run(&pool, Dialect::Sqlite, 8).await.unwrap();
+        assert_eq!(ran.len(), MIGRATIONS.len());
+        // a second run has nothing left to do
+        assert!(run(&pool, Dialect::Sqlite, 8).await.unwrap().is_empty());
+
+        let (user_message, reply_message, created_time, created_day): (String, String, i64, String) = sqlx::query_as(
+            "SELECT user_message, reply_message, created_time, created_day FROM dialogue_turn WHERE msg_id = 1",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(user_message, "hi");
+        assert_eq!(reply_message, "hello");
+        assert_eq!(created_time, 1678190400000);
+        assert_eq!(created_day, "2023-03-07");
+    }
+}
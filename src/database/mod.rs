@@ -0,0 +1,641 @@
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use chrono::{FixedOffset, LocalResult, TimeZone};
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    any::{AnyConnectOptions, AnyKind, AnyPoolOptions},
+    mysql::{MySqlConnectOptions, MySqlSslMode},
+};
+
+use crate::{
+    api::chat_gpt::TokenUsage,
+    error::Result,
+    settings::{AccessMode, Database},
+};
+
+pub mod memory;
+pub mod migrations;
+pub mod sql;
+pub mod timed;
+
+/// Persistence for the dialogue between a follower and the bot.
+#[async_trait]
+pub trait ConversationStore: Send + Sync {
+    /// Connections of the underlying pool, if there is one.
+    fn pool_status(&self) -> Option<PoolStatus> {
+        None
+    }
+
+    /// A round trip to the database, for readiness checks.
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Brings the schema up to date, returning the migrations that ran.
+    async fn migrate(&self) -> Result<Vec<&'static str>>;
+
+    /// Saves a turn and deletes the [`PendingReply`] of its message in the
+    /// same transaction, so that a crash cannot leave a message both answered
+    /// and waiting to be answered after the restart.
+    async fn save_turn(&self, turn: &Turn) -> Result<()>;
+
+    /// The reply already given to a message of a user, if it was answered
+    /// successfully. Message ids are only unique per user and account.
+    async fn get_reply_by_msg_id(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        msg_id: i64,
+    ) -> Result<Option<String>>;
+
+    /// The latest successful conversations of a user in a session, oldest first.
+    async fn get_conversations(
+        &self,
+        user_id: &str,
+        subscription_id: &str,
+        session_id: &str,
+    ) -> Result<Vec<Conversation>>;
+
+    /// Stored turns, newest first.
+    async fn search_turns(&self, query: &TurnQuery) -> Result<Vec<TurnRecord>>;
+
+    /// Token usage and cost summed per day or month.
+    async fn spend(&self, query: &SpendQuery) -> Result<Vec<SpendRow>>;
+
+    async fn get_user_tier(&self, subscription_id: &str, user_id: &str) -> Result<Option<String>>;
+
+    async fn set_user_tier(&self, subscription_id: &str, user_id: &str, tier: &str) -> Result<()>;
+
+    /// Adds to today's message and token count of a follower.
+    async fn record_usage(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        messages: i64,
+        tokens: i64,
+    ) -> Result<()>;
+
+    /// Today's usage of a follower, or of the whole account without `user_id`.
+    async fn get_daily_usage(
+        &self,
+        subscription_id: &str,
+        user_id: Option<&str>,
+    ) -> Result<DailyUsage>;
+
+    /// Keeps a flagged message for the audit trail.
+    async fn save_moderation_audit(&self, audit: &ModerationAudit) -> Result<()>;
+
+    async fn add_to_user_list(
+        &self,
+        list: UserList,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<()>;
+
+    /// Returns false when the user was not on the list.
+    async fn remove_from_user_list(
+        &self,
+        list: UserList,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<bool>;
+
+    async fn is_in_user_list(
+        &self,
+        list: UserList,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<bool>;
+
+    async fn get_user_list(
+        &self,
+        list: UserList,
+        subscription_id: Option<&str>,
+    ) -> Result<Vec<ListedUser>>;
+
+    /// The session new turns of a user belong to, empty until it is first reset.
+    async fn get_session_id(&self, subscription_id: &str, user_id: &str) -> Result<String>;
+
+    async fn set_session_id(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        session_id: &str,
+    ) -> Result<()>;
+
+    /// The mode an account was switched to, overriding `access.mode`.
+    async fn get_access_mode(&self, subscription_id: &str) -> Result<Option<AccessMode>>;
+
+    async fn set_access_mode(&self, subscription_id: &str, mode: AccessMode) -> Result<()>;
+
+    async fn get_personas(&self) -> Result<Vec<Persona>>;
+
+    /// The system prompt of an account, if it has its own.
+    async fn get_persona(&self, subscription_id: &str) -> Result<Option<String>>;
+
+    async fn set_persona(&self, subscription_id: &str, prompt: &str) -> Result<()>;
+
+    async fn delete_persona(&self, subscription_id: &str) -> Result<bool>;
+
+    async fn get_keyword_rules(&self, subscription_id: Option<&str>) -> Result<Vec<KeywordRule>>;
+
+    /// The canned reply for a message that is exactly a keyword.
+    async fn get_keyword_reply(
+        &self,
+        subscription_id: &str,
+        keyword: &str,
+    ) -> Result<Option<String>>;
+
+    async fn set_keyword_rule(
+        &self,
+        subscription_id: &str,
+        keyword: &str,
+        reply: &str,
+    ) -> Result<()>;
+
+    async fn delete_keyword_rule(&self, subscription_id: &str, keyword: &str) -> Result<bool>;
+
+    /// Remembers a message before it goes to the model, so that its answer can
+    /// still be sent after a restart.
+    async fn save_pending_reply(&self, pending: &PendingReply) -> Result<()>;
+
+    async fn delete_pending_reply(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        msg_id: i64,
+    ) -> Result<()>;
+
+    /// Oldest first.
+    async fn get_pending_replies(&self) -> Result<Vec<PendingReply>>;
+
+    /// Replaces whatever was ingested into `base` from `source` before.
+    async fn replace_knowledge_source(
+        &self,
+        base: &str,
+        source: &str,
+        chunks: &[KnowledgeChunk],
+    ) -> Result<()>;
+
+    /// Every chunk of `base`, for a brute-force search.
+    async fn get_knowledge_chunks(&self, base: &str) -> Result<Vec<KnowledgeChunk>>;
+
+    /// Tells whether the chunks of `base` changed since they were loaded.
+    async fn knowledge_version(&self, base: &str) -> Result<KnowledgeVersion>;
+
+    /// What a user chose for their answers, the defaults until they choose.
+    async fn get_user_preferences(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<UserPreferences>;
+
+    async fn set_user_preferences(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        preferences: &UserPreferences,
+    ) -> Result<()>;
+
+    /// Back to the defaults, `false` if the user never chose anything.
+    async fn delete_user_preferences(&self, subscription_id: &str, user_id: &str) -> Result<bool>;
+
+    /// The profile last fetched from WeChat, however old.
+    async fn get_wechat_user(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<Option<WechatUser>>;
+
+    async fn save_wechat_user(&self, user: &WechatUser) -> Result<()>;
+
+    /// Erases a user: their turns and moderation audits are kept under
+    /// [`ERASED_USER`] without what was said, their daily usage is added to
+    /// that of [`ERASED_USER`], and their other rows are deleted, including
+    /// those of the legacy `wechat_dialogue_record` table. What an admin
+    /// decided stays: their tier and their place on the block and allow lists,
+    /// erasing must not lift a restriction.
+    async fn forget_user(&self, subscription_id: &str, user_id: &str) -> Result<ErasedRows>;
+}
+
+/// Picks the store from `database.url`: `mysql://`, `postgres://`,
+/// `sqlite://` or `memory://`. Without a url the MySQL fields are used.
+/// Turns are bucketed into days at `utc_offset_hours`.
+pub async fn connect(
+    database: &Database,
+    utc_offset_hours: i32,
+) -> Result<Arc<dyn ConversationStore>> {
+    let options = match database.url.as_deref() {
+        Some(url) if url.starts_with(MEMORY_SCHEME) => {
+            return Ok(Arc::new(timed::TimedStore(memory::MemoryStore::new(
+                utc_offset_hours,
+            ))));
+        }
+        Some(url) => AnyConnectOptions::from_str(url)?,
+        None => AnyConnectOptions::from(mysql_options(database)),
+    };
+    let dialect = match options.kind() {
+        AnyKind::MySql => sql::Dialect::MySql,
+        AnyKind::Postgres => sql::Dialect::Postgres,
+        AnyKind::Sqlite => sql::Dialect::Sqlite,
+        // mssql is compiled into the Any driver but not supported here
+        #[allow(unreachable_patterns)]
+        _ => return Err(sqlx::Error::Configuration("unsupported database".into()).into()),
+    };
+
+    let pool = AnyPoolOptions::new()
+        .max_connections(database.max_connections)
+        .connect_with(options)
+        .await?;
+    Ok(Arc::new(timed::TimedStore(sql::SqlStore::new(
+        pool,
+        dialect,
+        utc_offset_hours,
+    ))))
+}
+
+fn mysql_options(database: &Database) -> MySqlConnectOptions {
+    let options = MySqlConnectOptions::new()
+        .host(&database.host)
+        .username(&database.username)
+        .password(&database.password)
+        .database(&database.database);
+
+    match database.ssl_ca_path() {
+        Some(ssl_ca_path) => options
+            .ssl_mode(MySqlSslMode::Preferred)
+            .ssl_ca(std::path::PathBuf::from(ssl_ca_path)),
+        None => options,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PoolStatus {
+    pub size: u32,
+    pub idle: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Conversation {
+    pub req_message: String,
+    pub resp_message: String,
+}
+
+/// One message from a follower and what the bot made of it.
+#[derive(Debug, Clone)]
+pub struct Turn {
+    pub msg_id: i64,
+    pub user_id: String,
+    pub subscription_id: String,
+    pub session_id: String,
+    pub model: String,
+    pub user_message: String,
+    pub reply_message: String,
+    pub status: TurnStatus,
+    pub error: Option<String>,
+    pub elapsed: Duration,
+    pub usage: TokenUsage,
+    pub cost: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TurnStatus {
+    Ok,
+    Error,
+}
+
+impl TurnStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TurnStatus::Ok => "ok",
+            TurnStatus::Error => "error",
+        }
+    }
+}
+
+/// Filters for [`ConversationStore::search_turns`].
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TurnQuery {
+    pub subscription_id: Option<String>,
+    pub user_id: Option<String>,
+    /// Matched anywhere in the user message or the reply.
+    pub q: Option<String>,
+    #[serde(default = "default_turn_limit")]
+    pub limit: i64,
+    #[serde(default)]
+    pub offset: i64,
+}
+
+fn default_turn_limit() -> i64 {
+    50
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TurnRecord {
+    pub msg_id: i64,
+    pub user_id: String,
+    pub subscription_id: String,
+    pub session_id: String,
+    pub model: String,
+    pub user_message: String,
+    pub reply_message: String,
+    pub status: String,
+    pub error: Option<String>,
+    pub total_tokens: i64,
+    pub cost: f64,
+    pub created_time: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserList {
+    Blocked,
+    Allowed,
+}
+
+impl UserList {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserList::Blocked => "block",
+            UserList::Allowed => "allow",
+        }
+    }
+
+    pub fn parse(list: &str) -> Option<UserList> {
+        match list {
+            "block" => Some(UserList::Blocked),
+            "allow" => Some(UserList::Allowed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplyMode {
+    #[default]
+    Text,
+    /// The answer read out, see `[speech]`.
+    Voice,
+}
+
+impl ReplyMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReplyMode::Text => "text",
+            ReplyMode::Voice => "voice",
+        }
+    }
+
+    pub fn parse(mode: &str) -> Option<ReplyMode> {
+        match mode {
+            "text" => Some(ReplyMode::Text),
+            "voice" => Some(ReplyMode::Voice),
+            _ => None,
+        }
+    }
+}
+
+/// How long the answers should be.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verbosity {
+    Brief,
+    #[default]
+    Normal,
+    Detailed,
+}
+
+impl Verbosity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Verbosity::Brief => "brief",
+            Verbosity::Normal => "normal",
+            Verbosity::Detailed => "detailed",
+        }
+    }
+
+    pub fn parse(verbosity: &str) -> Option<Verbosity> {
+        match verbosity {
+            "brief" => Some(Verbosity::Brief),
+            "normal" => Some(Verbosity::Normal),
+            "detailed" => Some(Verbosity::Detailed),
+            _ => None,
+        }
+    }
+}
+
+/// What a user chose for their own answers. `None` leaves it to the account.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserPreferences {
+    /// The name of one of the configured `personas`, which replaces the
+    /// persona of the account.
+    pub persona: Option<String>,
+    pub model: Option<String>,
+    /// The language to answer in, e.g. `English`.
+    pub language: Option<String>,
+    pub reply_mode: ReplyMode,
+    pub verbosity: Verbosity,
+    /// Neither earlier turns are sent to the model nor what they say stored.
+    pub history_opt_out: bool,
+}
+
+/// Who the turns of an erased user belong to.
+pub const ERASED_USER: &str = "erased";
+
+/// The tables [`ConversationStore::forget_user`] deletes a user's rows from.
+const USER_TABLES: &[&str] = &[
+    "pending_reply",
+    "user_preference",
+    "user_session",
+    "wechat_user",
+];
+
+/// Rows erased or anonymised per table.
+pub type ErasedRows = BTreeMap<&'static str, u64>;
+
+/// A follower as WeChat's `user/info` describes them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WechatUser {
+    pub subscription_id: String,
+    pub user_id: String,
+    /// Empty for most followers, WeChat stopped handing nicknames out.
+    pub nickname: String,
+    /// As WeChat has it, e.g. `zh_CN`.
+    pub language: String,
+    /// Seconds since the epoch, `0` if they do not follow the account.
+    pub subscribe_time: i64,
+    pub updated_time: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ListedUser {
+    pub subscription_id: String,
+    pub user_id: String,
+    pub created_time: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Persona {
+    pub subscription_id: String,
+    pub prompt: String,
+    pub updated_time: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KeywordRule {
+    pub subscription_id: String,
+    pub keyword: String,
+    pub reply: String,
+    pub updated_time: i64,
+}
+
+/// A piece of an ingested document with its embedding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KnowledgeChunk {
+    pub source: String,
+    /// The position of the chunk within `source`.
+    pub chunk_index: i64,
+    pub content: String,
+    pub embedding: Vec<f32>,
+}
+
+/// The chunk count of a knowledge base and when it was last ingested into,
+/// which together change whenever its chunks are replaced.
+pub type KnowledgeVersion = (i64, i64);
+
+/// A follower's message that has not been answered yet.
+#[derive(Debug, Clone)]
+pub struct PendingReply {
+    /// The name of the account in the settings, to answer through.
+    pub account: String,
+    pub msg_id: i64,
+    pub subscription_id: String,
+    pub user_id: String,
+    pub content: String,
+    pub created_time: i64,
+}
+
+/// A message that moderation refused, on the way in or on the way out.
+#[derive(Debug, Clone)]
+pub struct ModerationAudit {
+    pub msg_id: i64,
+    pub user_id: String,
+    pub subscription_id: String,
+    pub stage: ModerationStage,
+    /// `keyword` or `api`.
+    pub source: String,
+    /// The matched word or the flagged categories.
+    pub reason: String,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModerationStage {
+    Input,
+    Output,
+}
+
+impl ModerationStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ModerationStage::Input => "input",
+            ModerationStage::Output => "output",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpendPeriod {
+    Day,
+    Month,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpendGroup {
+    User,
+    Subscription,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpendQuery {
+    pub period: SpendPeriod,
+    pub group: SpendGroup,
+    /// Inclusive `YYYY-MM-DD` bounds.
+    pub from_day: String,
+    pub to_day: String,
+    pub subscription_id: Option<String>,
+}
+
+impl SpendQuery {
+    /// Daily spend per account from the first of this month until today.
+    pub fn month_to_date(utc_offset_hours: i32) -> SpendQuery {
+        let today = day_of(now_millis(), utc_offset_hours);
+        SpendQuery {
+            period: SpendPeriod::Day,
+            group: SpendGroup::Subscription,
+            from_day: format!("{}-01", &today[..7]),
+            to_day: today,
+            subscription_id: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SpendRow {
+    /// `YYYY-MM-DD` or `YYYY-MM`.
+    pub period: String,
+    pub subscription_id: String,
+    /// Empty when grouped by subscription.
+    pub user_id: String,
+    pub turns: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+    pub cost: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DailyUsage {
+    pub messages: i64,
+    pub tokens: i64,
+}
+
+/// The `YYYY-MM-DD` a unix timestamp falls on at the given offset.
+pub fn day_of(millis: i64, utc_offset_hours: i32) -> String {
+    format_millis(millis, utc_offset_hours, "%Y-%m-%d")
+}
+
+/// The `YYYY-MM-DD HH:MM` of a unix timestamp at the given offset.
+pub fn minute_of(millis: i64, utc_offset_hours: i32) -> String {
+    format_millis(millis, utc_offset_hours, "%Y-%m-%d %H:%M")
+}
+
+fn format_millis(millis: i64, utc_offset_hours: i32, format: &str) -> String {
+    let offset = FixedOffset::east_opt(utc_offset_hours * 3600)
+        .unwrap_or_else(|| FixedOffset::east_opt(0).unwrap());
+    match offset.timestamp_millis_opt(millis) {
+        LocalResult::Single(time) => time.format(format).to_string(),
+        _ => String::new(),
+    }
+}
+
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+const MEMORY_SCHEME: &str = "memory:";
+const LIMIT_COUNT: i64 = 10;
+/// Upper bound of [`TurnQuery::limit`].
+const MAX_TURNS: i64 = 500;
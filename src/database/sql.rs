@@ -0,0 +1,1543 @@
+use std::borrow::Cow;
+
+use async_trait::async_trait;
+use log::debug;
+use sqlx::{AnyPool, Row};
+
+use crate::{error::Result, settings::AccessMode};
+
+use super::{
+    day_of, migrations, now_millis, Conversation, ConversationStore, DailyUsage, ErasedRows,
+    KeywordRule, KnowledgeChunk, KnowledgeVersion, ListedUser, ModerationAudit, PendingReply,
+    Persona, PoolStatus, ReplyMode, SpendGroup, SpendPeriod, SpendQuery, SpendRow, Turn, TurnQuery,
+    TurnRecord, UserList, UserPreferences, Verbosity, WechatUser, ERASED_USER, LIMIT_COUNT,
+    MAX_TURNS, USER_TABLES,
+};
+
+/// The SQL flavours we run on. Queries are written once with `?`
+/// placeholders; the dialect takes care of where the databases differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    MySql,
+    Postgres,
+    Sqlite,
+}
+
+impl Dialect {
+    /// Rewrites `?` placeholders to `$1, $2, ...` for Postgres.
+    pub fn sql<'a>(&self, sql: &'a str) -> Cow<'a, str> {
+        match self {
+            Dialect::Postgres => {
+                let mut out = String::with_capacity(sql.len() + 8);
+                let mut n = 0;
+                for c in sql.chars() {
+                    if c == '?' {
+                        n += 1;
+                        out.push_str(&format!("${}", n));
+                    } else {
+                        out.push(c);
+                    }
+                }
+                Cow::Owned(out)
+            }
+            Dialect::MySql | Dialect::Sqlite => Cow::Borrowed(sql),
+        }
+    }
+
+    /// Counts the tables named by the single bound parameter.
+    pub fn table_exists_sql(&self) -> &'static str {
+        match self {
+            Dialect::MySql => "SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = DATABASE() AND table_name = ?",
+            Dialect::Postgres => "SELECT COUNT(*) FROM information_schema.tables WHERE table_name = ?",
+            Dialect::Sqlite => "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?",
+        }
+    }
+
+    /// Upsert clause adding the new values of `columns` to the stored ones.
+    pub fn on_conflict_add(&self, table: &str, key: &str, columns: &[&str]) -> String {
+        self.on_conflict(table, key, columns, true)
+    }
+
+    /// Upsert clause overwriting `columns` with the new values.
+    pub fn on_conflict_replace(&self, table: &str, key: &str, columns: &[&str]) -> String {
+        self.on_conflict(table, key, columns, false)
+    }
+
+    fn on_conflict(&self, table: &str, key: &str, columns: &[&str], add: bool) -> String {
+        let assignments: Vec<String> = columns
+            .iter()
+            .map(|c| match (self, add) {
+                (Dialect::MySql, true) => format!("{c} = {c} + VALUES({c})"),
+                (Dialect::MySql, false) => format!("{c} = VALUES({c})"),
+                (_, true) => format!("{c} = {table}.{c} + excluded.{c}"),
+                (_, false) => format!("{c} = excluded.{c}"),
+            })
+            .collect();
+        match self {
+            Dialect::MySql => format!("ON DUPLICATE KEY UPDATE {}", assignments.join(", ")),
+            Dialect::Postgres | Dialect::Sqlite => format!(
+                "ON CONFLICT ({}) DO UPDATE SET {}",
+                key,
+                assignments.join(", ")
+            ),
+        }
+    }
+
+    /// The Any driver only decodes plain integers, never DECIMAL.
+    pub fn cast_int(&self, expr: &str) -> String {
+        match self {
+            Dialect::MySql => format!("CAST({} AS SIGNED)", expr),
+            Dialect::Postgres => format!("CAST({} AS BIGINT)", expr),
+            Dialect::Sqlite => format!("CAST({} AS INTEGER)", expr),
+        }
+    }
+
+    /// Unix milliseconds of a DATETIME/TIMESTAMP column.
+    pub fn epoch_millis(&self, column: &str) -> String {
+        let seconds = match self {
+            Dialect::MySql => format!("UNIX_TIMESTAMP({})", column),
+            Dialect::Postgres => format!("EXTRACT(EPOCH FROM {})", column),
+            Dialect::Sqlite => format!("strftime('%s', {})", column),
+        };
+        format!("{} * 1000", self.cast_int(&seconds))
+    }
+}
+
+/// persona, model, language, reply_mode, verbosity, history_opt_out
+type PreferenceRow = (
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    String,
+    String,
+    i64,
+);
+
+/// `ConversationStore` for MySQL, PostgreSQL and SQLite.
+pub struct SqlStore {
+    pool: AnyPool,
+    dialect: Dialect,
+    utc_offset_hours: i32,
+}
+
+impl SqlStore {
+    pub fn new(pool: AnyPool, dialect: Dialect, utc_offset_hours: i32) -> SqlStore {
+        SqlStore {
+            pool,
+            dialect,
+            utc_offset_hours,
+        }
+    }
+}
+
+#[async_trait]
+impl ConversationStore for SqlStore {
+    fn pool_status(&self) -> Option<PoolStatus> {
+        Some(PoolStatus {
+            size: self.pool.size(),
+            idle: self.pool.num_idle(),
+        })
+    }
+
+    async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn migrate(&self) -> Result<Vec<&'static str>> {
+        migrations::run(&self.pool, self.dialect, self.utc_offset_hours).await
+    }
+
+    async fn save_turn(&self, turn: &Turn) -> Result<()> {
+        debug!("save_turn begin");
+        let created_time = now_millis();
+        let sql = self.dialect.sql("INSERT INTO dialogue_turn(msg_id, user_id, subscription_id, session_id, model, user_message, reply_message, prompt_tokens, completion_tokens, total_tokens, cost, status, error, elapsed, created_time, created_day) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)");
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(&sql)
+            .bind(turn.msg_id)
+            .bind(&turn.user_id)
+            .bind(&turn.subscription_id)
+            .bind(&turn.session_id)
+            .bind(&turn.model)
+            .bind(&turn.user_message)
+            .bind(&turn.reply_message)
+            .bind(turn.usage.prompt_tokens)
+            .bind(turn.usage.completion_tokens)
+            .bind(turn.usage.total_tokens)
+            .bind(turn.cost)
+            .bind(turn.status.as_str())
+            .bind(&turn.error)
+            .bind(turn.elapsed.as_millis() as i64)
+            .bind(created_time)
+            .bind(day_of(created_time, self.utc_offset_hours))
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(&self.dialect.sql(
+            "DELETE FROM pending_reply WHERE subscription_id = ? AND user_id = ? AND msg_id = ?",
+        ))
+        .bind(&turn.subscription_id)
+        .bind(&turn.user_id)
+        .bind(turn.msg_id)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        debug!("save_turn end");
+        Ok(())
+    }
+
+    async fn get_reply_by_msg_id(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        msg_id: i64,
+    ) -> Result<Option<String>> {
+        let sql = self.dialect.sql("SELECT reply_message FROM dialogue_turn WHERE subscription_id = ? AND user_id = ? AND msg_id = ? AND status = 'ok'");
+        let row: Option<(String,)> = sqlx::query_as(&sql)
+            .bind(subscription_id)
+            .bind(user_id)
+            .bind(msg_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| row.0))
+    }
+
+    async fn get_conversations(
+        &self,
+        user_id: &str,
+        subscription_id: &str,
+        session_id: &str,
+    ) -> Result<Vec<Conversation>> {
+        let sql = self.dialect.sql("SELECT user_message, reply_message FROM dialogue_turn WHERE user_id = ? AND subscription_id = ? AND session_id = ? AND status = 'ok' ORDER BY created_time DESC, id DESC LIMIT ?");
+        let rows: Vec<(String, String)> = sqlx::query_as(&sql)
+            .bind(user_id)
+            .bind(subscription_id)
+            .bind(session_id)
+            .bind(LIMIT_COUNT)
+            .fetch_all(&self.pool)
+            .await?;
+
+        //最后n条记录-1，-2，-3，-4，...， -n 需要正序排列，-n, ... -4, -3, -2, -1
+        Ok(rows
+            .into_iter()
+            .rev()
+            .map(|(req_message, resp_message)| Conversation {
+                req_message,
+                resp_message,
+            })
+            .collect())
+    }
+
+    async fn search_turns(&self, query: &TurnQuery) -> Result<Vec<TurnRecord>> {
+        let mut sql = "SELECT msg_id, user_id, subscription_id, session_id, model, user_message, reply_message, status, error, total_tokens, cost, created_time FROM dialogue_turn WHERE 1 = 1".to_string();
+        if query.subscription_id.is_some() {
+            sql.push_str(" AND subscription_id = ?");
+        }
+        if query.user_id.is_some() {
+            sql.push_str(" AND user_id = ?");
+        }
+        if query.q.is_some() {
+            sql.push_str(
+                " AND (user_message LIKE ? ESCAPE '!' OR reply_message LIKE ? ESCAPE '!')",
+            );
+        }
+        sql.push_str(" ORDER BY created_time DESC, id DESC LIMIT ? OFFSET ?");
+        let sql = self.dialect.sql(&sql);
+
+        let mut q = sqlx::query(&sql);
+        if let Some(subscription_id) = &query.subscription_id {
+            q = q.bind(subscription_id);
+        }
+        if let Some(user_id) = &query.user_id {
+            q = q.bind(user_id);
+        }
+        if let Some(text) = &query.q {
+            let pattern = format!("%{}%", escape_like(text));
+            q = q.bind(pattern.clone()).bind(pattern);
+        }
+        let rows = q
+            .bind(query.limit.clamp(0, MAX_TURNS))
+            .bind(query.offset.max(0))
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter()
+            .map(|row| {
+                Ok(TurnRecord {
+                    msg_id: row.try_get(0)?,
+                    user_id: row.try_get(1)?,
+                    subscription_id: row.try_get(2)?,
+                    session_id: row.try_get(3)?,
+                    model: row.try_get(4)?,
+                    user_message: row.try_get(5)?,
+                    reply_message: row.try_get(6)?,
+                    status: row.try_get(7)?,
+                    error: row.try_get(8)?,
+                    total_tokens: row.try_get(9)?,
+                    cost: row.try_get(10)?,
+                    created_time: row.try_get(11)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn spend(&self, query: &SpendQuery) -> Result<Vec<SpendRow>> {
+        let period = match query.period {
+            SpendPeriod::Day => "created_day",
+            SpendPeriod::Month => "SUBSTR(created_day, 1, 7)",
+        };
+        let group = match query.group {
+            SpendGroup::User => "subscription_id, user_id",
+            SpendGroup::Subscription => "subscription_id",
+        };
+        let mut sql = format!(
+            "SELECT {period}, {group}, COUNT(*), {}, {}, {}, SUM(cost) FROM dialogue_turn WHERE created_day >= ? AND created_day <= ?",
+            self.dialect.cast_int("SUM(prompt_tokens)"),
+            self.dialect.cast_int("SUM(completion_tokens)"),
+            self.dialect.cast_int("SUM(total_tokens)"),
+        );
+        if query.subscription_id.is_some() {
+            sql.push_str(" AND subscription_id = ?");
+        }
+        sql.push_str(&format!(
+            " GROUP BY {period}, {group} ORDER BY {period}, {group}"
+        ));
+        let sql = self.dialect.sql(&sql);
+
+        let mut q = sqlx::query(&sql).bind(&query.from_day).bind(&query.to_day);
+        if let Some(subscription_id) = &query.subscription_id {
+            q = q.bind(subscription_id);
+        }
+        let rows = q.fetch_all(&self.pool).await?;
+
+        let user_column = match query.group {
+            SpendGroup::User => 1,
+            SpendGroup::Subscription => 0,
+        };
+        rows.iter()
+            .map(|row| {
+                Ok(SpendRow {
+                    period: row.try_get(0)?,
+                    subscription_id: row.try_get(1)?,
+                    user_id: match user_column {
+                        0 => String::new(),
+                        _ => row.try_get(2)?,
+                    },
+                    turns: row.try_get(2 + user_column)?,
+                    prompt_tokens: row.try_get(3 + user_column)?,
+                    completion_tokens: row.try_get(4 + user_column)?,
+                    total_tokens: row.try_get(5 + user_column)?,
+                    cost: row.try_get(6 + user_column)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn get_user_tier(&self, subscription_id: &str, user_id: &str) -> Result<Option<String>> {
+        let sql = self
+            .dialect
+            .sql("SELECT tier FROM user_tier WHERE subscription_id = ? AND user_id = ?");
+        let row: Option<(String,)> = sqlx::query_as(&sql)
+            .bind(subscription_id)
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| row.0))
+    }
+
+    async fn set_user_tier(&self, subscription_id: &str, user_id: &str, tier: &str) -> Result<()> {
+        let sql = format!(
+            "INSERT INTO user_tier(subscription_id, user_id, tier, updated_time) VALUES (?, ?, ?, ?) {}",
+            self.dialect.on_conflict_replace(
+                "user_tier",
+                "subscription_id, user_id",
+                &["tier", "updated_time"]
+            )
+        );
+        sqlx::query(&self.dialect.sql(&sql))
+            .bind(subscription_id)
+            .bind(user_id)
+            .bind(tier)
+            .bind(now_millis())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn record_usage(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        messages: i64,
+        tokens: i64,
+    ) -> Result<()> {
+        let sql = format!(
+            "INSERT INTO daily_usage(subscription_id, user_id, day, messages, tokens) VALUES (?, ?, ?, ?, ?) {}",
+            self.dialect.on_conflict_add(
+                "daily_usage",
+                "subscription_id, user_id, day",
+                &["messages", "tokens"]
+            )
+        );
+        sqlx::query(&self.dialect.sql(&sql))
+            .bind(subscription_id)
+            .bind(user_id)
+            .bind(day_of(now_millis(), self.utc_offset_hours))
+            .bind(messages)
+            .bind(tokens)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_daily_usage(
+        &self,
+        subscription_id: &str,
+        user_id: Option<&str>,
+    ) -> Result<DailyUsage> {
+        let mut sql = format!(
+            "SELECT {}, {} FROM daily_usage WHERE subscription_id = ? AND day = ?",
+            self.dialect.cast_int("COALESCE(SUM(messages), 0)"),
+            self.dialect.cast_int("COALESCE(SUM(tokens), 0)"),
+        );
+        if user_id.is_some() {
+            sql.push_str(" AND user_id = ?");
+        }
+        let sql = self.dialect.sql(&sql);
+        let mut query = sqlx::query_as(&sql)
+            .bind(subscription_id)
+            .bind(day_of(now_millis(), self.utc_offset_hours));
+        if let Some(user_id) = user_id {
+            query = query.bind(user_id);
+        }
+        let (messages, tokens): (i64, i64) = query.fetch_one(&self.pool).await?;
+        Ok(DailyUsage { messages, tokens })
+    }
+
+    async fn save_moderation_audit(&self, audit: &ModerationAudit) -> Result<()> {
+        let sql = self.dialect.sql("INSERT INTO moderation_audit(msg_id, user_id, subscription_id, stage, source, reason, content, created_time) VALUES (?, ?, ?, ?, ?, ?, ?, ?)");
+        sqlx::query(&sql)
+            .bind(audit.msg_id)
+            .bind(&audit.user_id)
+            .bind(&audit.subscription_id)
+            .bind(audit.stage.as_str())
+            .bind(&audit.source)
+            .bind(&audit.reason)
+            .bind(&audit.content)
+            .bind(now_millis())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn add_to_user_list(
+        &self,
+        list: UserList,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<()> {
+        let sql = format!(
+            "INSERT INTO user_list(subscription_id, user_id, list, created_time) VALUES (?, ?, ?, ?) {}",
+            self.dialect.on_conflict_replace(
+                "user_list",
+                "subscription_id, user_id, list",
+                &["created_time"]
+            )
+        );
+        sqlx::query(&self.dialect.sql(&sql))
+            .bind(subscription_id)
+            .bind(user_id)
+            .bind(list.as_str())
+            .bind(now_millis())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_from_user_list(
+        &self,
+        list: UserList,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<bool> {
+        let sql = self
+            .dialect
+            .sql("DELETE FROM user_list WHERE subscription_id = ? AND user_id = ? AND list = ?");
+        let result = sqlx::query(&sql)
+            .bind(subscription_id)
+            .bind(user_id)
+            .bind(list.as_str())
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn is_in_user_list(
+        &self,
+        list: UserList,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<bool> {
+        let sql = self.dialect.sql(
+            "SELECT created_time FROM user_list WHERE subscription_id = ? AND user_id = ? AND list = ?",
+        );
+        let row: Option<(i64,)> = sqlx::query_as(&sql)
+            .bind(subscription_id)
+            .bind(user_id)
+            .bind(list.as_str())
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.is_some())
+    }
+
+    async fn get_user_list(
+        &self,
+        list: UserList,
+        subscription_id: Option<&str>,
+    ) -> Result<Vec<ListedUser>> {
+        let mut sql = "SELECT subscription_id, user_id, created_time FROM user_list WHERE list = ?"
+            .to_string();
+        if subscription_id.is_some() {
+            sql.push_str(" AND subscription_id = ?");
+        }
+        sql.push_str(" ORDER BY subscription_id, created_time");
+        let sql = self.dialect.sql(&sql);
+        let mut query = sqlx::query_as(&sql).bind(list.as_str());
+        if let Some(subscription_id) = subscription_id {
+            query = query.bind(subscription_id);
+        }
+        let rows: Vec<(String, String, i64)> = query.fetch_all(&self.pool).await?;
+        Ok(rows
+            .into_iter()
+            .map(|(subscription_id, user_id, created_time)| ListedUser {
+                subscription_id,
+                user_id,
+                created_time,
+            })
+            .collect())
+    }
+
+    async fn get_session_id(&self, subscription_id: &str, user_id: &str) -> Result<String> {
+        let sql = self
+            .dialect
+            .sql("SELECT session_id FROM user_session WHERE subscription_id = ? AND user_id = ?");
+        let row: Option<(String,)> = sqlx::query_as(&sql)
+            .bind(subscription_id)
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| row.0).unwrap_or_default())
+    }
+
+    async fn set_session_id(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        session_id: &str,
+    ) -> Result<()> {
+        let sql = format!(
+            "INSERT INTO user_session(subscription_id, user_id, session_id, updated_time) VALUES (?, ?, ?, ?) {}",
+            self.dialect.on_conflict_replace(
+                "user_session",
+                "subscription_id, user_id",
+                &["session_id", "updated_time"]
+            )
+        );
+        sqlx::query(&self.dialect.sql(&sql))
+            .bind(subscription_id)
+            .bind(user_id)
+            .bind(session_id)
+            .bind(now_millis())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_access_mode(&self, subscription_id: &str) -> Result<Option<AccessMode>> {
+        let sql = self
+            .dialect
+            .sql("SELECT mode FROM account_access WHERE subscription_id = ?");
+        let row: Option<(String,)> = sqlx::query_as(&sql)
+            .bind(subscription_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.and_then(|row| AccessMode::parse(&row.0)))
+    }
+
+    async fn set_access_mode(&self, subscription_id: &str, mode: AccessMode) -> Result<()> {
+        let sql = format!(
+            "INSERT INTO account_access(subscription_id, mode, updated_time) VALUES (?, ?, ?) {}",
+            self.dialect.on_conflict_replace(
+                "account_access",
+                "subscription_id",
+                &["mode", "updated_time"]
+            )
+        );
+        sqlx::query(&self.dialect.sql(&sql))
+            .bind(subscription_id)
+            .bind(mode.as_str())
+            .bind(now_millis())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_personas(&self) -> Result<Vec<Persona>> {
+        let rows: Vec<(String, String, i64)> = sqlx::query_as(
+            "SELECT subscription_id, prompt, updated_time FROM persona ORDER BY subscription_id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(subscription_id, prompt, updated_time)| Persona {
+                subscription_id,
+                prompt,
+                updated_time,
+            })
+            .collect())
+    }
+
+    async fn get_persona(&self, subscription_id: &str) -> Result<Option<String>> {
+        let sql = self
+            .dialect
+            .sql("SELECT prompt FROM persona WHERE subscription_id = ?");
+        let row: Option<(String,)> = sqlx::query_as(&sql)
+            .bind(subscription_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| row.0))
+    }
+
+    async fn set_persona(&self, subscription_id: &str, prompt: &str) -> Result<()> {
+        let sql = format!(
+            "INSERT INTO persona(subscription_id, prompt, updated_time) VALUES (?, ?, ?) {}",
+            self.dialect.on_conflict_replace(
+                "persona",
+                "subscription_id",
+                &["prompt", "updated_time"]
+            )
+        );
+        sqlx::query(&self.dialect.sql(&sql))
+            .bind(subscription_id)
+            .bind(prompt)
+            .bind(now_millis())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_persona(&self, subscription_id: &str) -> Result<bool> {
+        let sql = self
+            .dialect
+            .sql("DELETE FROM persona WHERE subscription_id = ?");
+        let result = sqlx::query(&sql)
+            .bind(subscription_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_keyword_rules(&self, subscription_id: Option<&str>) -> Result<Vec<KeywordRule>> {
+        let mut sql =
+            "SELECT subscription_id, keyword, reply, updated_time FROM keyword_rule".to_string();
+        if subscription_id.is_some() {
+            sql.push_str(" WHERE subscription_id = ?");
+        }
+        sql.push_str(" ORDER BY subscription_id, keyword");
+        let sql = self.dialect.sql(&sql);
+        let mut query = sqlx::query_as(&sql);
+        if let Some(subscription_id) = subscription_id {
+            query = query.bind(subscription_id);
+        }
+        let rows: Vec<(String, String, String, i64)> = query.fetch_all(&self.pool).await?;
+        Ok(rows
+            .into_iter()
+            .map(
+                |(subscription_id, keyword, reply, updated_time)| KeywordRule {
+                    subscription_id,
+                    keyword,
+                    reply,
+                    updated_time,
+                },
+            )
+            .collect())
+    }
+
+    async fn get_keyword_reply(
+        &self,
+        subscription_id: &str,
+        keyword: &str,
+    ) -> Result<Option<String>> {
+        let sql = self
+            .dialect
+            .sql("SELECT reply FROM keyword_rule WHERE subscription_id = ? AND keyword = ?");
+        let row: Option<(String,)> = sqlx::query_as(&sql)
+            .bind(subscription_id)
+            .bind(keyword)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| row.0))
+    }
+
+    async fn set_keyword_rule(
+        &self,
+        subscription_id: &str,
+        keyword: &str,
+        reply: &str,
+    ) -> Result<()> {
+        let sql = format!(
+            "INSERT INTO keyword_rule(subscription_id, keyword, reply, updated_time) VALUES (?, ?, ?, ?) {}",
+            self.dialect.on_conflict_replace(
+                "keyword_rule",
+                "subscription_id, keyword",
+                &["reply", "updated_time"]
+            )
+        );
+        sqlx::query(&self.dialect.sql(&sql))
+            .bind(subscription_id)
+            .bind(keyword)
+            .bind(reply)
+            .bind(now_millis())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_keyword_rule(&self, subscription_id: &str, keyword: &str) -> Result<bool> {
+        let sql = self
+            .dialect
+            .sql("DELETE FROM keyword_rule WHERE subscription_id = ? AND keyword = ?");
+        let result = sqlx::query(&sql)
+            .bind(subscription_id)
+            .bind(keyword)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn save_pending_reply(&self, pending: &PendingReply) -> Result<()> {
+        let sql = format!(
+            "INSERT INTO pending_reply(msg_id, account, subscription_id, user_id, content, created_time) VALUES (?, ?, ?, ?, ?, ?) {}",
+            self.dialect.on_conflict_replace(
+                "pending_reply",
+                "subscription_id, user_id, msg_id",
+                &["account", "content", "created_time"]
+            )
+        );
+        sqlx::query(&self.dialect.sql(&sql))
+            .bind(pending.msg_id)
+            .bind(&pending.account)
+            .bind(&pending.subscription_id)
+            .bind(&pending.user_id)
+            .bind(&pending.content)
+            .bind(pending.created_time)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_pending_reply(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        msg_id: i64,
+    ) -> Result<()> {
+        let sql = self.dialect.sql(
+            "DELETE FROM pending_reply WHERE subscription_id = ? AND user_id = ? AND msg_id = ?",
+        );
+        sqlx::query(&sql)
+            .bind(subscription_id)
+            .bind(user_id)
+            .bind(msg_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_pending_replies(&self) -> Result<Vec<PendingReply>> {
+        let rows: Vec<(i64, String, String, String, String, i64)> = sqlx::query_as(
+            "SELECT msg_id, account, subscription_id, user_id, content, created_time FROM pending_reply ORDER BY created_time",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(
+                |(msg_id, account, subscription_id, user_id, content, created_time)| PendingReply {
+                    account,
+                    msg_id,
+                    subscription_id,
+                    user_id,
+                    content,
+                    created_time,
+                },
+            )
+            .collect())
+    }
+
+    async fn replace_knowledge_source(
+        &self,
+        base: &str,
+        source: &str,
+        chunks: &[KnowledgeChunk],
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            &self
+                .dialect
+                .sql("DELETE FROM knowledge_chunk WHERE base = ? AND source = ?"),
+        )
+        .bind(base)
+        .bind(source)
+        .execute(&mut tx)
+        .await?;
+        let sql = self.dialect.sql(
+            "INSERT INTO knowledge_chunk(base, source, chunk_index, content, embedding, created_time) VALUES (?, ?, ?, ?, ?, ?)",
+        );
+        let now = now_millis();
+        for chunk in chunks {
+            sqlx::query(&sql)
+                .bind(base)
+                .bind(source)
+                .bind(chunk.chunk_index)
+                .bind(&chunk.content)
+                .bind(serde_json::to_string(&chunk.embedding)?)
+                .bind(now)
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_knowledge_chunks(&self, base: &str) -> Result<Vec<KnowledgeChunk>> {
+        let sql = self.dialect.sql(
+            "SELECT source, chunk_index, content, embedding FROM knowledge_chunk WHERE base = ? ORDER BY source, chunk_index",
+        );
+        let rows: Vec<(String, i64, String, String)> = sqlx::query_as(&sql)
+            .bind(base)
+            .fetch_all(&self.pool)
+            .await?;
+        rows.into_iter()
+            .map(|(source, chunk_index, content, embedding)| {
+                Ok(KnowledgeChunk {
+                    source,
+                    chunk_index,
+                    content,
+                    embedding: serde_json::from_str(&embedding)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn knowledge_version(&self, base: &str) -> Result<KnowledgeVersion> {
+        let sql = format!(
+            "SELECT {}, {} FROM knowledge_chunk WHERE base = ?",
+            self.dialect.cast_int("COUNT(*)"),
+            self.dialect.cast_int("COALESCE(MAX(created_time), 0)"),
+        );
+        let version: KnowledgeVersion = sqlx::query_as(&self.dialect.sql(&sql))
+            .bind(base)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(version)
+    }
+
+    async fn get_user_preferences(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<UserPreferences> {
+        let sql = self.dialect.sql(
+            "SELECT persona, model, language, reply_mode, verbosity, history_opt_out FROM user_preference WHERE subscription_id = ? AND user_id = ?",
+        );
+        let row: Option<PreferenceRow> = sqlx::query_as(&sql)
+            .bind(subscription_id)
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(match row {
+            Some((persona, model, language, reply_mode, verbosity, history_opt_out)) => {
+                UserPreferences {
+                    persona,
+                    model,
+                    language,
+                    reply_mode: ReplyMode::parse(&reply_mode).unwrap_or_default(),
+                    verbosity: Verbosity::parse(&verbosity).unwrap_or_default(),
+                    history_opt_out: history_opt_out != 0,
+                }
+            }
+            None => UserPreferences::default(),
+        })
+    }
+
+    async fn set_user_preferences(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        preferences: &UserPreferences,
+    ) -> Result<()> {
+        let sql = format!(
+            "INSERT INTO user_preference(subscription_id, user_id, persona, model, language, reply_mode, verbosity, history_opt_out, updated_time) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?) {}",
+            self.dialect.on_conflict_replace(
+                "user_preference",
+                "subscription_id, user_id",
+                &[
+                    "persona",
+                    "model",
+                    "language",
+                    "reply_mode",
+                    "verbosity",
+                    "history_opt_out",
+                    "updated_time"
+                ]
+            )
+        );
+        sqlx::query(&self.dialect.sql(&sql))
+            .bind(subscription_id)
+            .bind(user_id)
+            .bind(preferences.persona.as_deref())
+            .bind(preferences.model.as_deref())
+            .bind(preferences.language.as_deref())
+            .bind(preferences.reply_mode.as_str())
+            .bind(preferences.verbosity.as_str())
+            .bind(preferences.history_opt_out as i64)
+            .bind(now_millis())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_user_preferences(&self, subscription_id: &str, user_id: &str) -> Result<bool> {
+        let sql = self
+            .dialect
+            .sql("DELETE FROM user_preference WHERE subscription_id = ? AND user_id = ?");
+        let result = sqlx::query(&sql)
+            .bind(subscription_id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_wechat_user(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<Option<WechatUser>> {
+        let sql = self.dialect.sql(
+            "SELECT nickname, language, subscribe_time, updated_time FROM wechat_user WHERE subscription_id = ? AND user_id = ?",
+        );
+        let row: Option<(String, String, i64, i64)> = sqlx::query_as(&sql)
+            .bind(subscription_id)
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(
+            |(nickname, language, subscribe_time, updated_time)| WechatUser {
+                subscription_id: subscription_id.to_string(),
+                user_id: user_id.to_string(),
+                nickname,
+                language,
+                subscribe_time,
+                updated_time,
+            },
+        ))
+    }
+
+    async fn save_wechat_user(&self, user: &WechatUser) -> Result<()> {
+        let sql = format!(
+            "INSERT INTO wechat_user(subscription_id, user_id, nickname, language, subscribe_time, updated_time) VALUES (?, ?, ?, ?, ?, ?) {}",
+            self.dialect.on_conflict_replace(
+                "wechat_user",
+                "subscription_id, user_id",
+                &["nickname", "language", "subscribe_time", "updated_time"]
+            )
+        );
+        sqlx::query(&self.dialect.sql(&sql))
+            .bind(&user.subscription_id)
+            .bind(&user.user_id)
+            .bind(&user.nickname)
+            .bind(&user.language)
+            .bind(user.subscribe_time)
+            .bind(user.updated_time)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn forget_user(&self, subscription_id: &str, user_id: &str) -> Result<ErasedRows> {
+        let mut erased = ErasedRows::new();
+        let mut tx = self.pool.begin().await?;
+        // spend per account stays right, only who said what is gone
+        let result = sqlx::query(&self.dialect.sql(
+            "UPDATE dialogue_turn SET user_id = ?, session_id = '', user_message = '', reply_message = '', error = NULL WHERE subscription_id = ? AND user_id = ?",
+        ))
+        .bind(ERASED_USER)
+        .bind(subscription_id)
+        .bind(user_id)
+        .execute(&mut tx)
+        .await?;
+        erased.insert("dialogue_turn", result.rows_affected());
+        // the audit trail stays too, without the flagged content
+        let result = sqlx::query(&self.dialect.sql(
+            "UPDATE moderation_audit SET user_id = ?, content = '' WHERE subscription_id = ? AND user_id = ?",
+        ))
+        .bind(ERASED_USER)
+        .bind(subscription_id)
+        .bind(user_id)
+        .execute(&mut tx)
+        .await?;
+        erased.insert("moderation_audit", result.rows_affected());
+        // the account's usage stays, so that erasing does not hand out a new quota
+        let sql = format!(
+            "INSERT INTO daily_usage(subscription_id, user_id, day, messages, tokens) SELECT subscription_id, ?, day, messages, tokens FROM daily_usage WHERE subscription_id = ? AND user_id = ? {}",
+            self.dialect.on_conflict_add(
+                "daily_usage",
+                "subscription_id, user_id, day",
+                &["messages", "tokens"]
+            )
+        );
+        sqlx::query(&self.dialect.sql(&sql))
+            .bind(ERASED_USER)
+            .bind(subscription_id)
+            .bind(user_id)
+            .execute(&mut tx)
+            .await?;
+        let result = sqlx::query(
+            &self
+                .dialect
+                .sql("DELETE FROM daily_usage WHERE subscription_id = ? AND user_id = ?"),
+        )
+        .bind(subscription_id)
+        .bind(user_id)
+        .execute(&mut tx)
+        .await?;
+        erased.insert("daily_usage", result.rows_affected());
+        for table in USER_TABLES {
+            let sql = format!(
+                "DELETE FROM {} WHERE subscription_id = ? AND user_id = ?",
+                table
+            );
+            let result = sqlx::query(&self.dialect.sql(&sql))
+                .bind(subscription_id)
+                .bind(user_id)
+                .execute(&mut tx)
+                .await?;
+            erased.insert(table, result.rows_affected());
+        }
+        let (legacy,): (i64,) = sqlx::query_as(&self.dialect.sql(self.dialect.table_exists_sql()))
+            .bind(migrations::LEGACY_TABLE)
+            .fetch_one(&mut tx)
+            .await?;
+        if legacy > 0 {
+            let sql = format!(
+                "DELETE FROM {} WHERE subscription_id = ? AND user_id = ?",
+                migrations::LEGACY_TABLE
+            );
+            let result = sqlx::query(&self.dialect.sql(&sql))
+                .bind(subscription_id)
+                .bind(user_id)
+                .execute(&mut tx)
+                .await?;
+            erased.insert(migrations::LEGACY_TABLE, result.rows_affected());
+        }
+        tx.commit().await?;
+        Ok(erased)
+    }
+}
+
+/// Makes `%` and `_` in a search match themselves, for `LIKE ? ESCAPE '!'`.
+/// `!` rather than a backslash, which MySQL would treat as a string escape.
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '!' | '%' | '_') {
+            escaped.push('!');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sqlx::any::AnyPoolOptions;
+
+    use super::*;
+    use crate::{
+        api::chat_gpt::TokenUsage,
+        database::{ModerationStage, TurnStatus},
+    };
+
+    #[test]
+    fn test_postgres_placeholders() {
+        assert_eq!(
+            Dialect::Postgres.sql("SELECT a FROM t WHERE b = ? AND c = ?"),
+            "SELECT a FROM t WHERE b = $1 AND c = $2"
+        );
+        assert_eq!(Dialect::MySql.sql("WHERE b = ?"), "WHERE b = ?");
+    }
+
+    async fn store() -> SqlStore {
+        let pool = AnyPoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        let store = SqlStore::new(pool, Dialect::Sqlite, 8);
+        store.migrate().await.unwrap();
+        store
+    }
+
+    /// Saves an answered turn 1 and a failed turn 2 of `user`.
+    async fn save_turns(store: &SqlStore) {
+        let mut turn = Turn {
+            msg_id: 1,
+            user_id: "user".to_string(),
+            subscription_id: "sub".to_string(),
+            session_id: String::new(),
+            model: "gpt-3.5-turbo".to_string(),
+            user_message: "hi".to_string(),
+            reply_message: "hello".to_string(),
+            status: TurnStatus::Ok,
+            error: None,
+            elapsed: Duration::from_millis(10),
+            usage: TokenUsage {
+                prompt_tokens: 100,
+                completion_tokens: 20,
+                total_tokens: 120,
+            },
+            cost: 0.25,
+        };
+        store.save_turn(&turn).await.unwrap();
+        turn.msg_id = 2;
+        turn.reply_message = String::new();
+        turn.status = TurnStatus::Error;
+        turn.error = Some("timeout".to_string());
+        store.save_turn(&turn).await.unwrap();
+    }
+
+    fn month_spend(subscription_id: &str) -> SpendQuery {
+        let today = day_of(now_millis(), 8);
+        SpendQuery {
+            period: SpendPeriod::Month,
+            group: SpendGroup::User,
+            from_day: today.clone(),
+            to_day: today,
+            subscription_id: Some(subscription_id.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_turns_and_spend() {
+        let store = store().await;
+        save_turns(&store).await;
+
+        assert_eq!(
+            store.get_reply_by_msg_id("sub", "user", 1).await.unwrap(),
+            Some("hello".to_string())
+        );
+        assert_eq!(
+            store.get_reply_by_msg_id("sub", "user", 2).await.unwrap(),
+            None
+        );
+        // another sender may use the same id
+        assert_eq!(
+            store.get_reply_by_msg_id("sub", "other", 1).await.unwrap(),
+            None
+        );
+
+        let conversations = store.get_conversations("user", "sub", "").await.unwrap();
+        assert_eq!(conversations.len(), 1);
+        assert_eq!(conversations[0].req_message, "hi");
+
+        let spend = store.spend(&month_spend("sub")).await.unwrap();
+        assert_eq!(spend.len(), 1);
+        assert_eq!(spend[0].period, &day_of(now_millis(), 8)[..7]);
+        assert_eq!(spend[0].user_id, "user");
+        assert_eq!(spend[0].turns, 2);
+        assert_eq!(spend[0].total_tokens, 240);
+        assert_eq!(spend[0].cost, 0.5);
+    }
+
+    #[tokio::test]
+    async fn test_search_turns() {
+        let store = store().await;
+        save_turns(&store).await;
+
+        let found = store
+            .search_turns(&TurnQuery {
+                user_id: Some("user".to_string()),
+                q: Some("hi".to_string()),
+                limit: 10,
+                ..TurnQuery::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().all(|t| t.user_id == "user"));
+
+        // wildcards in the search match themselves
+        for q in ["%", "_", "h_"] {
+            let found = store
+                .search_turns(&TurnQuery {
+                    q: Some(q.to_string()),
+                    limit: 10,
+                    ..TurnQuery::default()
+                })
+                .await
+                .unwrap();
+            assert!(found.is_empty(), "{}", q);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_usage_and_tiers() {
+        let store = store().await;
+        store.record_usage("sub", "user", 1, 120).await.unwrap();
+        store.record_usage("sub", "user", 1, 80).await.unwrap();
+        store.record_usage("sub", "other", 1, 10).await.unwrap();
+        assert_eq!(
+            store.get_daily_usage("sub", Some("user")).await.unwrap(),
+            DailyUsage {
+                messages: 2,
+                tokens: 200
+            }
+        );
+        assert_eq!(
+            store.get_daily_usage("sub", None).await.unwrap().tokens,
+            210
+        );
+
+        assert_eq!(store.get_user_tier("sub", "user").await.unwrap(), None);
+        store.set_user_tier("sub", "user", "vip").await.unwrap();
+        store.set_user_tier("sub", "user", "gold").await.unwrap();
+        assert_eq!(
+            store.get_user_tier("sub", "user").await.unwrap(),
+            Some("gold".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_moderation_audit() {
+        let store = store().await;
+        store
+            .save_moderation_audit(&ModerationAudit {
+                msg_id: 3,
+                user_id: "user".to_string(),
+                subscription_id: "sub".to_string(),
+                stage: ModerationStage::Input,
+                source: "keyword".to_string(),
+                reason: "bad".to_string(),
+                content: "something bad".to_string(),
+            })
+            .await
+            .unwrap();
+        let (stage,): (String,) =
+            sqlx::query_as("SELECT stage FROM moderation_audit WHERE msg_id = 3")
+                .fetch_one(&store.pool)
+                .await
+                .unwrap();
+        assert_eq!(stage, "input");
+    }
+
+    #[tokio::test]
+    async fn test_user_lists_and_access_mode() {
+        let store = store().await;
+        store
+            .add_to_user_list(UserList::Blocked, "sub", "user")
+            .await
+            .unwrap();
+        store
+            .add_to_user_list(UserList::Blocked, "sub", "user")
+            .await
+            .unwrap();
+        assert!(store
+            .is_in_user_list(UserList::Blocked, "sub", "user")
+            .await
+            .unwrap());
+        assert_eq!(
+            store
+                .get_user_list(UserList::Blocked, Some("sub"))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(store
+            .remove_from_user_list(UserList::Blocked, "sub", "user")
+            .await
+            .unwrap());
+        assert!(!store
+            .remove_from_user_list(UserList::Blocked, "sub", "user")
+            .await
+            .unwrap());
+
+        assert_eq!(store.get_access_mode("sub").await.unwrap(), None);
+        store
+            .set_access_mode("sub", AccessMode::Allowlist)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get_access_mode("sub").await.unwrap(),
+            Some(AccessMode::Allowlist)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_user_preferences_and_profile() {
+        let store = store().await;
+        assert_eq!(
+            store.get_user_preferences("sub", "user").await.unwrap(),
+            UserPreferences::default()
+        );
+        let preferences = UserPreferences {
+            persona: Some("be brief".to_string()),
+            language: Some("English".to_string()),
+            reply_mode: ReplyMode::Voice,
+            verbosity: Verbosity::Detailed,
+            history_opt_out: true,
+            ..Default::default()
+        };
+        store
+            .set_user_preferences("sub", "user", &preferences)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get_user_preferences("sub", "user").await.unwrap(),
+            preferences
+        );
+        assert!(store.delete_user_preferences("sub", "user").await.unwrap());
+        assert!(!store.delete_user_preferences("sub", "user").await.unwrap());
+
+        assert_eq!(store.get_wechat_user("sub", "user").await.unwrap(), None);
+        let mut user = WechatUser {
+            subscription_id: "sub".to_string(),
+            user_id: "user".to_string(),
+            nickname: "小明".to_string(),
+            language: "zh_CN".to_string(),
+            subscribe_time: 1700000000,
+            updated_time: 1,
+        };
+        store.save_wechat_user(&user).await.unwrap();
+        user.updated_time = 2;
+        store.save_wechat_user(&user).await.unwrap();
+        assert_eq!(
+            store.get_wechat_user("sub", "user").await.unwrap(),
+            Some(user)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sessions_personas_and_keywords() {
+        let store = store().await;
+        save_turns(&store).await;
+        assert_eq!(store.get_session_id("sub", "user").await.unwrap(), "");
+        store.set_session_id("sub", "user", "s1").await.unwrap();
+        assert_eq!(store.get_session_id("sub", "user").await.unwrap(), "s1");
+        assert!(store
+            .get_conversations("user", "sub", "s1")
+            .await
+            .unwrap()
+            .is_empty());
+
+        store.set_persona("sub", "be nice").await.unwrap();
+        store.set_persona("sub", "be kind").await.unwrap();
+        assert_eq!(
+            store.get_persona("sub").await.unwrap(),
+            Some("be kind".to_string())
+        );
+        assert_eq!(store.get_personas().await.unwrap().len(), 1);
+        assert!(store.delete_persona("sub").await.unwrap());
+
+        store.set_keyword_rule("sub", "价格", "9.9").await.unwrap();
+        assert_eq!(
+            store.get_keyword_reply("sub", "价格").await.unwrap(),
+            Some("9.9".to_string())
+        );
+        assert_eq!(store.get_keyword_rules(None).await.unwrap().len(), 1);
+        assert!(store.delete_keyword_rule("sub", "价格").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_pending_replies() {
+        let store = store().await;
+        let pending = PendingReply {
+            account: "shop".to_string(),
+            msg_id: 42,
+            subscription_id: "sub".to_string(),
+            user_id: "user".to_string(),
+            content: "hi".to_string(),
+            created_time: now_millis(),
+        };
+        store.save_pending_reply(&pending).await.unwrap();
+        // a retry that reaches the model again replaces the row
+        store.save_pending_reply(&pending).await.unwrap();
+        let pendings = store.get_pending_replies().await.unwrap();
+        assert_eq!(pendings.len(), 1);
+        assert_eq!(pendings[0].content, "hi");
+        assert_eq!(pendings[0].account, "shop");
+        // the same id from another sender is another message
+        store
+            .save_pending_reply(&PendingReply {
+                user_id: "other".to_string(),
+                ..pending.clone()
+            })
+            .await
+            .unwrap();
+        assert_eq!(store.get_pending_replies().await.unwrap().len(), 2);
+        store
+            .delete_pending_reply("sub", "other", 42)
+            .await
+            .unwrap();
+        store.delete_pending_reply("sub", "user", 42).await.unwrap();
+        assert!(store.get_pending_replies().await.unwrap().is_empty());
+
+        // saving the answer deletes the pending reply with it
+        store
+            .save_pending_reply(&PendingReply {
+                msg_id: 1,
+                ..pending
+            })
+            .await
+            .unwrap();
+        save_turns(&store).await;
+        assert!(store.get_pending_replies().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_knowledge_chunks() {
+        let store = store().await;
+        let chunk = KnowledgeChunk {
+            source: "faq.md".to_string(),
+            chunk_index: 0,
+            content: "退货请在七天内申请".to_string(),
+            embedding: vec![0.25, -0.5],
+        };
+        store
+            .replace_knowledge_source("shop", "faq.md", std::slice::from_ref(&chunk))
+            .await
+            .unwrap();
+        let (count, ingested) = store.knowledge_version("shop").await.unwrap();
+        assert_eq!(count, 1);
+        // ingesting the file again replaces its chunks
+        store
+            .replace_knowledge_source("shop", "faq.md", std::slice::from_ref(&chunk))
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get_knowledge_chunks("shop").await.unwrap(),
+            vec![chunk]
+        );
+        let (count, reingested) = store.knowledge_version("shop").await.unwrap();
+        assert_eq!(count, 1);
+        assert!(reingested >= ingested);
+        assert_eq!(store.knowledge_version("other").await.unwrap(), (0, 0));
+        assert!(store
+            .get_knowledge_chunks("other")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_forget_user() {
+        let store = store().await;
+        save_turns(&store).await;
+        store.record_usage("sub", "user", 1, 120).await.unwrap();
+        store.record_usage("sub", ERASED_USER, 1, 30).await.unwrap();
+        store
+            .set_user_tier("sub", "user", "restricted")
+            .await
+            .unwrap();
+        store.set_session_id("sub", "user", "s1").await.unwrap();
+        store
+            .save_moderation_audit(&ModerationAudit {
+                msg_id: 3,
+                user_id: "user".to_string(),
+                subscription_id: "sub".to_string(),
+                stage: ModerationStage::Input,
+                source: "keyword".to_string(),
+                reason: "bad".to_string(),
+                content: "something bad".to_string(),
+            })
+            .await
+            .unwrap();
+        store
+            .save_wechat_user(&WechatUser {
+                subscription_id: "sub".to_string(),
+                user_id: "user".to_string(),
+                nickname: "小明".to_string(),
+                language: "zh_CN".to_string(),
+                subscribe_time: 1700000000,
+                updated_time: 1,
+            })
+            .await
+            .unwrap();
+        store
+            .add_to_user_list(UserList::Blocked, "sub", "user")
+            .await
+            .unwrap();
+        sqlx::query("CREATE TABLE wechat_dialogue_record (msg_id BIGINT, user_id TEXT, subscription_id TEXT, type_id TEXT, message TEXT, elapsed BIGINT, created_time TIMESTAMP)")
+            .execute(&store.pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO wechat_dialogue_record VALUES (1, 'user', 'sub', 'message', '{}', 10, '2023-03-07 12:00:00'), (2, 'other', 'sub', 'message', '{}', 10, '2023-03-07 12:00:00')")
+            .execute(&store.pool)
+            .await
+            .unwrap();
+
+        let erased = store.forget_user("sub", "user").await.unwrap();
+        assert_eq!(erased["dialogue_turn"], 2);
+        assert_eq!(erased["wechat_dialogue_record"], 1);
+        assert_eq!(erased["daily_usage"], 1);
+        assert_eq!(erased["moderation_audit"], 1);
+        assert_eq!(erased["wechat_user"], 1);
+        assert!(store
+            .get_conversations("user", "sub", "")
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(store.get_wechat_user("sub", "user").await.unwrap(), None);
+        assert_eq!(store.get_session_id("sub", "user").await.unwrap(), "");
+        // erasing lifts neither a block nor a tier
+        assert!(store
+            .is_in_user_list(UserList::Blocked, "sub", "user")
+            .await
+            .unwrap());
+        assert_eq!(
+            store.get_user_tier("sub", "user").await.unwrap().as_deref(),
+            Some("restricted")
+        );
+        // nor does it give the account back what the user used today
+        assert_eq!(
+            store.get_daily_usage("sub", None).await.unwrap().tokens,
+            150
+        );
+        assert_eq!(
+            store
+                .get_daily_usage("sub", Some(ERASED_USER))
+                .await
+                .unwrap()
+                .tokens,
+            150
+        );
+        let audit: (String, String, String) = sqlx::query_as(
+            "SELECT user_id, reason, content FROM moderation_audit WHERE msg_id = 3",
+        )
+        .fetch_one(&store.pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            audit,
+            (ERASED_USER.to_string(), "bad".to_string(), String::new())
+        );
+        // the spend stays, under nobody
+        let spend = store.spend(&month_spend("sub")).await.unwrap();
+        assert_eq!(spend[0].user_id, ERASED_USER);
+        assert_eq!(spend[0].turns, 2);
+    }
+}
@@ -0,0 +1,263 @@
+use async_trait::async_trait;
+
+use crate::{error::Result, metrics::METRICS, settings::AccessMode};
+
+use super::{
+    Conversation, ConversationStore, DailyUsage, ErasedRows, KeywordRule, KnowledgeChunk,
+    KnowledgeVersion, ListedUser, ModerationAudit, PendingReply, Persona, PoolStatus, SpendQuery,
+    SpendRow, Turn, TurnQuery, TurnRecord, UserList, UserPreferences, WechatUser,
+};
+
+/// Observes the latency of every call to the wrapped store as
+/// `db_query_seconds`, labelled with the name of the method.
+pub struct TimedStore<S>(pub S);
+
+macro_rules! timed {
+    ($self:ident.$method:ident($($arg:expr),*)) => {{
+        let _timer = METRICS.db_timer(stringify!($method));
+        $self.0.$method($($arg),*).await
+    }};
+}
+
+#[async_trait]
+impl<S: ConversationStore> ConversationStore for TimedStore<S> {
+    fn pool_status(&self) -> Option<PoolStatus> {
+        self.0.pool_status()
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.0.ping().await
+    }
+
+    async fn migrate(&self) -> Result<Vec<&'static str>> {
+        self.0.migrate().await
+    }
+
+    async fn save_turn(&self, turn: &Turn) -> Result<()> {
+        timed!(self.save_turn(turn))
+    }
+
+    async fn get_reply_by_msg_id(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        msg_id: i64,
+    ) -> Result<Option<String>> {
+        timed!(self.get_reply_by_msg_id(subscription_id, user_id, msg_id))
+    }
+
+    async fn get_conversations(
+        &self,
+        user_id: &str,
+        subscription_id: &str,
+        session_id: &str,
+    ) -> Result<Vec<Conversation>> {
+        timed!(self.get_conversations(user_id, subscription_id, session_id))
+    }
+
+    async fn search_turns(&self, query: &TurnQuery) -> Result<Vec<TurnRecord>> {
+        timed!(self.search_turns(query))
+    }
+
+    async fn spend(&self, query: &SpendQuery) -> Result<Vec<SpendRow>> {
+        timed!(self.spend(query))
+    }
+
+    async fn get_user_tier(&self, subscription_id: &str, user_id: &str) -> Result<Option<String>> {
+        timed!(self.get_user_tier(subscription_id, user_id))
+    }
+
+    async fn set_user_tier(&self, subscription_id: &str, user_id: &str, tier: &str) -> Result<()> {
+        timed!(self.set_user_tier(subscription_id, user_id, tier))
+    }
+
+    async fn record_usage(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        messages: i64,
+        tokens: i64,
+    ) -> Result<()> {
+        timed!(self.record_usage(subscription_id, user_id, messages, tokens))
+    }
+
+    async fn get_daily_usage(
+        &self,
+        subscription_id: &str,
+        user_id: Option<&str>,
+    ) -> Result<DailyUsage> {
+        timed!(self.get_daily_usage(subscription_id, user_id))
+    }
+
+    async fn save_moderation_audit(&self, audit: &ModerationAudit) -> Result<()> {
+        timed!(self.save_moderation_audit(audit))
+    }
+
+    async fn add_to_user_list(
+        &self,
+        list: UserList,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<()> {
+        timed!(self.add_to_user_list(list, subscription_id, user_id))
+    }
+
+    async fn remove_from_user_list(
+        &self,
+        list: UserList,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<bool> {
+        timed!(self.remove_from_user_list(list, subscription_id, user_id))
+    }
+
+    async fn is_in_user_list(
+        &self,
+        list: UserList,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<bool> {
+        timed!(self.is_in_user_list(list, subscription_id, user_id))
+    }
+
+    async fn get_user_list(
+        &self,
+        list: UserList,
+        subscription_id: Option<&str>,
+    ) -> Result<Vec<ListedUser>> {
+        timed!(self.get_user_list(list, subscription_id))
+    }
+
+    async fn get_session_id(&self, subscription_id: &str, user_id: &str) -> Result<String> {
+        timed!(self.get_session_id(subscription_id, user_id))
+    }
+
+    async fn set_session_id(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        session_id: &str,
+    ) -> Result<()> {
+        timed!(self.set_session_id(subscription_id, user_id, session_id))
+    }
+
+    async fn get_access_mode(&self, subscription_id: &str) -> Result<Option<AccessMode>> {
+        timed!(self.get_access_mode(subscription_id))
+    }
+
+    async fn set_access_mode(&self, subscription_id: &str, mode: AccessMode) -> Result<()> {
+        timed!(self.set_access_mode(subscription_id, mode))
+    }
+
+    async fn get_personas(&self) -> Result<Vec<Persona>> {
+        timed!(self.get_personas())
+    }
+
+    async fn get_persona(&self, subscription_id: &str) -> Result<Option<String>> {
+        timed!(self.get_persona(subscription_id))
+    }
+
+    async fn set_persona(&self, subscription_id: &str, prompt: &str) -> Result<()> {
+        timed!(self.set_persona(subscription_id, prompt))
+    }
+
+    async fn delete_persona(&self, subscription_id: &str) -> Result<bool> {
+        timed!(self.delete_persona(subscription_id))
+    }
+
+    async fn get_keyword_rules(&self, subscription_id: Option<&str>) -> Result<Vec<KeywordRule>> {
+        timed!(self.get_keyword_rules(subscription_id))
+    }
+
+    async fn get_keyword_reply(
+        &self,
+        subscription_id: &str,
+        keyword: &str,
+    ) -> Result<Option<String>> {
+        timed!(self.get_keyword_reply(subscription_id, keyword))
+    }
+
+    async fn set_keyword_rule(
+        &self,
+        subscription_id: &str,
+        keyword: &str,
+        reply: &str,
+    ) -> Result<()> {
+        timed!(self.set_keyword_rule(subscription_id, keyword, reply))
+    }
+
+    async fn delete_keyword_rule(&self, subscription_id: &str, keyword: &str) -> Result<bool> {
+        timed!(self.delete_keyword_rule(subscription_id, keyword))
+    }
+
+    async fn save_pending_reply(&self, pending: &PendingReply) -> Result<()> {
+        timed!(self.save_pending_reply(pending))
+    }
+
+    async fn delete_pending_reply(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        msg_id: i64,
+    ) -> Result<()> {
+        timed!(self.delete_pending_reply(subscription_id, user_id, msg_id))
+    }
+
+    async fn get_pending_replies(&self) -> Result<Vec<PendingReply>> {
+        timed!(self.get_pending_replies())
+    }
+
+    async fn replace_knowledge_source(
+        &self,
+        base: &str,
+        source: &str,
+        chunks: &[KnowledgeChunk],
+    ) -> Result<()> {
+        timed!(self.replace_knowledge_source(base, source, chunks))
+    }
+
+    async fn get_knowledge_chunks(&self, base: &str) -> Result<Vec<KnowledgeChunk>> {
+        timed!(self.get_knowledge_chunks(base))
+    }
+
+    async fn knowledge_version(&self, base: &str) -> Result<KnowledgeVersion> {
+        timed!(self.knowledge_version(base))
+    }
+
+    async fn get_user_preferences(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<UserPreferences> {
+        timed!(self.get_user_preferences(subscription_id, user_id))
+    }
+
+    async fn set_user_preferences(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+        preferences: &UserPreferences,
+    ) -> Result<()> {
+        timed!(self.set_user_preferences(subscription_id, user_id, preferences))
+    }
+
+    async fn delete_user_preferences(&self, subscription_id: &str, user_id: &str) -> Result<bool> {
+        timed!(self.delete_user_preferences(subscription_id, user_id))
+    }
+
+    async fn get_wechat_user(
+        &self,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<Option<WechatUser>> {
+        timed!(self.get_wechat_user(subscription_id, user_id))
+    }
+
+    async fn save_wechat_user(&self, user: &WechatUser) -> Result<()> {
+        timed!(self.save_wechat_user(user))
+    }
+
+    async fn forget_user(&self, subscription_id: &str, user_id: &str) -> Result<ErasedRows> {
+        timed!(self.forget_user(subscription_id, user_id))
+    }
+}
@@ -0,0 +1,1071 @@
+use std::time::{Duration, Instant};
+
+use actix_web::web;
+use log::{debug, info, warn};
+use tokio::time::{sleep, timeout};
+use tracing::{info_span, Instrument, Span};
+
+use crate::{
+    access::{self, Admission},
+    api::{
+        chat_gpt::{ChatApi, ChatRequest, TokenUsage},
+        chat_gpt_35_turbo::ChatGpt35Turbo,
+        chat_gpt_text_davinci_003::ChatGptTextDavinci003,
+        images::{self, Drawing},
+        speech,
+        wechat::{self, MediaType},
+        wecom,
+    },
+    commands,
+    database::{
+        now_millis, ModerationAudit, ModerationStage, PendingReply, ReplyMode, Turn, TurnStatus,
+    },
+    error::{Error, Result},
+    knowledge,
+    limits::{Quota, Verdict},
+    metrics::{msg_type_label, METRICS, WECHAT_DEADLINE},
+    privacy, profile,
+    reload::{AccountSettings, Channel, Live},
+    settings::ChatGptConfig,
+    telemetry::{hash_openid, redact},
+    tools::ToolContext,
+    AppState,
+};
+
+/// WeChat lets customer service messages through within 48 hours of the
+/// follower's last message.
+const CUSTOM_MESSAGE_WINDOW: Duration = Duration::from_secs(48 * 3600);
+/// Left of the WeChat deadline to send a passive reply.
+const REPLY_MARGIN: Duration = Duration::from_millis(500);
+const DRAW_USAGE: &str = "用法: /draw <想画的内容>";
+const NO_MEDIA_REPLY: &str = "这里还不能发图片。";
+/// Commands about everything kept on the sender, which the web channel cannot
+/// tell apart from anyone else posting their user id.
+const PRIVATE_COMMANDS: &[&str] = &["/export", "/forget"];
+const NO_PRIVATE_COMMANDS_REPLY: &str = "这里不能导出或删除记录，请联系管理员。";
+/// How long a follower's first answer waits for their WeChat profile.
+const USER_INFO_TIMEOUT: Duration = Duration::from_secs(1);
+/// Characters per message of an `/export`, WeChat takes 2048 bytes.
+const EXPORT_PART_CHARS: usize = 600;
+/// Messages an `/export` sends at most, the rest is left to the admin API.
+const EXPORT_MAX_PARTS: usize = 20;
+/// What WeChat takes for a voice message at most.
+const VOICE_MAX_BYTES: usize = 2 * 1024 * 1024;
+/// What WeChat plays of a voice message at most.
+const VOICE_MAX_DURATION: Duration = Duration::from_secs(60);
+
+/// A message as every channel hands it to the [`BotEngine`].
+#[derive(Debug, Clone)]
+pub struct IncomingMessage {
+    /// Whose settings answer it, see [`crate::reload::Live::account`].
+    pub account: String,
+    /// Unique per `user_id` within `subscription_id`, a retry carries the
+    /// same one.
+    pub msg_id: i64,
+    pub user_id: String,
+    /// The conversation partner of `user_id`: an official account, a WeCom
+    /// corp or a website.
+    pub subscription_id: String,
+    pub msg_type: String,
+    pub content: String,
+    /// Seconds since the epoch.
+    pub create_time: i64,
+}
+
+/// What to send back for a message.
+#[derive(Debug, PartialEq, Eq)]
+pub enum OutgoingReply {
+    /// Nothing, the message is dropped or still being answered.
+    Nothing,
+    Text(String),
+    /// What a retried message was answered with the first time.
+    Again(String),
+    /// Media uploaded to the channel of the account, by its media id.
+    Media(MediaType, String),
+}
+
+/// The channel-neutral core. Channels turn what they receive into an
+/// [`IncomingMessage`] and send the [`OutgoingReply`] back their own way.
+#[derive(Clone)]
+pub struct BotEngine {
+    data: web::Data<AppState>,
+}
+
+impl BotEngine {
+    pub fn new(data: web::Data<AppState>) -> BotEngine {
+        BotEngine { data }
+    }
+
+    /// Runs a message through dedup and answers it with
+    /// [`BotEngine::respond`]. Text replies are cached for the retries of the
+    /// message, and a failed message is left for its retries to answer.
+    pub async fn handle(&self, message: &IncomingMessage, start: Instant) -> Result<OutgoingReply> {
+        let app_state = self.data.get_ref();
+        let live = app_state.live();
+        let account = match live.account(&message.account) {
+            Some(account) => account,
+            None => {
+                return Err(Error::ArgumentError(format!(
+                    "unknown account {}",
+                    message.account
+                )))
+            }
+        };
+
+        debug!(
+            "received {} message: {}",
+            message.msg_type,
+            redact(&message.content)
+        );
+        METRICS
+            .messages
+            .with_label_values(&[account.name, msg_type_label(&message.msg_type)])
+            .inc();
+
+        let msg_id = message.msg_id;
+        let user_id = message.user_id.clone();
+        let subscription_id = message.subscription_id.clone();
+
+        let cache = &app_state.cache;
+        let key = dedup_key(&subscription_id, &user_id, msg_id);
+        let create_time = message.create_time.to_string();
+        // claiming the key is atomic in every backend, so only one instance answers a message
+        if !cache
+            .set_nx(&key, &create_time, app_state.dedup_ttl)
+            .await?
+        {
+            warn!("there is re_call from the channel, key is {:?}", &key);
+            let message_from_cache = match cache
+                .get(&reply_key(&subscription_id, &user_id, msg_id))
+                .await?
+            {
+                Some(message) => {
+                    METRICS.dedup.with_label_values(&["cached_reply"]).inc();
+                    message
+                }
+                // turns of users who opted out of history keep no text
+                None => match app_state
+                    .store
+                    .get_reply_by_msg_id(&subscription_id, &user_id, msg_id)
+                    .await?
+                    .filter(|reply| !reply.is_empty())
+                {
+                    Some(message) => {
+                        METRICS.dedup.with_label_values(&["stored_reply"]).inc();
+                        message
+                    }
+                    None => {
+                        // the first request is still waiting for the model, nothing to answer yet
+                        METRICS.dedup.with_label_values(&["pending"]).inc();
+                        warn!("the re_call found no result yet, key is {:?}", &key);
+                        return Ok(OutgoingReply::Nothing);
+                    }
+                },
+            };
+            warn!("the re_call get correct result, key is {:?}", &key);
+            return Ok(OutgoingReply::Again(message_from_cache));
+        }
+        METRICS.dedup.with_label_values(&["first"]).inc();
+
+        let reply = match self.respond(message, &live, &account, start).await {
+            Ok(reply) => reply,
+            Err(e) => {
+                // a retry answers the message again instead of finding nothing
+                if let Err(e) = cache.delete(&key).await {
+                    warn!("could not release {:?}, {}", &key, e);
+                }
+                return Err(e);
+            }
+        };
+        // a retry gets the same reply, whichever step gave it
+        if let OutgoingReply::Text(text) = &reply {
+            if let Err(e) = cache
+                .set(
+                    &reply_key(&subscription_id, &user_id, msg_id),
+                    text,
+                    app_state.dedup_ttl,
+                )
+                .await
+            {
+                warn!("could not cache the reply, {}", e);
+            }
+        }
+        Ok(reply)
+    }
+
+    /// Everything after dedup: access, commands, keyword replies, limits,
+    /// moderation and finally the model.
+    async fn respond(
+        &self,
+        message: &IncomingMessage,
+        live: &Live,
+        account: &AccountSettings<'_>,
+        start: Instant,
+    ) -> Result<OutgoingReply> {
+        let app_state = self.data.get_ref();
+        let cache = &app_state.cache;
+        let msg_id = message.msg_id;
+        let user_id = message.user_id.clone();
+        let subscription_id = message.subscription_id.clone();
+
+        let admission = access::admit(
+            app_state.store.as_ref(),
+            &live.access,
+            account.admins,
+            &subscription_id,
+            &user_id,
+        )
+        .await?;
+        let rejection = match admission {
+            Admission::Admitted => None,
+            Admission::Blocked => Some(&live.access.blocked_reply),
+            Admission::NotAllowed => Some(&live.access.not_allowed_reply),
+        };
+        if let Some(reply) = rejection {
+            warn!(
+                "{} of {} rejected: {:?}",
+                hash_openid(&user_id),
+                subscription_id,
+                admission
+            );
+            if reply.is_empty() {
+                return Ok(OutgoingReply::Nothing);
+            }
+            return Ok(OutgoingReply::Text(reply.clone()));
+        }
+
+        if matches!(account.channel, Channel::Web(_)) && is_private_command(&message.content) {
+            return Ok(OutgoingReply::Text(NO_PRIVATE_COMMANDS_REPLY.to_string()));
+        }
+        if is_export(&message.content) {
+            return self.export(account, &subscription_id, &user_id).await;
+        }
+        if let Some(reply) = commands::handle(
+            app_state,
+            account,
+            &subscription_id,
+            &user_id,
+            &message.content,
+        )
+        .await?
+        {
+            return Ok(OutgoingReply::Text(reply));
+        }
+        let draw = draw_prompt(&message.content).filter(|_| live.images.enabled);
+        match draw {
+            Some("") => return Ok(OutgoingReply::Text(DRAW_USAGE.to_string())),
+            Some(_) if matches!(account.channel, Channel::Web(_)) => {
+                return Ok(OutgoingReply::Text(NO_MEDIA_REPLY.to_string()))
+            }
+            _ => {}
+        }
+
+        if let Some(reply) = app_state
+            .store
+            .get_keyword_reply(&subscription_id, message.content.trim())
+            .await?
+        {
+            return Ok(OutgoingReply::Text(reply));
+        }
+
+        let quota = Quota {
+            cache: cache.as_ref(),
+            store: app_state.store.as_ref(),
+            limits: account.limits,
+            utc_offset_hours: live.pricing.utc_offset_hours,
+        };
+        match quota.check(&subscription_id, &user_id).await? {
+            Verdict::Allowed => {}
+            Verdict::RateLimited => {
+                return Ok(OutgoingReply::Text(
+                    account.limits.rate_limited_reply.clone(),
+                ));
+            }
+            Verdict::QuotaExceeded => {
+                return Ok(OutgoingReply::Text(
+                    account.limits.quota_exceeded_reply.clone(),
+                ));
+            }
+        }
+
+        // only WeChat plays MP3, WeCom wants AMR
+        let voice = live.speech.enabled
+            && matches!(account.channel, Channel::Wechat(_))
+            && app_state
+                .store
+                .get_user_preferences(&subscription_id, &user_id)
+                .await?
+                .reply_mode
+                == ReplyMode::Voice;
+        let pending = PendingReply {
+            account: account.name.to_string(),
+            msg_id,
+            subscription_id,
+            user_id,
+            content: message.content.clone(),
+            created_time: now_millis(),
+        };
+        if !passes_moderation(
+            app_state,
+            ModerationStage::Input,
+            &pending,
+            &pending.content,
+        )
+        .await?
+        {
+            return Ok(OutgoingReply::Text(live.moderator.blocked_reply.clone()));
+        }
+        if let Some(prompt) = draw {
+            // a picture has no tokens, it counts as a message
+            quota
+                .record(&pending.subscription_id, &pending.user_id, 0)
+                .await?;
+            return self.draw(pending, prompt.to_string(), start).await;
+        }
+
+        if chat_api(&account.chat_gpt_config.model).is_none() {
+            return Err(Error::ArgumentError(format!(
+                "unknown model {}",
+                account.chat_gpt_config.model
+            )));
+        }
+        app_state.store.save_pending_reply(&pending).await?;
+        // the task finishes and saves the answer even if the channel hangs up first
+        let message_from_chat = app_state
+            .tasks
+            .spawn(
+                self.clone()
+                    .answer(pending, start)
+                    .instrument(Span::current()),
+            )
+            .await??;
+
+        if voice && message_from_chat.chars().count() <= live.speech.max_chars {
+            return Ok(self
+                .speak(account.name, &message.user_id, message_from_chat, start)
+                .await);
+        }
+        Ok(OutgoingReply::Text(message_from_chat))
+    }
+
+    /// The user's history as Markdown. Too long for one message, it goes out
+    /// in parts through [`BotEngine::deliver`] and the reply says how many.
+    async fn export(
+        &self,
+        account: &AccountSettings<'_>,
+        subscription_id: &str,
+        user_id: &str,
+    ) -> Result<OutgoingReply> {
+        let app_state = self.data.get_ref();
+        let exported = privacy::export(app_state.store.as_ref(), subscription_id, user_id).await?;
+        let markdown = privacy::to_markdown(&exported, app_state.live().pricing.utc_offset_hours);
+        let mut parts = privacy::split(&markdown, EXPORT_PART_CHARS);
+        let total = parts.len();
+        parts.truncate(EXPORT_MAX_PARTS);
+        let reply = if total > EXPORT_MAX_PARTS {
+            format!(
+                "记录共 {} 段，这里只发前 {} 段，完整的记录请联系管理员。",
+                total, EXPORT_MAX_PARTS
+            )
+        } else {
+            format!("记录共 {} 段，马上发给你。", total)
+        };
+
+        let engine = self.clone();
+        let (account, user_id) = (account.name.to_string(), user_id.to_string());
+        self.data.tasks.spawn(
+            async move {
+                // after the reply announcing them
+                sleep(REPLY_MARGIN).await;
+                for part in parts {
+                    if let Err(e) = engine.deliver(&account, &user_id, &part).await {
+                        warn!("could not deliver an export: {}", e);
+                        break;
+                    }
+                }
+            }
+            .instrument(Span::current()),
+        );
+        Ok(OutgoingReply::Text(reply))
+    }
+
+    /// `text` as a voice message for `user_id`. Like a drawing, the voice
+    /// goes back in the response if it is ready before WeChat stops waiting,
+    /// through [`BotEngine::deliver_media`] otherwise; the text goes instead
+    /// when it cannot be read out.
+    async fn speak(
+        &self,
+        account: &str,
+        user_id: &str,
+        text: String,
+        start: Instant,
+    ) -> OutgoingReply {
+        let (engine, name, reply) = (self.clone(), account.to_string(), text.clone());
+        let mut speaking = self.data.tasks.spawn(
+            async move { engine.read_out(&name, &reply).await }.instrument(info_span!("speak")),
+        );
+        let budget = WECHAT_DEADLINE.saturating_sub(REPLY_MARGIN + start.elapsed());
+        if let Ok(result) = timeout(budget, &mut speaking).await {
+            return match result {
+                Ok(Some(media_id)) => OutgoingReply::Media(MediaType::Voice, media_id),
+                _ => OutgoingReply::Text(text),
+            };
+        }
+
+        let engine = self.clone();
+        let (account, user_id) = (account.to_string(), user_id.to_string());
+        self.data.tasks.spawn(
+            async move {
+                let result = match speaking.await {
+                    Ok(Some(media_id)) => {
+                        engine
+                            .deliver_media(&account, &user_id, MediaType::Voice, &media_id)
+                            .await
+                    }
+                    _ => engine.deliver(&account, &user_id, &text).await,
+                };
+                if let Err(e) = result {
+                    warn!("could not deliver a voice answer: {}", e);
+                }
+            }
+            .instrument(Span::current()),
+        );
+        OutgoingReply::Nothing
+    }
+
+    /// `text` read out and uploaded to the channel of `account`, `None` when
+    /// the audio is too long for WeChat or anything fails.
+    async fn read_out(&self, account: &str, text: &str) -> Option<String> {
+        let live = self.data.live();
+        let result = async {
+            let account = live
+                .account(account)
+                .ok_or_else(|| Error::ArgumentError(format!("account {} is gone", account)))?;
+            let audio = speech::synthesize(
+                &self.data.client,
+                &live.speech,
+                &account.chat_gpt_config.api,
+                text,
+            )
+            .await?;
+            let duration = speech::mp3_duration(&audio);
+            if audio.len() > VOICE_MAX_BYTES
+                || duration.is_none_or(|duration| duration > VOICE_MAX_DURATION)
+            {
+                return Err(Error::ArgumentError(format!(
+                    "{} bytes of audio lasting {:?}",
+                    audio.len(),
+                    duration
+                )));
+            }
+            self.upload(account.channel, MediaType::Voice, "answer.mp3", audio)
+                .await
+        }
+        .await;
+        match result {
+            Ok(media_id) => Some(media_id),
+            Err(e) => {
+                warn!("answering in text, no voice: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Asks the model, then records the turn and the usage and caches the reply
+    /// for retries.
+    async fn answer(self, pending: PendingReply, start: Instant) -> Result<String> {
+        let app_state = self.data.get_ref();
+        let live = app_state.live();
+        let account = match live.account(&pending.account) {
+            Some(account) => account,
+            None => {
+                app_state
+                    .store
+                    .delete_pending_reply(
+                        &pending.subscription_id,
+                        &pending.user_id,
+                        pending.msg_id,
+                    )
+                    .await?;
+                return Err(Error::ArgumentError(format!(
+                    "account {} is gone",
+                    pending.account
+                )));
+            }
+        };
+        let msg_id = pending.msg_id;
+        let user_id = &pending.user_id;
+        let subscription_id = &pending.subscription_id;
+
+        let preferences = app_state
+            .store
+            .get_user_preferences(subscription_id, user_id)
+            .await?;
+        // a model the user picked that is no longer served falls back to the account's
+        let config = ChatGptConfig {
+            model: preferences
+                .model
+                .clone()
+                .filter(|model| known_model(model))
+                .unwrap_or_else(|| account.chat_gpt_config.model.clone()),
+            ..account.chat_gpt_config.clone()
+        };
+        let session_id = app_state
+            .store
+            .get_session_id(subscription_id, user_id)
+            .await?;
+        let context = if preferences.history_opt_out {
+            vec![]
+        } else {
+            app_state
+                .store
+                .get_conversations(user_id, subscription_id, &session_id)
+                .await?
+        };
+        // a persona the operator has since removed falls back to the account's
+        let persona = match preferences
+            .persona
+            .as_deref()
+            .and_then(|name| live.personas.get(name))
+        {
+            Some(persona) => Some(persona.clone()),
+            None => app_state
+                .store
+                .get_persona(subscription_id)
+                .await?
+                .or_else(|| account.persona.map(str::to_string)),
+        };
+        let wechat_user = match account.channel {
+            Channel::Wechat(config) if live.user_info.enabled => {
+                let lookup = profile::lookup(app_state, config, subscription_id, user_id)
+                    .instrument(info_span!("user_info"));
+                // a persona without the nickname beats a late answer
+                match timeout(USER_INFO_TIMEOUT, lookup).await {
+                    Ok(Ok(user)) => Some(user),
+                    Ok(Err(e)) => {
+                        warn!("answering without the user's profile: {}", e);
+                        None
+                    }
+                    Err(_) => {
+                        warn!("answering without the user's profile: timed out");
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        let persona = persona.map(|persona| {
+            profile::render_persona(
+                &persona,
+                wechat_user.as_ref(),
+                &live.user_info.nickname_fallback,
+            )
+        });
+        let passages = match account.knowledge_base {
+            // a failed lookup should not cost the answer
+            Some(base) => knowledge::retrieve(
+                app_state.store.as_ref(),
+                &app_state.chunks,
+                &app_state.client,
+                &live.knowledge,
+                &account.chat_gpt_config.api,
+                base,
+                &pending.content,
+            )
+            .instrument(info_span!("retrieve", base))
+            .await
+            .unwrap_or_else(|e| {
+                warn!("answering without knowledge base {}: {}", base, e);
+                vec![]
+            }),
+            None => vec![],
+        };
+
+        let tools = live.tools.with_context(ToolContext {
+            store: app_state.store.as_ref(),
+            chunks: &app_state.chunks,
+            client: &app_state.client,
+            knowledge: &live.knowledge,
+            api_key: &account.chat_gpt_config.api,
+            knowledge_base: account.knowledge_base,
+        });
+
+        debug!("send prompt to chatgpt");
+
+        let api = match chat_api(&config.model) {
+            Some(api) => api,
+            None => {
+                app_state
+                    .store
+                    .delete_pending_reply(subscription_id, user_id, msg_id)
+                    .await?;
+                return Err(Error::ArgumentError(format!(
+                    "unknown model {}",
+                    config.model
+                )));
+            }
+        };
+        let llm_labels = [account.name, api.provider(), config.model.as_str()];
+        let llm_timer = METRICS
+            .llm_seconds
+            .with_label_values(&llm_labels)
+            .start_timer();
+        let result = api
+            .send_message(
+                &app_state.client,
+                &config,
+                &ChatRequest {
+                    persona: persona.as_deref(),
+                    preferences: &preferences,
+                    knowledge: &passages,
+                    tools: Some(&tools),
+                    context: &context,
+                    message_from_user: &pending.content,
+                },
+            )
+            .instrument(info_span!(
+                "llm",
+                provider = llm_labels[1],
+                model = llm_labels[2]
+            ))
+            .await;
+
+        llm_timer.observe_duration();
+        debug!("get result to chatgpt");
+
+        let mut turn = Turn {
+            msg_id,
+            user_id: user_id.clone(),
+            subscription_id: subscription_id.clone(),
+            session_id,
+            model: config.model.clone(),
+            // kept for usage and cost, without what was said
+            user_message: if preferences.history_opt_out {
+                String::new()
+            } else {
+                pending.content.clone()
+            },
+            reply_message: String::new(),
+            status: TurnStatus::Ok,
+            error: None,
+            elapsed: start.elapsed(),
+            usage: TokenUsage::default(),
+            cost: 0.0,
+        };
+        let mut message_from_chat = match result {
+            Ok(reply) => {
+                if !reply.model.is_empty() {
+                    turn.model = reply.model;
+                }
+                turn.usage = reply.usage;
+                METRICS.record_tokens(account.name, &turn.model, &turn.usage);
+                turn.cost = live.pricing.cost(&turn.model, &turn.usage);
+                reply.content
+            }
+            Err(e) => {
+                METRICS.llm_errors.with_label_values(&llm_labels).inc();
+                turn.status = TurnStatus::Error;
+                turn.error = Some(e.to_string());
+                app_state.store.save_turn(&turn).await?;
+                // let a retry try again instead of looking up a reply that never came
+                app_state
+                    .cache
+                    .delete(&dedup_key(subscription_id, user_id, msg_id))
+                    .await?;
+                return Err(e);
+            }
+        };
+
+        if !passes_moderation(
+            app_state,
+            ModerationStage::Output,
+            &pending,
+            &message_from_chat,
+        )
+        .await?
+        {
+            message_from_chat = live.moderator.blocked_reply.clone();
+        }
+
+        if !preferences.history_opt_out {
+            turn.reply_message = message_from_chat.clone();
+        }
+        app_state.store.save_turn(&turn).await?;
+        let quota = Quota {
+            cache: app_state.cache.as_ref(),
+            store: app_state.store.as_ref(),
+            limits: account.limits,
+            utc_offset_hours: live.pricing.utc_offset_hours,
+        };
+        quota
+            .record(subscription_id, user_id, turn.usage.total_tokens)
+            .await?;
+
+        // a retry may land on another instance before it can see the database row
+        app_state
+            .cache
+            .set(
+                &reply_key(subscription_id, user_id, msg_id),
+                &message_from_chat,
+                app_state.dedup_ttl,
+            )
+            .await?;
+
+        Ok(message_from_chat)
+    }
+
+    /// Draws `prompt` for the sender of `pending`. The picture goes back in
+    /// the response if it is ready before WeChat stops waiting, through
+    /// [`BotEngine::deliver_media`] otherwise.
+    async fn draw(
+        &self,
+        pending: PendingReply,
+        prompt: String,
+        start: Instant,
+    ) -> Result<OutgoingReply> {
+        let engine = self.clone();
+        let account = pending.account.clone();
+        let mut drawing = self.data.tasks.spawn(
+            async move { engine.paint(&account, &prompt).await }.instrument(info_span!("draw")),
+        );
+        let budget = WECHAT_DEADLINE.saturating_sub(REPLY_MARGIN + start.elapsed());
+        if let Ok(reply) = timeout(budget, &mut drawing).await {
+            return reply?;
+        }
+
+        let engine = self.clone();
+        self.data.tasks.spawn(
+            async move {
+                let (account, user_id) = (&pending.account, &pending.user_id);
+                let result = match drawing.await {
+                    Ok(Ok(OutgoingReply::Media(media_type, media_id))) => {
+                        engine
+                            .deliver_media(account, user_id, media_type, &media_id)
+                            .await
+                    }
+                    Ok(Ok(OutgoingReply::Text(reply))) => {
+                        engine.deliver(account, user_id, &reply).await
+                    }
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err(e)) => Err(e),
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = result {
+                    warn!("could not deliver a drawing: {}", e);
+                }
+            }
+            .instrument(Span::current()),
+        );
+        Ok(OutgoingReply::Nothing)
+    }
+
+    /// The picture for `prompt` uploaded to the channel of `account`, or a
+    /// text saying why there is none.
+    async fn paint(&self, account: &str, prompt: &str) -> Result<OutgoingReply> {
+        let data = self.data.get_ref();
+        let live = data.live();
+        let account = match live.account(account) {
+            Some(account) => account,
+            None => return Err(Error::ArgumentError(format!("account {} is gone", account))),
+        };
+        let failed = || Ok(OutgoingReply::Text(live.images.failed_reply.clone()));
+        let png = match images::generate(
+            &data.client,
+            &live.images,
+            &account.chat_gpt_config.api,
+            prompt,
+        )
+        .await
+        {
+            Ok(Drawing::Png(png)) => png,
+            Ok(Drawing::Refused(reason)) => {
+                warn!("the images endpoint refused a prompt: {}", reason);
+                return Ok(OutgoingReply::Text(live.moderator.blocked_reply.clone()));
+            }
+            Err(e) => {
+                warn!("could not draw: {}", e);
+                return failed();
+            }
+        };
+        match self
+            .upload(account.channel, MediaType::Image, "drawing.png", png)
+            .await
+        {
+            Ok(media_id) => Ok(OutgoingReply::Media(MediaType::Image, media_id)),
+            Err(e) => {
+                warn!("could not upload a drawing: {}", e);
+                failed()
+            }
+        }
+    }
+
+    /// Uploads temporary media to `channel`, returning its media id.
+    async fn upload(
+        &self,
+        channel: Channel<'_>,
+        media_type: MediaType,
+        file_name: &str,
+        media: Vec<u8>,
+    ) -> Result<String> {
+        let (client, cache) = (&self.data.client, self.data.cache.as_ref());
+        match channel {
+            Channel::Wechat(config) => {
+                let access_token = wechat::get_access_token(client, cache, config).await?;
+                wechat::upload_media(client, &access_token, media_type, file_name, media).await
+            }
+            Channel::Wecom(config) => {
+                let access_token = wecom::get_access_token(client, cache, config).await?;
+                wecom::upload_media(client, &access_token, media_type, file_name, media).await
+            }
+            Channel::Web(_) => Err(Error::ArgumentError(NO_MEDIA_REPLY.to_string())),
+        }
+    }
+
+    /// Answers the messages an earlier run took in but never answered. The
+    /// channels have stopped waiting for them, so the answers go out through
+    /// [`BotEngine::deliver`]. Only messages from before `started` are picked
+    /// up, after `delay` gave another instance sharing the database time to
+    /// finish its own.
+    pub async fn resume_pending_replies(&self, started: i64, delay: Duration) -> Result<()> {
+        sleep(delay).await;
+        let app_state = self.data.get_ref();
+        for pending in app_state.store.get_pending_replies().await? {
+            if pending.created_time >= started {
+                continue;
+            }
+            if now_millis() - pending.created_time > CUSTOM_MESSAGE_WINDOW.as_millis() as i64 {
+                warn!("message {} is too old to be answered", pending.msg_id);
+                app_state
+                    .store
+                    .delete_pending_reply(
+                        &pending.subscription_id,
+                        &pending.user_id,
+                        pending.msg_id,
+                    )
+                    .await?;
+                continue;
+            }
+            let span = info_span!(
+                "resume",
+                msg_id = pending.msg_id,
+                openid = %hash_openid(&pending.user_id),
+                subscription = %pending.subscription_id,
+            );
+            let engine = self.clone();
+            app_state.tasks.spawn(
+                async move {
+                    let account = pending.account.clone();
+                    let user_id = pending.user_id.clone();
+                    let result = match engine.clone().answer(pending, Instant::now()).await {
+                        Ok(reply) => engine.deliver(&account, &user_id, &reply).await,
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(()) => info!("sent the answer of an earlier run"),
+                        Err(e) => warn!("could not answer a message of an earlier run: {}", e),
+                    }
+                }
+                .instrument(span),
+            );
+        }
+        Ok(())
+    }
+
+    /// Sends an answer outside of the request that asked for it.
+    pub async fn deliver(&self, account: &str, user_id: &str, content: &str) -> Result<()> {
+        let data = self.data.get_ref();
+        let live = data.live();
+        let channel = match live.account(account) {
+            Some(account) => account.channel,
+            None => return Err(Error::ArgumentError(format!("account {} is gone", account))),
+        };
+        let (client, cache) = (&data.client, data.cache.as_ref());
+        match channel {
+            Channel::Wechat(config) => {
+                let access_token = wechat::get_access_token(client, cache, config).await?;
+                wechat::send_text(client, &access_token, user_id, content).await
+            }
+            Channel::Wecom(config) => {
+                let access_token = wecom::get_access_token(client, cache, config).await?;
+                wecom::send_text(client, &access_token, config.agent_id, user_id, content).await
+            }
+            // nothing to push to, a retry with the same msg_id picks the answer up
+            Channel::Web(_) => Ok(()),
+        }
+    }
+
+    /// Sends media uploaded to the channel outside of the request that asked
+    /// for it.
+    pub async fn deliver_media(
+        &self,
+        account: &str,
+        user_id: &str,
+        media_type: MediaType,
+        media_id: &str,
+    ) -> Result<()> {
+        let data = self.data.get_ref();
+        let live = data.live();
+        let channel = match live.account(account) {
+            Some(account) => account.channel,
+            None => return Err(Error::ArgumentError(format!("account {} is gone", account))),
+        };
+        let (client, cache) = (&data.client, data.cache.as_ref());
+        match channel {
+            Channel::Wechat(config) => {
+                let access_token = wechat::get_access_token(client, cache, config).await?;
+                wechat::send_media(client, &access_token, user_id, media_type, media_id).await
+            }
+            Channel::Wecom(config) => {
+                let access_token = wecom::get_access_token(client, cache, config).await?;
+                wecom::send_media(
+                    client,
+                    &access_token,
+                    config.agent_id,
+                    user_id,
+                    media_type,
+                    media_id,
+                )
+                .await
+            }
+            Channel::Web(_) => Ok(()),
+        }
+    }
+}
+
+/// Whether the message is an `/export`.
+fn is_export(content: &str) -> bool {
+    content.split_whitespace().next() == Some("/export")
+}
+
+fn is_private_command(content: &str) -> bool {
+    content
+        .split_whitespace()
+        .next()
+        .is_some_and(|command| PRIVATE_COMMANDS.contains(&command))
+}
+
+/// The prompt of a `/draw` message, empty when there is none.
+fn draw_prompt(content: &str) -> Option<&str> {
+    let prompt = content.trim().strip_prefix("/draw")?;
+    if !prompt.is_empty() && !prompt.starts_with(char::is_whitespace) {
+        return None;
+    }
+    Some(prompt.trim())
+}
+
+/// The models [`chat_api`] has an API for.
+pub const MODELS: &[&str] = &["gpt-3.5-turbo", "text-davinci-003"];
+
+pub fn known_model(model: &str) -> bool {
+    MODELS.contains(&model)
+}
+
+fn chat_api(model: &str) -> Option<Box<dyn ChatApi + Send + Sync>> {
+    match model {
+        "text-davinci-003" => Some(Box::new(ChatGptTextDavinci003)),
+        "gpt-3.5-turbo" => Some(Box::new(ChatGpt35Turbo)),
+        _ => None,
+    }
+}
+
+/// Whether `content` may be published, auditing it when it may not.
+async fn passes_moderation(
+    app_state: &AppState,
+    stage: ModerationStage,
+    message: &PendingReply,
+    content: &str,
+) -> Result<bool> {
+    let flag = match app_state
+        .live()
+        .moderator
+        .check(&app_state.client, content)
+        .await?
+    {
+        Some(flag) => flag,
+        None => return Ok(true),
+    };
+    warn!(
+        "{} of {} flagged by {}: {}",
+        stage.as_str(),
+        message.msg_id,
+        flag.source,
+        flag.reason
+    );
+    app_state
+        .store
+        .save_moderation_audit(&ModerationAudit {
+            msg_id: message.msg_id,
+            user_id: message.user_id.clone(),
+            subscription_id: message.subscription_id.clone(),
+            stage,
+            source: flag.source.to_string(),
+            reason: flag.reason,
+            content: content.to_string(),
+        })
+        .await?;
+    Ok(false)
+}
+
+// message ids are only unique per sender, the web channel leaves them to its clients
+fn dedup_key(subscription_id: &str, user_id: &str, msg_id: i64) -> String {
+    format!("WECHAT_MSG_ID_{}_{}_{}", subscription_id, user_id, msg_id)
+}
+
+/// Where a reply is cached for the retries of its message.
+pub fn reply_key(subscription_id: &str, user_id: &str, msg_id: i64) -> String {
+    format!("WECHAT_REPLY_{}_{}_{}", subscription_id, user_id, msg_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::DEFAULT_ACCOUNT;
+
+    fn message(content: &str) -> IncomingMessage {
+        IncomingMessage {
+            account: DEFAULT_ACCOUNT.to_string(),
+            msg_id: 1,
+            user_id: "user".to_string(),
+            subscription_id: "sub".to_string(),
+            msg_type: "text".to_string(),
+            content: content.to_string(),
+            create_time: 1700000000,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_gets_the_same_reply() {
+        let state = AppState::for_tests();
+        let store = state.store.clone();
+        let cache = state.cache.clone();
+        let engine = BotEngine::new(web::Data::new(state.clone()));
+
+        // a reply given before the model is asked
+        store
+            .set_keyword_rule("sub", "你好", "hello")
+            .await
+            .unwrap();
+        let reply = engine.handle(&message("你好"), Instant::now()).await;
+        assert_eq!(reply.unwrap(), OutgoingReply::Text("hello".to_string()));
+        store.set_keyword_rule("sub", "你好", "hi").await.unwrap();
+        let reply = engine.handle(&message("你好"), Instant::now()).await;
+        assert_eq!(reply.unwrap(), OutgoingReply::Again("hello".to_string()));
+
+        // a message that failed is answered again by its retry
+        state.update_live(|live| live.chat_gpt_config.model = "gpt-99".to_string());
+        let mut failing = message("讲个笑话");
+        failing.msg_id = 2;
+        assert!(engine.handle(&failing, Instant::now()).await.is_err());
+        let key = dedup_key("sub", "user", 2);
+        assert_eq!(cache.get(&key).await.unwrap(), None);
+        assert!(engine.handle(&failing, Instant::now()).await.is_err());
+    }
+
+    #[test]
+    fn test_draw_prompt() {
+        assert_eq!(draw_prompt(" /draw 一只猫 "), Some("一只猫"));
+        assert_eq!(draw_prompt("/draw"), Some(""));
+        assert_eq!(draw_prompt("/drawing"), None);
+        assert_eq!(draw_prompt("画一只猫"), None);
+    }
+}
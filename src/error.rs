@@ -5,6 +5,7 @@ use thiserror::Error;
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("Invalid signature")]
     InvalidSignature,
@@ -16,6 +17,18 @@ pub enum Error {
     IoError(#[from] std::io::Error),
     #[error("json error: {0}")]
     JsonError(#[from] serde_json::Error),
+    #[error("cache error: {0}")]
+    CacheError(#[from] redis::RedisError),
+    #[error("invalid argument: {0}")]
+    ArgumentError(String),
+    #[error("unauthorized")]
+    Unauthorized,
+    #[error("telemetry error: {0}")]
+    TelemetryError(String),
+    #[error("wechat error {0}: {1}")]
+    WechatError(i64, String),
+    #[error("task error: {0}")]
+    TaskError(#[from] tokio::task::JoinError),
 }
 
 impl ResponseError for Error {
@@ -34,6 +47,22 @@ impl ResponseError for Error {
             Error::JsonError(e) => {
                 HttpResponse::InternalServerError().body(format!("json error: {}", e))
             }
+            Error::CacheError(e) => {
+                HttpResponse::InternalServerError().body(format!("cache error: {}", e))
+            }
+            Error::ArgumentError(e) => {
+                HttpResponse::BadRequest().body(format!("invalid argument: {}", e))
+            }
+            Error::Unauthorized => HttpResponse::Unauthorized().body("unauthorized"),
+            Error::TelemetryError(e) => {
+                HttpResponse::InternalServerError().body(format!("telemetry error: {}", e))
+            }
+            Error::WechatError(code, e) => {
+                HttpResponse::BadGateway().body(format!("wechat error {}: {}", code, e))
+            }
+            Error::TaskError(e) => {
+                HttpResponse::InternalServerError().body(format!("task error: {}", e))
+            }
         }
     }
 }
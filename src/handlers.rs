@@ -1,21 +1,25 @@
-use std::time::{Duration, Instant};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use actix_web::{get, post, web, HttpResponse};
 use log::{debug, warn};
+use tracing::{info_span, instrument, Instrument};
 
 use actix_xml::Xml;
 use serde::Deserialize;
-use serde_xml_rs::to_string;
+use serde_xml_rs::{from_str, to_string};
 
 use crate::{
     api::{
-        chat_gpt::ChatApi,
-        chat_gpt_35_turbo::ChatGpt35Turbo,
-        chat_gpt_text_davinci_003::ChatGptTextDavinci003,
-        wechat::{verify_signature, TextMessage, WeChatRequest},
+        msg_crypt::{EncryptedMessage, MsgCrypt},
+        wechat::{verify_signature, MediaMessage, TextMessage, WeChatRequest},
+        wecom::{self, WecomRequest},
     },
-    database::{get_conversation_by_msg_id, get_conversations, save_conversation},
-    error::Result,
+    engine::{BotEngine, IncomingMessage, OutgoingReply},
+    error::{Error, Result},
+    metrics::ReplyTimer,
+    reload::Channel,
+    settings::{DEFAULT_ACCOUNT, WECOM_ACCOUNT},
+    telemetry::{hash_openid, redact},
     AppState,
 };
 
@@ -35,93 +39,258 @@ pub struct WeChatMessage {
     pub msg_id: i64,
 }
 
+impl WeChatMessage {
+    fn into_incoming(self, account: &str) -> IncomingMessage {
+        IncomingMessage {
+            account: account.to_string(),
+            msg_id: self.msg_id,
+            user_id: self.from_user_name,
+            subscription_id: self.to_user_name,
+            msg_type: self.msg_type,
+            content: self.content,
+            create_time: self.create_time,
+        }
+    }
+}
+
 #[post("/")]
 async fn handle_wechat_message(
-    wechat_message: Xml<WeChatMessage>,
+    info: web::Query<WeChatRequest>,
+    body: String,
     data: web::Data<AppState>,
 ) -> Result<HttpResponse> {
-    let start = Instant::now();
-    let app_state = data.get_ref();
-
-    debug!("received wechat message: {:?}", &wechat_message);
-
-    let msg_id = wechat_message.msg_id;
-    let user_id = wechat_message.from_user_name.clone();
-    let subscription_id = wechat_message.to_user_name.clone();
-
-    let cache = &data.cache;
-    let key = format!("WECHAT_MSG_ID_{}", msg_id.to_string());
-    match cache.get(&key).await {
-        Some(_i) => {
-            warn!("there is re_call from wechat, key is {:?}", &key);
-            let message_from_cache = get_conversation_by_msg_id(&app_state.pool, msg_id).await?;
-            warn!("the re_call get correct result, key is {:?}", &key);
-            let xml_response = get_response_xml(user_id, subscription_id, message_from_cache);
-
-            return Ok(HttpResponse::Ok()
-                .content_type("text/xml")
-                .body(xml_response));
-        }
-        None => {
-            let value = std::time::Instant::now();
-            let ttl = Duration::from_secs(60);
-            cache.set(&key, value, ttl).await;
-        }
-    }
+    open_message(DEFAULT_ACCOUNT, info.into_inner(), &body, data).await
+}
 
-    let context = get_conversations(&app_state.pool, &user_id, &subscription_id).await?;
+/// Messages to one of `[[accounts]]`.
+#[post("/wx/{account}")]
+async fn handle_account_message(
+    account: web::Path<String>,
+    info: web::Query<WeChatRequest>,
+    body: String,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    open_message(&account, info.into_inner(), &body, data).await
+}
 
-    debug!("send prompt to chatgpt");
+/// Checks the signature of a message to an official account and decrypts it
+/// when the account is in safe mode, before anything else looks at it.
+async fn open_message(
+    account_name: &str,
+    info: WeChatRequest,
+    body: &str,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let live = data.live();
+    let config = match live.account(account_name).map(|account| account.channel) {
+        Some(Channel::Wechat(config)) => config,
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+    verify_signature(&info, &config.token)?;
 
-    let api: Box<dyn ChatApi> = match app_state.chat_gpt_config.model.as_str() {
-        "text-davinci-003" => Box::new(ChatGptTextDavinci003),
-        "gpt-3.5-turbo" => Box::new(ChatGpt35Turbo),
-        _ => return Ok(HttpResponse::BadRequest().finish()),
+    let parse = |xml: &str| {
+        from_str::<WeChatMessage>(xml)
+            .map_err(|e| Error::ArgumentError(format!("wechat message: {}", e)))
     };
-    let message_from_chat = api
-        .send_message(
-            &app_state.client,
-            &app_state.chat_gpt_config,
-            &context,
-            &wechat_message.content,
-        )
-        .await?;
+    if info.encrypt_type.as_deref() != Some("aes") {
+        return handle_message(account_name, parse(body)?, None, data).await;
+    }
+    let encoding_aes_key = config.encoding_aes_key.as_deref().ok_or_else(|| {
+        Error::ArgumentError(format!(
+            "account {} is in safe mode without an encoding_aes_key",
+            account_name
+        ))
+    })?;
+    let crypt = MsgCrypt::new(&config.token, encoding_aes_key, &config.app_id)?;
+    let sealed = from_str::<EncryptedMessage>(body)
+        .map_err(|e| Error::ArgumentError(format!("wechat message: {}", e)))?;
+    let xml = crypt.open(
+        info.msg_signature.as_deref().unwrap_or_default(),
+        &info.timestamp,
+        &info.nonce,
+        &sealed.encrypt,
+    )?;
+    let sealer = Sealer {
+        crypt,
+        nonce: info.nonce,
+    };
+    handle_message(account_name, parse(&xml)?, Some(sealer), data).await
+}
 
-    debug!("get result to chatgpt");
+/// Encrypts the passive replies of an account in safe mode.
+struct Sealer {
+    crypt: MsgCrypt,
+    nonce: String,
+}
 
-    let elapsed = start.elapsed();
+impl Sealer {
+    fn seal(&self, xml: &str) -> HttpResponse {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .to_string();
+        let reply = self.crypt.seal(xml, &timestamp, &self.nonce);
+        HttpResponse::Ok()
+            .content_type("text/xml")
+            .body(to_string(&reply).unwrap())
+    }
+}
 
-    save_conversation(
-        &app_state.pool,
-        msg_id,
-        &user_id,
-        &subscription_id,
-        &wechat_message.content,
-        &message_from_chat,
-        elapsed,
+#[instrument(
+    name = "message",
+    skip_all,
+    fields(
+        account = %account_name,
+        msg_id = wechat_message.msg_id,
+        openid = %hash_openid(&wechat_message.from_user_name),
+        subscription = %wechat_message.to_user_name,
     )
-    .await?;
+)]
+async fn handle_message(
+    account_name: &str,
+    wechat_message: WeChatMessage,
+    sealer: Option<Sealer>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let start = Instant::now();
+    let _reply_timer = ReplyTimer::start();
 
-    let xml_response = get_response_xml(user_id, subscription_id, message_from_chat);
+    let message = wechat_message.into_incoming(account_name);
+    let xml = match BotEngine::new(data).handle(&message, start).await? {
+        OutgoingReply::Text(content) | OutgoingReply::Again(content) => {
+            get_response_xml(message.user_id, message.subscription_id, content)
+        }
+        OutgoingReply::Media(media_type, media_id) => {
+            let media_message = MediaMessage::new(
+                message.user_id,
+                message.subscription_id,
+                media_type,
+                media_id,
+            );
+            to_string(&media_message).unwrap()
+        }
+        OutgoingReply::Nothing => return Ok(HttpResponse::Ok().body("success")),
+    };
+    match sealer {
+        Some(sealer) => Ok(sealer.seal(&xml)),
+        None => Ok(HttpResponse::Ok().content_type("text/xml").body(xml)),
+    }
+}
 
-    Ok(HttpResponse::Ok()
-        .content_type("text/xml")
-        .body(xml_response))
+/// Messages to the WeCom application. They come encrypted and are answered
+/// through `message/send` once the model is done, so WeCom gets an empty
+/// response right away and has nothing to retry.
+#[post("/wecom")]
+async fn handle_wecom_message(
+    request: web::Query<WecomRequest>,
+    body: Xml<EncryptedMessage>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let live = data.live();
+    let config = match live.account(WECOM_ACCOUNT).map(|account| account.channel) {
+        Some(Channel::Wecom(config)) => config,
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let xml = wecom::msg_crypt(config)?.open(
+        &request.msg_signature,
+        &request.timestamp,
+        &request.nonce,
+        &body.encrypt,
+    )?;
+    let message = from_str::<WeChatMessage>(&xml)
+        .map_err(|e| Error::ArgumentError(format!("wecom message: {}", e)))?
+        .into_incoming(WECOM_ACCOUNT);
+
+    let span = info_span!(
+        "message",
+        account = WECOM_ACCOUNT,
+        msg_id = message.msg_id,
+        openid = %hash_openid(&message.user_id),
+        subscription = %message.subscription_id,
+    );
+    let engine = BotEngine::new(data.clone());
+    data.tasks.spawn(
+        async move {
+            let result = match engine.handle(&message, Instant::now()).await {
+                Ok(OutgoingReply::Text(reply)) => {
+                    engine
+                        .deliver(WECOM_ACCOUNT, &message.user_id, &reply)
+                        .await
+                }
+                Ok(OutgoingReply::Media(media_type, media_id)) => {
+                    engine
+                        .deliver_media(WECOM_ACCOUNT, &message.user_id, media_type, &media_id)
+                        .await
+                }
+                // the first callback is answered already
+                Ok(OutgoingReply::Again(_) | OutgoingReply::Nothing) => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                warn!("could not answer a wecom message: {}", e);
+            }
+        }
+        .instrument(span),
+    );
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// The callback address check of the WeCom application.
+#[get("/wecom")]
+async fn verify_wecom(
+    request: web::Query<WecomRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let live = data.live();
+    let config = match live.account(WECOM_ACCOUNT).map(|account| account.channel) {
+        Some(Channel::Wecom(config)) => config,
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let echostr = match &request.echostr {
+        Some(s) => s,
+        None => return Ok(HttpResponse::BadRequest().finish()),
+    };
+    let echostr = wecom::msg_crypt(config)?.open(
+        &request.msg_signature,
+        &request.timestamp,
+        &request.nonce,
+        echostr,
+    )?;
+    Ok(HttpResponse::Ok().body(echostr))
 }
 
 fn get_response_xml(to_user_name: String, from_user_name: String, content: String) -> String {
     let text_message = TextMessage::new(to_user_name, from_user_name, content);
 
-    debug!("xml_response: {:?}", &text_message);
+    debug!("xml_response: {}", redact(format!("{:?}", &text_message)));
     to_string(&text_message).unwrap()
 }
 
 #[get("/")]
 async fn index(info: web::Query<WeChatRequest>, data: web::Data<AppState>) -> Result<HttpResponse> {
+    verify(DEFAULT_ACCOUNT, info.into_inner(), &data)
+}
+
+/// The server address check of one of `[[accounts]]`.
+#[get("/wx/{account}")]
+async fn verify_account(
+    account: web::Path<String>,
+    info: web::Query<WeChatRequest>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    verify(&account, info.into_inner(), &data)
+}
+
+fn verify(account: &str, info: WeChatRequest, app_state: &AppState) -> Result<HttpResponse> {
     debug!("info is {:?}", &info);
     let echostr = info.echostr.clone();
-    let app_state = data.get_ref();
-    verify_signature(&info, &app_state.wechat_config.token)?;
+    let live = app_state.live();
+    let wechat_config = match live.account(account).map(|account| account.channel) {
+        Some(Channel::Wechat(config)) => config,
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+    verify_signature(&info, &wechat_config.token)?;
 
     let echostr = match echostr {
         Some(s) => s,
@@ -129,3 +298,92 @@ async fn index(info: web::Query<WeChatRequest>, data: web::Data<AppState>) -> Re
     };
     Ok(HttpResponse::Ok().body(echostr))
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, App};
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+    use crate::api::msg_crypt::EncryptedReply;
+
+    const KEY: &str = "jWmYm7qr5nMoAUwZRjGtBxmz3KA1tkAj3ykkR6q2B2C";
+
+    fn signature(token: &str, timestamp: &str, nonce: &str) -> String {
+        let mut values = [token, timestamp, nonce];
+        values.sort();
+        hex::encode(Sha1::digest(values.concat().as_bytes()))
+    }
+
+    #[actix_web::test]
+    async fn test_wechat_message() {
+        let state = AppState::for_tests();
+        state.update_live(|live| {
+            live.wechat_config.app_id = "wxapp".to_string();
+            live.wechat_config.token = "token".to_string();
+            live.wechat_config.encoding_aes_key = Some(KEY.to_string());
+        });
+        state
+            .store
+            .set_keyword_rule("gh_sub", "你好", "hello")
+            .await
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .service(handle_wechat_message),
+        )
+        .await;
+        let message = "<xml><ToUserName>gh_sub</ToUserName><FromUserName>user</FromUserName><CreateTime>1</CreateTime><MsgType>text</MsgType><Content>你好</Content><MsgId>1</MsgId></xml>";
+
+        let req = test::TestRequest::post()
+            .uri("/?signature=forged&timestamp=1&nonce=2")
+            .set_payload(message)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .to_string();
+        let uri = format!(
+            "/?signature={}&timestamp={}&nonce=2",
+            signature("token", &now, "2"),
+            now
+        );
+        let req = test::TestRequest::post()
+            .uri(&uri)
+            .set_payload(message)
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert!(String::from_utf8_lossy(&body).contains("<Content>hello</Content>"));
+
+        // safe mode: the message and the reply are encrypted
+        let crypt = MsgCrypt::new("token", KEY, "wxapp").unwrap();
+        let message = message.replace("<MsgId>1</MsgId>", "<MsgId>2</MsgId>");
+        let sealed = crypt.seal(&message, &now, "2");
+        let req = test::TestRequest::post()
+            .uri(&format!(
+                "{}&encrypt_type=aes&msg_signature={}",
+                uri, sealed.msg_signature
+            ))
+            .set_payload(format!(
+                "<xml><ToUserName>gh_sub</ToUserName><Encrypt>{}</Encrypt></xml>",
+                sealed.encrypt
+            ))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let reply: EncryptedReply = from_str(&String::from_utf8_lossy(&body)).unwrap();
+        let xml = crypt
+            .open(
+                &reply.msg_signature,
+                &reply.timestamp,
+                &reply.nonce,
+                &reply.encrypt,
+            )
+            .unwrap();
+        assert!(xml.contains("<Content>hello</Content>"));
+    }
+}
@@ -0,0 +1,119 @@
+use std::{collections::BTreeMap, future::Future, time::Instant};
+
+use actix_web::{get, web, HttpResponse};
+use log::warn;
+use serde::Serialize;
+use tokio::time::timeout;
+
+use crate::{error::Result, AppState};
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    status: &'static str,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+#[derive(Debug, Serialize)]
+struct Check {
+    status: &'static str,
+    latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn is_ok(&self) -> bool {
+        self.status == "ok"
+    }
+}
+
+/// Liveness: the process is up and serving requests.
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness: the database and the cache answer. A failing provider ping only
+/// marks the instance `degraded`, every instance would fail it at once.
+#[get("/readyz")]
+async fn readyz(data: web::Data<AppState>) -> HttpResponse {
+    let app_state = data.get_ref();
+    let live = app_state.live();
+    let mut checks = BTreeMap::new();
+    checks.insert("database", check(app_state, app_state.store.ping()).await);
+    checks.insert("cache", check(app_state, app_state.cache.ping()).await);
+    let ready = checks.values().all(Check::is_ok);
+    if let Some(url) = &live.health.provider_url {
+        checks.insert(
+            "provider",
+            check(app_state, ping_provider(app_state, url)).await,
+        );
+    }
+
+    let status = match (ready, checks.values().all(Check::is_ok)) {
+        (false, _) => "unavailable",
+        (true, false) => "degraded",
+        (true, true) => "ready",
+    };
+    let body = Readiness { status, checks };
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        warn!("not ready: {:?}", body);
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+async fn check(app_state: &AppState, probe: impl Future<Output = Result<()>>) -> Check {
+    let start = Instant::now();
+    let error = match timeout(app_state.live().health.timeout(), probe).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some("timed out".to_string()),
+    };
+    Check {
+        status: if error.is_none() { "ok" } else { "error" },
+        latency_ms: start.elapsed().as_millis(),
+        error,
+    }
+}
+
+async fn ping_provider(app_state: &AppState, url: &str) -> Result<()> {
+    app_state
+        .client
+        .get(url)
+        .bearer_auth(&app_state.live().chat_gpt_config.api)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, App};
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_readyz() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState::for_tests()))
+                .service(healthz)
+                .service(readyz),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/healthz").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get().uri("/readyz").to_request();
+        let body: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["status"], "ready");
+        assert_eq!(body["checks"]["database"]["status"], "ok");
+        assert_eq!(body["checks"]["cache"]["status"], "ok");
+        assert!(body["checks"].get("provider").is_none());
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::{ConversationStore, KnowledgeChunk, KnowledgeVersion},
+    error::{Error, Result},
+    settings::Knowledge,
+};
+
+/// Inputs per embeddings request.
+const EMBED_BATCH: usize = 64;
+/// What `the-world ingest` reads from a directory.
+const TEXT_EXTENSIONS: &[&str] = &["md", "markdown", "txt"];
+
+/// A chunk picked for a message. The model cites it by its position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Passage {
+    pub source: String,
+    pub content: String,
+    pub score: f32,
+}
+
+/// What [`ingest`] did.
+#[derive(Debug, Default)]
+pub struct Ingested {
+    /// The chunk count per file.
+    pub files: Vec<(String, usize)>,
+    /// PDFs found in a directory, left out until their text is extracted.
+    pub skipped: Vec<String>,
+}
+
+/// The chunks of every base searched so far, so that the embeddings are not
+/// loaded and parsed again for each message. `the-world ingest` runs in a
+/// process of its own, so a base is loaded again once its version in the
+/// database no longer matches.
+#[derive(Default)]
+pub struct ChunkCache {
+    bases: Mutex<HashMap<String, CachedBase>>,
+}
+
+struct CachedBase {
+    version: KnowledgeVersion,
+    chunks: Arc<Vec<KnowledgeChunk>>,
+}
+
+impl ChunkCache {
+    pub async fn chunks(
+        &self,
+        store: &dyn ConversationStore,
+        base: &str,
+    ) -> Result<Arc<Vec<KnowledgeChunk>>> {
+        let version = store.knowledge_version(base).await?;
+        if let Some(cached) = self.bases.lock().unwrap().get(base) {
+            if cached.version == version {
+                return Ok(cached.chunks.clone());
+            }
+        }
+        let chunks = Arc::new(store.get_knowledge_chunks(base).await?);
+        self.bases.lock().unwrap().insert(
+            base.to_string(),
+            CachedBase {
+                version,
+                chunks: chunks.clone(),
+            },
+        );
+        Ok(chunks)
+    }
+}
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<Embedding>,
+}
+
+#[derive(Deserialize)]
+struct Embedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+/// Cuts `text` at paragraph breaks into chunks of at most `chunk_chars`
+/// characters. A paragraph longer than that is cut into windows sharing
+/// `overlap` characters.
+pub fn chunk(text: &str, chunk_chars: usize, overlap: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut current = String::new();
+    for paragraph in text.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+        let length = paragraph.chars().count();
+        if !current.is_empty() && current.chars().count() + 2 + length > chunk_chars {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if length <= chunk_chars {
+            if !current.is_empty() {
+                current.push_str("\n\n");
+            }
+            current.push_str(paragraph);
+            continue;
+        }
+        let chars: Vec<char> = paragraph.chars().collect();
+        let mut start = 0;
+        loop {
+            let end = (start + chunk_chars).min(chars.len());
+            chunks.push(chars[start..end].iter().collect());
+            if end == chars.len() {
+                break;
+            }
+            start = end - overlap.min(chunk_chars - 1);
+        }
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Embeds `inputs` through an OpenAI compatible `/v1/embeddings` endpoint.
+pub async fn embed(
+    client: &Client,
+    knowledge: &Knowledge,
+    api_key: &str,
+    inputs: &[String],
+) -> Result<Vec<Vec<f32>>> {
+    let mut embeddings = Vec::with_capacity(inputs.len());
+    for batch in inputs.chunks(EMBED_BATCH) {
+        let mut response: EmbeddingResponse = client
+            .post(&knowledge.url)
+            .bearer_auth(knowledge.api_key.as_deref().unwrap_or(api_key))
+            .json(&EmbeddingRequest {
+                model: &knowledge.model,
+                input: batch,
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if response.data.len() != batch.len() {
+            return Err(Error::ArgumentError(format!(
+                "{} embeddings for {} inputs",
+                response.data.len(),
+                batch.len()
+            )));
+        }
+        response.data.sort_by_key(|embedding| embedding.index);
+        embeddings.extend(response.data.into_iter().map(|e| e.embedding));
+    }
+    Ok(embeddings)
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 || a.len() != b.len() {
+        return 0.0;
+    }
+    dot / norms
+}
+
+/// The `top_k` chunks most similar to `query`, best first.
+pub fn rank(
+    chunks: &[KnowledgeChunk],
+    query: &[f32],
+    top_k: usize,
+    min_score: f32,
+) -> Vec<Passage> {
+    let mut scored: Vec<(f32, &KnowledgeChunk)> = chunks
+        .iter()
+        .map(|chunk| (cosine(&chunk.embedding, query), chunk))
+        .filter(|(score, _)| *score >= min_score)
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored
+        .into_iter()
+        .take(top_k)
+        .map(|(score, chunk)| Passage {
+            source: chunk.source.clone(),
+            content: chunk.content.clone(),
+            score,
+        })
+        .collect()
+}
+
+/// The passages of `base` to answer `query` with. Every chunk is compared,
+/// which is quick enough for a few thousand of them.
+pub async fn retrieve(
+    store: &dyn ConversationStore,
+    cache: &ChunkCache,
+    client: &Client,
+    knowledge: &Knowledge,
+    api_key: &str,
+    base: &str,
+    query: &str,
+) -> Result<Vec<Passage>> {
+    let chunks = cache.chunks(store, base).await?;
+    if chunks.is_empty() {
+        return Ok(vec![]);
+    }
+    let query = embed(client, knowledge, api_key, &[query.to_string()]).await?;
+    Ok(rank(
+        &chunks,
+        &query[0],
+        knowledge.top_k,
+        knowledge.min_score,
+    ))
+}
+
+/// Chunks, embeds and stores the file at `path`, or every Markdown and text
+/// file below it, into `base`. Files are stored under their canonical path,
+/// so a file ingested again replaces its chunks however it was named.
+pub async fn ingest(
+    store: &dyn ConversationStore,
+    client: &Client,
+    knowledge: &Knowledge,
+    api_key: &str,
+    base: &str,
+    path: &Path,
+) -> Result<Ingested> {
+    let mut files = vec![];
+    let mut ingested = Ingested::default();
+    collect_files(path, &mut files, &mut ingested.skipped)?;
+    for file in files {
+        if file.extension().is_some_and(|ext| ext == "pdf") {
+            return Err(Error::ArgumentError(format!(
+                "{}: extract the text first, e.g. with pdftotext",
+                file.display()
+            )));
+        }
+        let chunks = chunk(
+            &fs::read_to_string(&file)?,
+            knowledge.chunk_chars,
+            knowledge.chunk_overlap,
+        );
+        let embeddings = embed(client, knowledge, api_key, &chunks).await?;
+        let source = fs::canonicalize(&file)?.display().to_string();
+        let chunks: Vec<KnowledgeChunk> = chunks
+            .into_iter()
+            .zip(embeddings)
+            .enumerate()
+            .map(|(i, (content, embedding))| KnowledgeChunk {
+                source: source.clone(),
+                chunk_index: i as i64,
+                content,
+                embedding,
+            })
+            .collect();
+        store
+            .replace_knowledge_source(base, &source, &chunks)
+            .await?;
+        ingested.files.push((source, chunks.len()));
+    }
+    Ok(ingested)
+}
+
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>, skipped: &mut Vec<String>) -> Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries: Vec<_> = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<_>>()?;
+    entries.sort();
+    for entry in entries {
+        let is_text = entry
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| TEXT_EXTENSIONS.contains(&ext));
+        if entry.is_dir() {
+            collect_files(&entry, files, skipped)?;
+        } else if is_text {
+            files.push(entry);
+        } else if entry.extension().is_some_and(|ext| ext == "pdf") {
+            skipped.push(entry.display().to_string());
+        }
+    }
+    Ok(())
+}
+
+/// The passages as the model gets them, numbered for citing.
+pub fn reference_text(passages: &[Passage]) -> String {
+    let mut text = "回答时优先依据以下资料，并在用到的句子后标注资料编号，如 [1]。资料里没有的内容不要编造。\n".to_string();
+    for (i, passage) in passages.iter().enumerate() {
+        text.push_str(&format!(
+            "\n[{}]（{}）\n{}\n",
+            i + 1,
+            passage.source,
+            passage.content
+        ));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::memory::MemoryStore;
+
+    #[test]
+    fn test_chunk() {
+        let text = "第一段。\n\n第二段。\n\n\n0123456789";
+        assert_eq!(
+            chunk(text, 10, 2),
+            vec!["第一段。\n\n第二段。", "0123456789"]
+        );
+        assert_eq!(chunk("0123456789", 4, 1), vec!["0123", "3456", "6789"]);
+        assert!(chunk("\n\n", 4, 1).is_empty());
+    }
+
+    #[test]
+    fn test_rank() {
+        let chunk = |source: &str, embedding: Vec<f32>| KnowledgeChunk {
+            source: source.to_string(),
+            chunk_index: 0,
+            content: source.to_string(),
+            embedding,
+        };
+        let chunks = vec![
+            chunk("near", vec![1.0, 0.1]),
+            chunk("far", vec![-1.0, 0.0]),
+            chunk("nearest", vec![1.0, 0.0]),
+        ];
+        let passages = rank(&chunks, &[2.0, 0.0], 2, 0.5);
+        let sources: Vec<&str> = passages.iter().map(|p| p.source.as_str()).collect();
+        assert_eq!(sources, vec!["nearest", "near"]);
+        assert!((passages[0].score - 1.0).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn test_chunk_cache() {
+        let store = MemoryStore::new(8);
+        let cache = ChunkCache::default();
+        let chunk = |content: &str| KnowledgeChunk {
+            source: "faq.md".to_string(),
+            chunk_index: 0,
+            content: content.to_string(),
+            embedding: vec![1.0],
+        };
+        store
+            .replace_knowledge_source("shop", "faq.md", &[chunk("old")])
+            .await
+            .unwrap();
+        let loaded = cache.chunks(&store, "shop").await.unwrap();
+        assert!(Arc::ptr_eq(
+            &loaded,
+            &cache.chunks(&store, "shop").await.unwrap()
+        ));
+
+        // an ingest changes the version, whichever process it ran in
+        store
+            .replace_knowledge_source("shop", "faq.md", &[chunk("new"), chunk("more")])
+            .await
+            .unwrap();
+        let reloaded = cache.chunks(&store, "shop").await.unwrap();
+        assert_eq!(reloaded[0].content, "new");
+        assert!(cache.chunks(&store, "other").await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_collect_files() {
+        let dir = std::env::temp_dir().join(format!("wechatgpt-knowledge-{}", std::process::id()));
+        fs::create_dir_all(dir.join("manuals")).unwrap();
+        for name in [
+            "faq.md",
+            "logo.png",
+            "manuals/guide.txt",
+            "manuals/scan.pdf",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let mut files = vec![];
+        let mut skipped = vec![];
+        collect_files(&dir, &mut files, &mut skipped).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            files,
+            vec![dir.join("faq.md"), dir.join("manuals/guide.txt")]
+        );
+        assert_eq!(
+            skipped,
+            vec![dir.join("manuals/scan.pdf").display().to_string()]
+        );
+    }
+}
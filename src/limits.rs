@@ -0,0 +1,223 @@
+use std::time::Duration;
+
+use log::warn;
+
+use crate::{
+    cache::CacheStore,
+    database::{day_of, now_millis, ConversationStore},
+    error::Result,
+    settings::Limits,
+    telemetry::hash_openid,
+};
+
+const MINUTE: Duration = Duration::from_secs(60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+const TIER_TTL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    RateLimited,
+    QuotaExceeded,
+}
+
+/// Enforces `[limits]`. Counters live in the cache so that every instance
+/// sees them; the database keeps the daily totals and seeds the token
+/// counters after a restart.
+pub struct Quota<'a> {
+    pub cache: &'a dyn CacheStore,
+    pub store: &'a dyn ConversationStore,
+    pub limits: &'a Limits,
+    pub utc_offset_hours: i32,
+}
+
+impl<'a> Quota<'a> {
+    /// Counts the message against the per-minute limits and checks what is
+    /// left of today's tokens.
+    pub async fn check(&self, subscription_id: &str, user_id: &str) -> Result<Verdict> {
+        let user_limit = self
+            .limits
+            .tier(&self.tier(subscription_id, user_id).await?);
+        let account_limit = self.limits.subscription;
+        let minute = now_millis() / 60_000;
+
+        let user_messages = self
+            .cache
+            .incr(&user_rate_key(subscription_id, user_id, minute), 1, MINUTE)
+            .await?;
+        let account_messages = self
+            .cache
+            .incr(&format!("RATE_{}_{}", subscription_id, minute), 1, MINUTE)
+            .await?;
+        if exceeds(user_messages, user_limit.messages_per_minute)
+            || exceeds(account_messages, account_limit.messages_per_minute)
+        {
+            warn!(
+                "{} of {} is rate limited",
+                hash_openid(user_id),
+                subscription_id
+            );
+            return Ok(Verdict::RateLimited);
+        }
+
+        let user_tokens = self.tokens_today(subscription_id, Some(user_id)).await?;
+        let account_tokens = self.tokens_today(subscription_id, None).await?;
+        if reached(user_tokens, user_limit.tokens_per_day)
+            || reached(account_tokens, account_limit.tokens_per_day)
+        {
+            warn!(
+                "{} of {} is out of tokens",
+                hash_openid(user_id),
+                subscription_id
+            );
+            return Ok(Verdict::QuotaExceeded);
+        }
+        Ok(Verdict::Allowed)
+    }
+
+    /// Books an answered message and the tokens it used.
+    pub async fn record(&self, subscription_id: &str, user_id: &str, tokens: i64) -> Result<()> {
+        // make sure the counters start from the stored totals before adding to them
+        self.tokens_today(subscription_id, Some(user_id)).await?;
+        self.tokens_today(subscription_id, None).await?;
+        self.cache
+            .incr(
+                &self.tokens_key(subscription_id, Some(user_id)),
+                tokens,
+                DAY,
+            )
+            .await?;
+        self.cache
+            .incr(&self.tokens_key(subscription_id, None), tokens, DAY)
+            .await?;
+        self.store
+            .record_usage(subscription_id, user_id, 1, tokens)
+            .await
+    }
+
+    pub async fn grant_tier(&self, subscription_id: &str, user_id: &str, tier: &str) -> Result<()> {
+        self.store
+            .set_user_tier(subscription_id, user_id, tier)
+            .await?;
+        self.cache.delete(&tier_key(subscription_id, user_id)).await
+    }
+
+    async fn tier(&self, subscription_id: &str, user_id: &str) -> Result<String> {
+        let key = tier_key(subscription_id, user_id);
+        if let Some(tier) = self.cache.get(&key).await? {
+            return Ok(tier);
+        }
+        let tier = self
+            .store
+            .get_user_tier(subscription_id, user_id)
+            .await?
+            .unwrap_or_else(|| self.limits.default_tier.clone());
+        self.cache.set(&key, &tier, TIER_TTL).await?;
+        Ok(tier)
+    }
+
+    async fn tokens_today(&self, subscription_id: &str, user_id: Option<&str>) -> Result<i64> {
+        let key = self.tokens_key(subscription_id, user_id);
+        if let Some(tokens) = self.cache.get(&key).await? {
+            return Ok(tokens.parse().unwrap_or(0));
+        }
+        let stored = self
+            .store
+            .get_daily_usage(subscription_id, user_id)
+            .await?
+            .tokens;
+        // another instance may have seeded the counter in the meantime, keep theirs
+        self.cache.set_nx(&key, &stored.to_string(), DAY).await?;
+        Ok(self
+            .cache
+            .get(&key)
+            .await?
+            .and_then(|tokens| tokens.parse().ok())
+            .unwrap_or(stored))
+    }
+
+    fn tokens_key(&self, subscription_id: &str, user_id: Option<&str>) -> String {
+        format!(
+            "TOKENS_{}_{}_{}",
+            subscription_id,
+            user_id.unwrap_or("*"),
+            day_of(now_millis(), self.utc_offset_hours)
+        )
+    }
+}
+
+fn exceeds(count: i64, limit: i64) -> bool {
+    limit > 0 && count > limit
+}
+
+fn reached(count: i64, limit: i64) -> bool {
+    limit > 0 && count >= limit
+}
+
+fn user_rate_key(subscription_id: &str, user_id: &str, minute: i64) -> String {
+    format!("RATE_{}_{}_{}", subscription_id, user_id, minute)
+}
+
+/// Where a user's tier is cached.
+pub fn tier_key(subscription_id: &str, user_id: &str) -> String {
+    format!("TIER_{}_{}", subscription_id, user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        cache::memory::MemoryStore as MemoryCache, database::memory::MemoryStore, settings::Limit,
+    };
+
+    #[tokio::test]
+    async fn test_quota() {
+        let cache = MemoryCache::new(100);
+        let store = MemoryStore::new(8);
+        let limits = Limits {
+            tiers: HashMap::from([
+                (
+                    "default".to_string(),
+                    Limit {
+                        messages_per_minute: 2,
+                        tokens_per_day: 100,
+                    },
+                ),
+                (
+                    "vip".to_string(),
+                    Limit {
+                        messages_per_minute: 10,
+                        tokens_per_day: 1000,
+                    },
+                ),
+            ]),
+            ..Limits::default()
+        };
+        let quota = Quota {
+            cache: &cache,
+            store: &store,
+            limits: &limits,
+            utc_offset_hours: 8,
+        };
+
+        assert_eq!(quota.check("sub", "user").await.unwrap(), Verdict::Allowed);
+        quota.record("sub", "user", 100).await.unwrap();
+        assert_eq!(
+            quota.check("sub", "user").await.unwrap(),
+            Verdict::QuotaExceeded
+        );
+        assert_eq!(
+            quota.check("sub", "user").await.unwrap(),
+            Verdict::RateLimited
+        );
+
+        quota.grant_tier("sub", "user", "vip").await.unwrap();
+        assert_eq!(quota.check("sub", "user").await.unwrap(), Verdict::Allowed);
+        assert_eq!(
+            store.get_daily_usage("sub", None).await.unwrap().tokens,
+            100
+        );
+    }
+}
@@ -1,98 +1,233 @@
-use std::{sync::Arc, time::Duration};
+use std::{path::Path, sync::Arc, time::Duration};
 
-use actix_web::{web::Data, App, HttpServer};
-use log::info;
+use actix_web::{rt, web::Data, App, HttpServer};
+use arc_swap::ArcSwap;
+use log::{info, warn};
 
 use reqwest::Client;
-use simple_logger::SimpleLogger;
-use sqlx::{
-    mysql::{MySqlConnectOptions, MySqlPoolOptions, MySqlSslMode},
-    MySql, Pool,
-};
 
 use crate::{
-    cache::Cache,
+    cache::CacheStore,
+    cli::Command,
+    database::{now_millis, ConversationStore},
+    engine::BotEngine,
     error::Result,
-    handlers::{handle_wechat_message, index},
-    settings::{ChatGptConfig, Database, Settings, WechatConfig},
+    handlers::{
+        handle_account_message, handle_wechat_message, handle_wecom_message, index, verify_account,
+        verify_wecom,
+    },
+    knowledge::ChunkCache,
+    reload::{Live, Reloader},
+    settings::Settings,
+    tasks::Tasks,
 };
 
+mod access;
+mod admin;
 mod api;
 mod cache;
+mod cli;
+mod commands;
 mod database;
+mod engine;
 mod error;
 mod handlers;
+mod health;
+mod knowledge;
+mod limits;
+mod metrics;
+mod moderation;
+mod privacy;
+mod profile;
+mod reload;
 mod settings;
+mod tasks;
+mod telemetry;
+mod tools;
+mod web_channel;
 
 #[derive(Clone)]
 struct AppState {
-    pool: Pool<MySql>,
+    store: Arc<dyn ConversationStore>,
+    /// The knowledge chunks loaded from `store`.
+    chunks: Arc<ChunkCache>,
     client: Client,
-    chat_gpt_config: ChatGptConfig,
-    wechat_config: WechatConfig,
-    cache: Arc<Cache>,
+    cache: Arc<dyn CacheStore>,
+    dedup_ttl: Duration,
+    tasks: Tasks,
+    live: Arc<ArcSwap<Live>>,
+}
+
+impl AppState {
+    /// The reloadable settings as they are now.
+    fn live(&self) -> Arc<Live> {
+        self.live.load_full()
+    }
 }
 
-async fn get_pool(database: Database) -> Result<Pool<MySql>> {
-    let options = MySqlConnectOptions::new()
-        .host(&database.host)
-        .username(&database.username)
-        .password(&database.password)
-        .database(&database.database)
-        .ssl_mode(MySqlSslMode::Preferred)
-        .ssl_ca(&std::path::PathBuf::from(&database.ssl_ca_path()));
-
-    let pool = MySqlPoolOptions::new()
-        .max_connections(database.max_connections)
-        .connect_with(options)
-        .await?;
-    Ok(pool)
+#[cfg(test)]
+impl AppState {
+    /// In-memory stores and default settings.
+    fn for_tests() -> AppState {
+        AppState {
+            store: Arc::new(database::memory::MemoryStore::new(8)),
+            chunks: Default::default(),
+            client: Client::new(),
+            cache: Arc::new(cache::memory::MemoryStore::new(100)),
+            dedup_ttl: Duration::from_secs(60),
+            tasks: Tasks::new(),
+            live: Arc::new(ArcSwap::from_pointee(Live {
+                wechat_config: settings::WechatConfig {
+                    app_id: String::new(),
+                    app_secret: String::new(),
+                    token: String::new(),
+                    encoding_aes_key: None,
+                },
+                chat_gpt_config: settings::ChatGptConfig {
+                    api: String::new(),
+                    model: "gpt-3.5-turbo".to_string(),
+                },
+                pricing: Default::default(),
+                limits: Default::default(),
+                admin: Default::default(),
+                access: Default::default(),
+                health: Default::default(),
+                moderator: Arc::new(moderation::Moderator::new(&Default::default(), "").unwrap()),
+                accounts: vec![],
+                wecom: None,
+                web: None,
+                knowledge: Default::default(),
+                tools: Arc::new(tools::ToolRegistry::new(&Default::default()).unwrap()),
+                images: Default::default(),
+                speech: Default::default(),
+                user_info: Default::default(),
+                personas: Default::default(),
+            })),
+        }
+    }
+
+    fn update_live(&self, f: impl FnOnce(&mut Live)) {
+        let mut live = (*self.live()).clone();
+        f(&mut live);
+        self.live.store(Arc::new(live));
+    }
 }
 
 #[actix_web::main]
 async fn main() -> Result<()> {
-    let s = Settings::new().unwrap();
-
-    SimpleLogger::new()
-        .with_level(s.log.get_level_filter())
-        .init()
-        .unwrap();
-
-    let cache = Arc::new(Cache::new());
-    let cache_clone = Arc::clone(&cache);
-    let ttl = Duration::from_secs(60);
-    tokio::spawn(async move {
-        cache_clone.cleanup(ttl).await;
-    });
-
-    let pool = get_pool(s.database).await?;
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let config_path = cli::take_config_path(&mut args)?;
+    let s = match Settings::load(config_path.as_deref()) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    let command = cli::parse(&args, s.pricing.utc_offset_hours)?;
+
+    telemetry::init(&s.log)?;
+
+    let cache = cache::connect(&s.cache).await?;
+    let dedup_ttl = s.cache.ttl();
+
+    let store = database::connect(&s.database, s.pricing.utc_offset_hours).await?;
+    let migrate_only = matches!(command, Command::Migrate);
+    if migrate_only || s.database.run_migrations {
+        for name in store.migrate().await? {
+            info!("migration {} applied", name);
+        }
+    }
+    match command {
+        Command::Serve => {}
+        Command::Migrate => return Ok(()),
+        Command::Usage(query) => {
+            cli::print_spend(&store.spend(&query).await?, &s.pricing.currency);
+            return Ok(());
+        }
+        Command::Ingest { base, paths } => {
+            let client = Client::new();
+            for path in paths {
+                let ingested = knowledge::ingest(
+                    store.as_ref(),
+                    &client,
+                    &s.knowledge,
+                    &s.chat_gpt_config.api,
+                    &base,
+                    Path::new(&path),
+                )
+                .await?;
+                for (source, chunks) in ingested.files {
+                    println!("{}\t{} chunks", source, chunks);
+                }
+                for source in ingested.skipped {
+                    eprintln!(
+                        "{}\tskipped, extract the text first, e.g. with pdftotext",
+                        source
+                    );
+                }
+            }
+            return Ok(());
+        }
+    }
 
     let client = Client::new();
 
-    let chat_gpt_config = s.chat_gpt_config;
-
-    let wechat_config = s.wechat_config;
+    let live = Arc::new(ArcSwap::from_pointee(Live::new(&s)?));
+    let reloader = Reloader::new(config_path, &s, live.clone());
+    rt::spawn(reloader.watch(s.server.config_poll()));
 
     let app_state = AppState {
-        pool,
+        store,
+        chunks: Default::default(),
         client,
-        chat_gpt_config,
-        wechat_config,
-        cache: Arc::clone(&cache),
+        cache,
+        dedup_ttl,
+        tasks: Tasks::new(),
+        live,
     };
+    let tasks = app_state.tasks.clone();
+    let data = Data::new(app_state);
+
+    let grace = s.server.shutdown_grace();
+    let started = now_millis();
+    let engine = BotEngine::new(data.clone());
+    rt::spawn(async move {
+        if let Err(e) = engine.resume_pending_replies(started, grace).await {
+            warn!("could not resume pending replies: {}", e);
+        }
+    });
 
     let ip = s.server.get_ip();
 
     info!("server listening at http://{:?}", &ip);
     HttpServer::new(move || {
         App::new()
-            .app_data(Data::new(app_state.clone()))
+            .app_data(data.clone())
             .service(handle_wechat_message)
             .service(index)
+            .service(handle_account_message)
+            .service(verify_account)
+            .service(handle_wecom_message)
+            .service(verify_wecom)
+            .service(web_channel::post_message)
+            .service(admin::scope())
+            .service(metrics::get_metrics)
+            .service(health::healthz)
+            .service(health::readyz)
     })
     .bind(&ip)?
+    .shutdown_timeout(grace.as_secs())
     .run()
     .await?;
 
+    let running = tasks.drain(grace).await;
+    if running > 0 {
+        warn!(
+            "{} model calls still running at shutdown, they are answered after the restart",
+            running
+        );
+    }
+    telemetry::shutdown();
     Ok(())
 }
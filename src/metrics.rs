@@ -0,0 +1,201 @@
+use std::{
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use actix_web::{get, web, HttpResponse};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramTimer, HistogramVec, IntCounter, IntCounterVec,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::{admin::AdminAuth, api::chat_gpt::TokenUsage, error::Result, AppState};
+
+/// WeChat gives up on a passive reply after 5 seconds and retries.
+pub const WECHAT_DEADLINE: Duration = Duration::from_secs(5);
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// Inbound messages by `account` and `msg_type`, see [`msg_type_label`].
+    pub messages: IntCounterVec,
+    /// How the dedup key resolved: `first`, `cached_reply`, `stored_reply` or `pending`.
+    pub dedup: IntCounterVec,
+    pub reply_seconds: Histogram,
+    pub deadline_exceeded: IntCounter,
+    pub llm_seconds: HistogramVec,
+    pub llm_errors: IntCounterVec,
+    /// Tokens by `account`, `model` and `kind` (`prompt` or `completion`).
+    pub llm_tokens: IntCounterVec,
+    /// Tool calls by `tool` and `result` (`ok` or `error`).
+    pub tool_calls: IntCounterVec,
+    pub db_seconds: HistogramVec,
+    /// Connections by `state`: `size` and `idle`.
+    pub db_pool: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("wechatgpt".to_string()), None).unwrap();
+        let metrics = Metrics {
+            messages: IntCounterVec::new(
+                Opts::new("messages_total", "Inbound WeChat messages"),
+                &["account", "msg_type"],
+            )
+            .unwrap(),
+            dedup: IntCounterVec::new(
+                Opts::new("dedup_total", "Messages by retry dedup outcome"),
+                &["result"],
+            )
+            .unwrap(),
+            reply_seconds: Histogram::with_opts(
+                HistogramOpts::new("reply_seconds", "Time to answer WeChat").buckets(vec![
+                    0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 10.0, 15.0, 30.0,
+                ]),
+            )
+            .unwrap(),
+            deadline_exceeded: IntCounter::new(
+                "deadline_exceeded_total",
+                "Replies slower than the 5s WeChat deadline",
+            )
+            .unwrap(),
+            llm_seconds: HistogramVec::new(
+                HistogramOpts::new("llm_seconds", "LLM request latency").buckets(vec![
+                    0.25, 0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 10.0, 20.0, 40.0, 60.0,
+                ]),
+                &["account", "provider", "model"],
+            )
+            .unwrap(),
+            llm_errors: IntCounterVec::new(
+                Opts::new("llm_errors_total", "Failed LLM requests"),
+                &["account", "provider", "model"],
+            )
+            .unwrap(),
+            llm_tokens: IntCounterVec::new(
+                Opts::new("llm_tokens_total", "Tokens used"),
+                &["account", "model", "kind"],
+            )
+            .unwrap(),
+            tool_calls: IntCounterVec::new(
+                Opts::new("tool_calls_total", "Tool calls made by the model"),
+                &["tool", "result"],
+            )
+            .unwrap(),
+            db_seconds: HistogramVec::new(
+                HistogramOpts::new("db_query_seconds", "Database query latency").buckets(vec![
+                    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+                ]),
+                &["operation"],
+            )
+            .unwrap(),
+            db_pool: IntGaugeVec::new(
+                Opts::new("db_pool_connections", "Database pool connections"),
+                &["state"],
+            )
+            .unwrap(),
+            registry,
+        };
+        let r = &metrics.registry;
+        r.register(Box::new(metrics.messages.clone())).unwrap();
+        r.register(Box::new(metrics.dedup.clone())).unwrap();
+        r.register(Box::new(metrics.reply_seconds.clone())).unwrap();
+        r.register(Box::new(metrics.deadline_exceeded.clone()))
+            .unwrap();
+        r.register(Box::new(metrics.llm_seconds.clone())).unwrap();
+        r.register(Box::new(metrics.llm_errors.clone())).unwrap();
+        r.register(Box::new(metrics.llm_tokens.clone())).unwrap();
+        r.register(Box::new(metrics.tool_calls.clone())).unwrap();
+        r.register(Box::new(metrics.db_seconds.clone())).unwrap();
+        r.register(Box::new(metrics.db_pool.clone())).unwrap();
+        metrics
+    }
+
+    pub fn record_tokens(&self, account: &str, model: &str, usage: &TokenUsage) {
+        self.llm_tokens
+            .with_label_values(&[account, model, "prompt"])
+            .inc_by(usage.prompt_tokens.max(0) as u64);
+        self.llm_tokens
+            .with_label_values(&[account, model, "completion"])
+            .inc_by(usage.completion_tokens.max(0) as u64);
+    }
+
+    /// Observes the query latency when dropped.
+    pub fn db_timer(&self, operation: &str) -> HistogramTimer {
+        self.db_seconds
+            .with_label_values(&[operation])
+            .start_timer()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        buffer
+    }
+}
+
+/// The `msg_type` label of a message: `MsgType` comes from the request, so
+/// anything but the types answered here is counted as `other`.
+pub fn msg_type_label(msg_type: &str) -> &'static str {
+    match msg_type {
+        "text" => "text",
+        "image" => "image",
+        "voice" => "voice",
+        "event" => "event",
+        _ => "other",
+    }
+}
+
+/// Observes the time taken to answer WeChat when dropped, whichever way the
+/// handler returns.
+pub struct ReplyTimer(Instant);
+
+impl ReplyTimer {
+    pub fn start() -> ReplyTimer {
+        ReplyTimer(Instant::now())
+    }
+}
+
+impl Drop for ReplyTimer {
+    fn drop(&mut self) {
+        let elapsed = self.0.elapsed();
+        METRICS.reply_seconds.observe(elapsed.as_secs_f64());
+        if elapsed > WECHAT_DEADLINE {
+            METRICS.deadline_exceeded.inc();
+        }
+    }
+}
+
+/// Behind the same token or address check as `/admin`.
+#[get("/metrics")]
+async fn get_metrics(_auth: AdminAuth, data: web::Data<AppState>) -> Result<HttpResponse> {
+    if let Some(pool) = data.store.pool_status() {
+        let gauge = &METRICS.db_pool;
+        gauge.with_label_values(&["size"]).set(pool.size as i64);
+        gauge.with_label_values(&["idle"]).set(pool.idle as i64);
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(TextEncoder::new().format_type())
+        .body(METRICS.encode()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        METRICS
+            .messages
+            .with_label_values(&["default", "text"])
+            .inc();
+        drop(METRICS.db_timer("save_turn"));
+        assert_eq!(msg_type_label("voice"), "voice");
+        assert_eq!(msg_type_label("<random>"), "other");
+        let text = String::from_utf8(METRICS.encode()).unwrap();
+        assert!(text.contains("wechatgpt_messages_total{account=\"default\",msg_type=\"text\"}"));
+        assert!(text.contains("wechatgpt_db_query_seconds_count{operation=\"save_turn\"} "));
+    }
+}
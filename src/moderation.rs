@@ -0,0 +1,171 @@
+use std::{collections::BTreeMap, fs};
+
+use aho_corasick::AhoCorasick;
+use log::warn;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::{Error, Result},
+    settings::Moderation,
+};
+
+pub const SOURCE_KEYWORD: &str = "keyword";
+pub const SOURCE_API: &str = "api";
+
+/// Why a text was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flag {
+    pub source: &'static str,
+    pub reason: String,
+}
+
+/// Screens text against the sensitive word list and, when configured, a
+/// moderation endpoint. A disabled moderator lets everything through.
+pub struct Moderator {
+    pub blocked_reply: String,
+    words: Option<AhoCorasick>,
+    api: Option<Api>,
+}
+
+struct Api {
+    url: String,
+    api_key: String,
+}
+
+#[derive(Serialize)]
+struct ModerationRequest<'a> {
+    input: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+    #[serde(default)]
+    categories: BTreeMap<String, bool>,
+}
+
+impl Moderator {
+    pub fn new(config: &Moderation, default_api_key: &str) -> Result<Moderator> {
+        let mut moderator = Moderator {
+            blocked_reply: config.blocked_reply.clone(),
+            words: None,
+            api: None,
+        };
+        if !config.enabled {
+            return Ok(moderator);
+        }
+
+        let mut words = config.words.clone();
+        if let Some(path) = config.words_path() {
+            words.extend(fs::read_to_string(path)?.lines().map(str::to_string));
+        }
+        words.retain(|word| !word.trim().is_empty());
+        if !words.is_empty() {
+            let matcher = AhoCorasick::builder()
+                .ascii_case_insensitive(true)
+                .build(words.iter().map(|word| word.trim()))
+                .map_err(|e| Error::ArgumentError(format!("sensitive words: {}", e)))?;
+            moderator.words = Some(matcher);
+        }
+
+        moderator.api = config.api.as_ref().map(|api| Api {
+            url: api.url.clone(),
+            api_key: api
+                .api_key
+                .clone()
+                .unwrap_or_else(|| default_api_key.to_string()),
+        });
+        Ok(moderator)
+    }
+
+    pub async fn check(&self, client: &Client, text: &str) -> Result<Option<Flag>> {
+        if let Some(words) = &self.words {
+            if let Some(found) = words.find(text) {
+                return Ok(Some(Flag {
+                    source: SOURCE_KEYWORD,
+                    reason: text[found.range()].to_string(),
+                }));
+            }
+        }
+        if let Some(api) = &self.api {
+            // an outage of the endpoint must not take the account down, the word list still applies
+            match api.check(client, text).await {
+                Ok(flag) => return Ok(flag),
+                Err(e) => warn!("moderation api failed: {}", e),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Api {
+    async fn check(&self, client: &Client, text: &str) -> Result<Option<Flag>> {
+        let response: ModerationResponse = client
+            .post(&self.url)
+            .bearer_auth(&self.api_key)
+            .json(&ModerationRequest { input: text })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response.results.into_iter().find(|r| r.flagged).map(|r| {
+            let categories: Vec<String> = r
+                .categories
+                .into_iter()
+                .filter(|(_, flagged)| *flagged)
+                .map(|(category, _)| category)
+                .collect();
+            Flag {
+                source: SOURCE_API,
+                reason: categories.join(","),
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_word_list() {
+        let client = Client::new();
+        let mut config = Moderation {
+            words: vec!["敏感词".to_string(), "Forbidden".to_string()],
+            ..Moderation::default()
+        };
+        let disabled = Moderator::new(&config, "").unwrap();
+        assert_eq!(disabled.check(&client, "这里有敏感词").await.unwrap(), None);
+
+        config.enabled = true;
+        let moderator = Moderator::new(&config, "").unwrap();
+        assert_eq!(
+            moderator.check(&client, "这里有敏感词吗").await.unwrap(),
+            Some(Flag {
+                source: SOURCE_KEYWORD,
+                reason: "敏感词".to_string()
+            })
+        );
+        assert!(moderator
+            .check(&client, "a FORBIDDEN topic")
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(moderator.check(&client, "你好").await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_moderation_response_from_json() {
+        let json_str = r#"{"id":"modr-XXXXX","model":"text-moderation-004","results":[{"flagged":true,"categories":{"sexual":false,"hate":true,"violence":true},"category_scores":{"sexual":0.01,"hate":0.9,"violence":0.8}}]}"#;
+        let response = serde_json::from_str::<ModerationResponse>(json_str).unwrap();
+        assert!(response.results[0].flagged);
+        assert_eq!(response.results[0].categories.len(), 3);
+    }
+}
@@ -0,0 +1,259 @@
+use serde::Serialize;
+
+use crate::{
+    database::{
+        minute_of, now_millis, ConversationStore, ErasedRows, TurnQuery, TurnRecord,
+        UserPreferences, WechatUser,
+    },
+    engine,
+    error::Result,
+    profile, AppState,
+};
+
+/// Turns fetched per query while exporting.
+const EXPORT_PAGE: i64 = 500;
+
+/// Everything kept about a user, for `/export` and the admin API.
+#[derive(Debug, Serialize)]
+pub struct UserExport {
+    pub subscription_id: String,
+    pub user_id: String,
+    pub exported_time: i64,
+    pub profile: Option<WechatUser>,
+    pub preferences: UserPreferences,
+    pub tier: Option<String>,
+    /// Oldest first.
+    pub turns: Vec<TurnRecord>,
+}
+
+pub async fn export(
+    store: &dyn ConversationStore,
+    subscription_id: &str,
+    user_id: &str,
+) -> Result<UserExport> {
+    let mut turns = vec![];
+    loop {
+        let page = store
+            .search_turns(&TurnQuery {
+                subscription_id: Some(subscription_id.to_string()),
+                user_id: Some(user_id.to_string()),
+                q: None,
+                limit: EXPORT_PAGE,
+                offset: turns.len() as i64,
+            })
+            .await?;
+        let last = (page.len() as i64) < EXPORT_PAGE;
+        turns.extend(page);
+        if last {
+            break;
+        }
+    }
+    turns.reverse();
+    Ok(UserExport {
+        subscription_id: subscription_id.to_string(),
+        user_id: user_id.to_string(),
+        exported_time: now_millis(),
+        profile: store.get_wechat_user(subscription_id, user_id).await?,
+        preferences: store.get_user_preferences(subscription_id, user_id).await?,
+        tier: store.get_user_tier(subscription_id, user_id).await?,
+        turns,
+    })
+}
+
+/// `export` for people to read, times at `utc_offset_hours`.
+pub fn to_markdown(export: &UserExport, utc_offset_hours: i32) -> String {
+    let mut markdown = format!(
+        "# 对话记录\n\n- 账号: {}\n- 用户: {}\n- 导出时间: {}\n",
+        export.subscription_id,
+        export.user_id,
+        minute_of(export.exported_time, utc_offset_hours)
+    );
+    if let Some(profile) = &export.profile {
+        markdown.push_str(&format!(
+            "- 昵称: {}\n- 语言: {}\n",
+            profile.nickname, profile.language
+        ));
+    }
+    if let Some(tier) = &export.tier {
+        markdown.push_str(&format!("- 等级: {}\n", tier));
+    }
+    let preferences = &export.preferences;
+    markdown.push_str(&format!(
+        "- 偏好: 回复 {}, 详略 {}, 保存记录 {}\n",
+        preferences.reply_mode.as_str(),
+        preferences.verbosity.as_str(),
+        if preferences.history_opt_out {
+            "否"
+        } else {
+            "是"
+        }
+    ));
+    for turn in &export.turns {
+        markdown.push_str(&format!(
+            "\n## {}\n\n**我**: {}\n\n**回复**: {}\n",
+            minute_of(turn.created_time, utc_offset_hours),
+            turn.user_message,
+            turn.reply_message
+        ));
+    }
+    markdown
+}
+
+/// `text` cut at line breaks into parts of at most `max_chars` characters,
+/// a longer line is cut where it has to be.
+pub fn split(text: &str, max_chars: usize) -> Vec<String> {
+    let mut parts = vec![];
+    let mut current = String::new();
+    let mut length = 0;
+    for line in text.lines() {
+        let chars: Vec<char> = line.chars().collect();
+        let pieces: Vec<&[char]> = if chars.is_empty() {
+            // keeps blank lines between paragraphs
+            vec![&[]]
+        } else {
+            chars.chunks(max_chars.max(1)).collect()
+        };
+        for piece in pieces {
+            // the line break joining it to `current` counts as well
+            if length > 0 && length + 1 + piece.len() > max_chars {
+                parts.push(std::mem::take(&mut current));
+                length = 0;
+            }
+            if length > 0 {
+                current.push('\n');
+                length += 1;
+            }
+            current.extend(piece);
+            length += piece.len();
+        }
+    }
+    if !current.trim().is_empty() {
+        parts.push(current);
+    }
+    parts
+}
+
+/// Erases the user in the store and drops what the cache holds about them:
+/// their profile and the replies still kept for retries. Their tier and
+/// today's counters stay, so that erasing does not lift a limit.
+pub async fn forget(
+    app_state: &AppState,
+    subscription_id: &str,
+    user_id: &str,
+) -> Result<ErasedRows> {
+    let recent = recent_msg_ids(app_state, subscription_id, user_id).await?;
+    let erased = app_state
+        .store
+        .forget_user(subscription_id, user_id)
+        .await?;
+    let cache = &app_state.cache;
+    for msg_id in recent {
+        cache
+            .delete(&engine::reply_key(subscription_id, user_id, msg_id))
+            .await?;
+    }
+    cache
+        .delete(&profile::user_key(subscription_id, user_id))
+        .await?;
+    Ok(erased)
+}
+
+/// The messages of the user whose replies may still be cached.
+async fn recent_msg_ids(
+    app_state: &AppState,
+    subscription_id: &str,
+    user_id: &str,
+) -> Result<Vec<i64>> {
+    let since = now_millis() - app_state.dedup_ttl.as_millis() as i64;
+    let mut msg_ids = vec![];
+    loop {
+        let page = app_state
+            .store
+            .search_turns(&TurnQuery {
+                subscription_id: Some(subscription_id.to_string()),
+                user_id: Some(user_id.to_string()),
+                q: None,
+                limit: EXPORT_PAGE,
+                offset: msg_ids.len() as i64,
+            })
+            .await?;
+        let last = (page.len() as i64) < EXPORT_PAGE;
+        let recent: Vec<i64> = page
+            .iter()
+            .take_while(|turn| turn.created_time >= since)
+            .map(|turn| turn.msg_id)
+            .collect();
+        // newest first, so the rest are older still
+        let done = last || recent.len() < page.len();
+        msg_ids.extend(recent);
+        if done {
+            return Ok(msg_ids);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        api::chat_gpt::TokenUsage,
+        database::{Turn, TurnStatus},
+    };
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split("ab\ncd\nef", 5), ["ab\ncd", "ef"]);
+        assert_eq!(split("abcdefg", 3), ["abc", "def", "g"]);
+        assert_eq!(split("一二\n\n三四", 3), ["一二\n", "三四"]);
+        assert!(split("", 10).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_export_and_forget() {
+        let state = AppState::for_tests();
+        state
+            .store
+            .set_user_tier("sub", "user", "vip")
+            .await
+            .unwrap();
+        state
+            .store
+            .save_turn(&Turn {
+                msg_id: 7,
+                user_id: "user".to_string(),
+                subscription_id: "sub".to_string(),
+                session_id: String::new(),
+                model: "gpt-3.5-turbo".to_string(),
+                user_message: "hi".to_string(),
+                reply_message: "hello".to_string(),
+                status: TurnStatus::Ok,
+                error: None,
+                elapsed: Duration::from_millis(10),
+                usage: TokenUsage::default(),
+                cost: 0.0,
+            })
+            .await
+            .unwrap();
+        let reply = engine::reply_key("sub", "user", 7);
+        let cache = &state.cache;
+        cache.set(&reply, "hello", MINUTE).await.unwrap();
+        let rate = format!("RATE_sub_user_{}", now_millis() / 60_000);
+        cache.incr(&rate, 1, MINUTE).await.unwrap();
+        let exported = export(state.store.as_ref(), "sub", "user").await.unwrap();
+        assert_eq!(exported.tier.as_deref(), Some("vip"));
+        assert!(to_markdown(&exported, 8).contains("- 等级: vip"));
+
+        let erased = forget(&state, "sub", "user").await.unwrap();
+        assert_eq!(erased["dialogue_turn"], 1);
+        let exported = export(state.store.as_ref(), "sub", "user").await.unwrap();
+        assert!(exported.turns.is_empty());
+        assert_eq!(cache.get(&reply).await.unwrap(), None);
+        // erasing neither resets the limits nor lifts the tier
+        assert_eq!(cache.get(&rate).await.unwrap().as_deref(), Some("1"));
+        assert_eq!(exported.tier.as_deref(), Some("vip"));
+    }
+}
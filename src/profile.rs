@@ -0,0 +1,138 @@
+use std::time::Duration;
+
+use crate::{
+    api::wechat,
+    database::{now_millis, WechatUser},
+    error::Result,
+    settings::WechatConfig,
+    AppState,
+};
+
+/// The follower's profile: from the cache, from the database while it is
+/// younger than `user_info.ttl_secs`, or fetched from WeChat and stored.
+/// A stored profile of any age stands in when WeChat cannot be reached.
+pub async fn lookup(
+    app_state: &AppState,
+    config: &WechatConfig,
+    subscription_id: &str,
+    user_id: &str,
+) -> Result<WechatUser> {
+    let ttl = Duration::from_secs(app_state.live().user_info.ttl_secs);
+    let key = user_key(subscription_id, user_id);
+    if let Some(json) = app_state.cache.get(&key).await? {
+        return Ok(serde_json::from_str(&json)?);
+    }
+
+    let stored = app_state
+        .store
+        .get_wechat_user(subscription_id, user_id)
+        .await?;
+    let user = match stored {
+        Some(user) if now_millis() - user.updated_time < ttl.as_millis() as i64 => user,
+        stored => match fetch(app_state, config, subscription_id, user_id).await {
+            Ok(user) => {
+                app_state.store.save_wechat_user(&user).await?;
+                user
+            }
+            Err(e) => return stored.ok_or(e),
+        },
+    };
+    app_state
+        .cache
+        .set(&key, &serde_json::to_string(&user)?, ttl)
+        .await?;
+    Ok(user)
+}
+
+async fn fetch(
+    app_state: &AppState,
+    config: &WechatConfig,
+    subscription_id: &str,
+    user_id: &str,
+) -> Result<WechatUser> {
+    let client = &app_state.client;
+    let access_token = wechat::get_access_token(client, app_state.cache.as_ref(), config).await?;
+    let info = wechat::get_user_info(client, &access_token, user_id).await?;
+    Ok(WechatUser {
+        subscription_id: subscription_id.to_string(),
+        user_id: user_id.to_string(),
+        nickname: info.nickname,
+        language: info.language,
+        subscribe_time: info.subscribe_time,
+        updated_time: now_millis(),
+    })
+}
+
+/// `persona` with `{nickname}` and `{language}` filled in for `user`.
+pub fn render_persona(persona: &str, user: Option<&WechatUser>, nickname_fallback: &str) -> String {
+    let nickname = user
+        .map(|user| user.nickname.as_str())
+        .filter(|nickname| !nickname.is_empty())
+        .unwrap_or(nickname_fallback);
+    let language = user.map_or("", |user| user.language.as_str());
+    persona
+        .replace("{nickname}", nickname)
+        .replace("{language}", language)
+}
+
+/// Where a user's profile is cached.
+pub fn user_key(subscription_id: &str, user_id: &str) -> String {
+    format!("WECHAT_USER_{}_{}", subscription_id, user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_persona() {
+        let mut user = WechatUser {
+            subscription_id: "sub".to_string(),
+            user_id: "user".to_string(),
+            nickname: "小明".to_string(),
+            language: "zh_CN".to_string(),
+            subscribe_time: 0,
+            updated_time: 0,
+        };
+        let persona = "你在和{nickname}聊天，对方的语言是{language}。";
+        assert_eq!(
+            render_persona(persona, Some(&user), "朋友"),
+            "你在和小明聊天，对方的语言是zh_CN。"
+        );
+        user.nickname.clear();
+        assert_eq!(
+            render_persona("你好，{nickname}", Some(&user), "朋友"),
+            "你好，朋友"
+        );
+        assert_eq!(
+            render_persona("你好，{nickname}", None, "朋友"),
+            "你好，朋友"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lookup_uses_fresh_rows() {
+        let state = AppState::for_tests();
+        let user = WechatUser {
+            subscription_id: "sub".to_string(),
+            user_id: "user".to_string(),
+            nickname: "小明".to_string(),
+            language: "zh_CN".to_string(),
+            subscribe_time: 1700000000,
+            updated_time: now_millis(),
+        };
+        state.store.save_wechat_user(&user).await.unwrap();
+        let config = state.live().wechat_config.clone();
+        // no call to WeChat, which the test config could not make
+        assert_eq!(lookup(&state, &config, "sub", "user").await.unwrap(), user);
+        state
+            .store
+            .save_wechat_user(&WechatUser {
+                nickname: "changed".to_string(),
+                ..user.clone()
+            })
+            .await
+            .unwrap();
+        assert_eq!(lookup(&state, &config, "sub", "user").await.unwrap(), user);
+    }
+}
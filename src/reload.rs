@@ -0,0 +1,330 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwap;
+use log::{info, warn};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    time::interval,
+};
+
+use crate::{
+    error::Result,
+    moderation::Moderator,
+    settings::{
+        Access, Account, Admin, CacheConfig, ChatGptConfig, Database, Health, Images, Knowledge,
+        Limits, Log, Pricing, Server, Settings, Speech, UserInfo, WebChannel, WechatConfig,
+        WecomConfig, DEFAULT_ACCOUNT, WEB_ACCOUNT, WECOM_ACCOUNT,
+    },
+    tools::ToolRegistry,
+};
+
+/// The settings that take effect without a restart. Handlers load a
+/// snapshot once per message, so a reload never mixes old and new settings
+/// within one answer.
+#[derive(Clone)]
+pub struct Live {
+    pub wechat_config: WechatConfig,
+    pub chat_gpt_config: ChatGptConfig,
+    pub pricing: Pricing,
+    pub limits: Limits,
+    pub admin: Admin,
+    pub access: Access,
+    pub health: Health,
+    pub moderator: Arc<Moderator>,
+    pub accounts: Vec<Account>,
+    pub wecom: Option<WecomConfig>,
+    pub web: Option<WebChannel>,
+    pub knowledge: Knowledge,
+    pub tools: Arc<ToolRegistry>,
+    pub images: Images,
+    pub speech: Speech,
+    pub user_info: UserInfo,
+    pub personas: BTreeMap<String, String>,
+}
+
+/// What one official account runs with, falling back to the top-level
+/// settings for what it does not set itself.
+pub struct AccountSettings<'a> {
+    pub name: &'a str,
+    pub channel: Channel<'a>,
+    pub chat_gpt_config: &'a ChatGptConfig,
+    pub persona: Option<&'a str>,
+    pub limits: &'a Limits,
+    pub knowledge_base: Option<&'a str>,
+    /// The users of this account who may run admin commands.
+    pub admins: &'a [String],
+}
+
+/// Where an account receives its messages and sends its answers.
+pub enum Channel<'a> {
+    Wechat(&'a WechatConfig),
+    Wecom(&'a WecomConfig),
+    Web(&'a WebChannel),
+}
+
+impl Live {
+    pub fn new(s: &Settings) -> Result<Live> {
+        Ok(Live {
+            moderator: Arc::new(Moderator::new(&s.moderation, &s.chat_gpt_config.api)?),
+            wechat_config: s.wechat_config.clone(),
+            chat_gpt_config: s.chat_gpt_config.clone(),
+            pricing: s.pricing.clone(),
+            limits: s.limits.clone(),
+            admin: s.admin.clone(),
+            access: s.access.clone(),
+            health: s.health.clone(),
+            accounts: s.accounts.clone(),
+            wecom: s.wecom.clone(),
+            web: s.web.clone(),
+            knowledge: s.knowledge.clone(),
+            tools: Arc::new(ToolRegistry::new(&s.tools)?),
+            images: s.images.clone(),
+            speech: s.speech.clone(),
+            user_info: s.user_info.clone(),
+            personas: s.personas.clone(),
+        })
+    }
+
+    /// The account named `name`, [`DEFAULT_ACCOUNT`] being the one at the
+    /// top level, [`WECOM_ACCOUNT`] the WeCom application and [`WEB_ACCOUNT`]
+    /// the JSON channel.
+    pub fn account(&self, name: &str) -> Option<AccountSettings<'_>> {
+        if name == DEFAULT_ACCOUNT {
+            return Some(AccountSettings {
+                name: DEFAULT_ACCOUNT,
+                channel: Channel::Wechat(&self.wechat_config),
+                chat_gpt_config: &self.chat_gpt_config,
+                persona: None,
+                limits: &self.limits,
+                knowledge_base: self.knowledge.base.as_deref(),
+                admins: &self.admin.openids,
+            });
+        }
+        if name == WECOM_ACCOUNT {
+            let wecom = self.wecom.as_ref()?;
+            return Some(AccountSettings {
+                name: WECOM_ACCOUNT,
+                channel: Channel::Wecom(wecom),
+                chat_gpt_config: &self.chat_gpt_config,
+                persona: wecom.persona.as_deref(),
+                limits: &self.limits,
+                knowledge_base: wecom
+                    .knowledge_base
+                    .as_deref()
+                    .or(self.knowledge.base.as_deref()),
+                admins: &wecom.admins,
+            });
+        }
+        if name == WEB_ACCOUNT {
+            let web = self.web.as_ref()?;
+            return Some(AccountSettings {
+                name: WEB_ACCOUNT,
+                channel: Channel::Web(web),
+                chat_gpt_config: &self.chat_gpt_config,
+                persona: web.persona.as_deref(),
+                limits: &self.limits,
+                knowledge_base: web
+                    .knowledge_base
+                    .as_deref()
+                    .or(self.knowledge.base.as_deref()),
+                // anyone can post any user_id, nobody is an admin here
+                admins: &[],
+            });
+        }
+        let account = self.accounts.iter().find(|account| account.name == name)?;
+        Some(AccountSettings {
+            name: &account.name,
+            channel: Channel::Wechat(&account.wechat_config),
+            chat_gpt_config: account
+                .chat_gpt_config
+                .as_ref()
+                .unwrap_or(&self.chat_gpt_config),
+            persona: account.persona.as_deref(),
+            limits: account.limits.as_ref().unwrap_or(&self.limits),
+            knowledge_base: account
+                .knowledge_base
+                .as_deref()
+                .or(self.knowledge.base.as_deref()),
+            admins: &account.admins,
+        })
+    }
+}
+
+/// Settings that are only read at startup.
+struct Fixed {
+    log: Log,
+    server: Server,
+    database: Database,
+    cache: CacheConfig,
+    utc_offset_hours: i32,
+}
+
+impl Fixed {
+    fn new(s: &Settings) -> Fixed {
+        Fixed {
+            log: s.log.clone(),
+            server: s.server.clone(),
+            database: s.database.clone(),
+            cache: s.cache.clone(),
+            utc_offset_hours: s.pricing.utc_offset_hours,
+        }
+    }
+
+    /// The sections that differ in `s`.
+    fn changed_in(&self, s: &Settings) -> Vec<&'static str> {
+        let mut changed = vec![];
+        if self.log != s.log {
+            changed.push("log");
+        }
+        if self.server != s.server {
+            changed.push("server");
+        }
+        if self.database != s.database {
+            changed.push("database");
+        }
+        if self.cache != s.cache {
+            changed.push("cache");
+        }
+        if self.utc_offset_hours != s.pricing.utc_offset_hours {
+            changed.push("pricing.utc_offset_hours");
+        }
+        changed
+    }
+}
+
+/// Re-reads the settings when the config files change or on SIGHUP and
+/// swaps the reloadable part into [`crate::AppState`].
+pub struct Reloader {
+    path: Option<String>,
+    fixed: Fixed,
+    live: Arc<ArcSwap<Live>>,
+}
+
+impl Reloader {
+    pub fn new(path: Option<String>, s: &Settings, live: Arc<ArcSwap<Live>>) -> Reloader {
+        Reloader {
+            path,
+            fixed: Fixed::new(s),
+            live,
+        }
+    }
+
+    /// Keeps the current settings when the new ones are invalid. Sections
+    /// that need a restart are left as they are and logged.
+    pub fn reload(&self) {
+        let s = match Settings::load(self.path.as_deref()) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("settings not reloaded, {}", e);
+                return;
+            }
+        };
+        let mut live = match Live::new(&s) {
+            Ok(live) => live,
+            Err(e) => {
+                warn!("settings not reloaded, {}", e);
+                return;
+            }
+        };
+        let changed = self.fixed.changed_in(&s);
+        if !changed.is_empty() {
+            warn!(
+                "changes to {} need a restart and were not applied",
+                changed.join(", ")
+            );
+            live.pricing.utc_offset_hours = self.fixed.utc_offset_hours;
+        }
+        self.live.store(Arc::new(live));
+        info!("settings reloaded");
+    }
+
+    pub async fn watch(self, period: Duration) {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                warn!("cannot listen for SIGHUP, settings are not reloaded: {}", e);
+                return;
+            }
+        };
+        let mut interval = interval(period.max(Duration::from_secs(1)));
+        let mut modified = self.modified();
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => info!("SIGHUP, reloading settings"),
+                _ = interval.tick(), if !period.is_zero() => {
+                    let now = self.modified();
+                    if now == modified {
+                        continue;
+                    }
+                    modified = now;
+                    info!("settings file changed, reloading");
+                }
+            }
+            self.reload();
+        }
+    }
+
+    /// When the config files were last written.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        Settings::paths(self.path.as_deref())
+            .iter()
+            .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restart_only_changes() {
+        let s = Settings::for_tests();
+        let fixed = Fixed::new(&s);
+        assert!(fixed.changed_in(&s).is_empty());
+
+        let mut changed = Settings::for_tests();
+        changed.server.port += 1;
+        changed.limits.default_tier = "vip".to_string();
+        assert_eq!(fixed.changed_in(&changed), vec!["server"]);
+    }
+
+    #[test]
+    fn test_account_falls_back_to_top_level() {
+        let mut s = Settings::for_tests();
+        s.admin.openids = vec!["owner".to_string()];
+        s.accounts = vec![Account {
+            name: "shop".to_string(),
+            wechat_config: WechatConfig {
+                app_id: "shop".to_string(),
+                app_secret: String::new(),
+                token: "shop".to_string(),
+                encoding_aes_key: None,
+            },
+            chat_gpt_config: None,
+            persona: Some("sell things".to_string()),
+            limits: None,
+            knowledge_base: Some("products".to_string()),
+            admins: vec!["clerk".to_string()],
+        }];
+        let live = Live::new(&s).unwrap();
+
+        let account = live.account("shop").unwrap();
+        assert!(matches!(account.channel, Channel::Wechat(c) if c.token == "shop"));
+        assert_eq!(account.chat_gpt_config.model, s.chat_gpt_config.model);
+        assert_eq!(account.persona, Some("sell things"));
+        assert_eq!(account.knowledge_base, Some("products"));
+        // openids are per account, so are admins
+        assert_eq!(account.admins, ["clerk"]);
+        let default = live.account(DEFAULT_ACCOUNT).unwrap();
+        assert!(matches!(default.channel, Channel::Wechat(c) if c.token == s.wechat_config.token));
+        assert_eq!(default.admins, ["owner"]);
+        assert!(live.account("unknown").is_none());
+        assert!(live.account(WECOM_ACCOUNT).is_none());
+    }
+}
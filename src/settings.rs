@@ -1,61 +1,158 @@
-use config::{Config, ConfigError};
-use log::LevelFilter;
-use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{Display, Formatter, Result as FmtResult},
+    fs,
+    net::IpAddr,
+    time::Duration,
+};
 
-#[derive(Debug, Deserialize)]
+use config::{builder::DefaultState, Config, ConfigBuilder, ConfigError, Environment};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::api::chat_gpt::TokenUsage;
+
+#[derive(Debug)]
 pub struct Settings {
     pub log: Log,
     pub server: Server,
     pub wechat_config: WechatConfig,
     pub database: Database,
     pub chat_gpt_config: ChatGptConfig,
+    pub cache: CacheConfig,
+    pub pricing: Pricing,
+    pub limits: Limits,
+    pub admin: Admin,
+    pub moderation: Moderation,
+    pub access: Access,
+    pub health: Health,
+    pub accounts: Vec<Account>,
+    pub wecom: Option<WecomConfig>,
+    pub web: Option<WebChannel>,
+    pub knowledge: Knowledge,
+    pub tools: Tools,
+    pub images: Images,
+    pub speech: Speech,
+    pub user_info: UserInfo,
+    /// System prompts by name, which followers may pick from with
+    /// `/pref persona <name>`.
+    pub personas: BTreeMap<String, String>,
+}
+
+/// Everything wrong with the configuration, reported together so that it
+/// can be fixed in one go.
+#[derive(Debug)]
+pub struct InvalidSettings(pub Vec<String>);
+
+impl Display for InvalidSettings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "invalid configuration:")?;
+        for problem in &self.0 {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<ConfigError> for InvalidSettings {
+    fn from(e: ConfigError) -> Self {
+        InvalidSettings(vec![e.to_string()])
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct Log {
+    /// A level such as `info`, or filter directives like `info,sqlx=warn`.
+    /// `RUST_LOG` takes precedence.
     pub level: String,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Keep what followers and the model say out of the logs.
+    #[serde(default = "default_redact_content")]
+    pub redact_content: bool,
+    /// An OTLP/gRPC collector such as `http://localhost:4317`, needs the `otlp` feature.
+    pub otlp_endpoint: Option<String>,
+    /// Reported to the OTLP collector.
+    #[serde(default = "default_service_name")]
+    #[cfg_attr(not(feature = "otlp"), allow(dead_code))]
+    pub service_name: String,
 }
 
-impl Log {
-    // Set the maximum log level based on the configuration file.
-    pub fn get_level_filter(&self) -> LevelFilter {
-        match self.level.as_str() {
-            "error" => LevelFilter::Error,
-            "warn" => LevelFilter::Warn,
-            "info" => LevelFilter::Info,
-            "debug" => LevelFilter::Debug,
-            "trace" => LevelFilter::Trace,
-            _ => LevelFilter::Info,
-        }
-    }
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Json,
+    Text,
 }
 
-#[derive(Debug, Deserialize)]
+fn default_redact_content() -> bool {
+    true
+}
+
+fn default_service_name() -> String {
+    "we_chat_gpt".to_string()
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct Server {
     pub port: u32,
     pub ip: String,
+    /// How long a stopping server waits for in-flight requests, and then
+    /// again for model calls whose request is gone, before giving up.
+    #[serde(default = "default_shutdown_grace_secs")]
+    pub shutdown_grace_secs: u64,
+    /// How often the config files are checked for changes to reload, 0 to
+    /// reload on SIGHUP only.
+    #[serde(default = "default_config_poll_secs")]
+    pub config_poll_secs: u64,
 }
 
 impl Server {
     pub fn get_ip(&self) -> String {
         format!("{}:{}", self.ip, self.port)
     }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_secs)
+    }
+
+    pub fn config_poll(&self) -> Duration {
+        Duration::from_secs(self.config_poll_secs)
+    }
+}
+
+fn default_shutdown_grace_secs() -> u64 {
+    30
+}
+
+fn default_config_poll_secs() -> u64 {
+    5
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct Database {
-    // pub url: String,
+    /// mysql://, postgres://, sqlite:// or memory://, replaces the MySQL fields below
+    pub url: Option<String>,
+    #[serde(default)]
     pub host: String,
+    #[serde(default)]
     pub username: String,
+    #[serde(default)]
     pub password: String,
+    #[serde(default)]
     pub database: String,
     pub max_connections: u32,
-    pub ssl_ca_name: String,
+    pub ssl_ca_name: Option<String>,
+    /// Apply pending schema migrations at startup, otherwise run `the-world migrate`.
+    #[serde(default = "default_run_migrations")]
+    pub run_migrations: bool,
 }
 
 impl Database {
-    pub fn ssl_ca_path(&self) -> String {
-        format!("{}{}", CURRENT_DIR, self.ssl_ca_name)
+    pub fn ssl_ca_path(&self) -> Option<String> {
+        self.ssl_ca_name
+            .as_ref()
+            .map(|name| format!("{}{}", CURRENT_DIR, name))
     }
 }
 #[derive(Debug, Deserialize, Clone)]
@@ -63,6 +160,83 @@ pub struct WechatConfig {
     pub app_id: String,
     pub app_secret: String,
     pub token: String,
+    /// The EncodingAESKey of an account in safe mode, whose messages come
+    /// encrypted and are answered encrypted.
+    #[serde(default)]
+    pub encoding_aes_key: Option<String>,
+}
+
+/// Another official account served by the same process at `/wx/{name}`.
+/// The account configured at the top level stays at `/`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Account {
+    pub name: String,
+    pub wechat_config: WechatConfig,
+    /// Defaults to the top-level `chat_gpt_config`.
+    pub chat_gpt_config: Option<ChatGptConfig>,
+    /// The system prompt unless one is set through the admin API.
+    pub persona: Option<String>,
+    /// Defaults to the top-level `limits`.
+    pub limits: Option<Limits>,
+    /// Defaults to `knowledge.base`.
+    pub knowledge_base: Option<String>,
+    /// Followers of this account allowed to run admin commands. Openids are
+    /// only unique within one account, so `[admin].openids` do not apply.
+    #[serde(default)]
+    pub admins: Vec<String>,
+}
+
+/// The name metrics and logs use for the account configured at the top level.
+pub const DEFAULT_ACCOUNT: &str = "default";
+/// The name metrics and logs use for the WeCom application.
+pub const WECOM_ACCOUNT: &str = "wecom";
+/// The name of the JSON channel, which also stands in for the official
+/// account in storage and in the admin API.
+pub const WEB_ACCOUNT: &str = "web";
+/// Names `[[accounts]]` cannot take.
+const RESERVED_ACCOUNTS: &[&str] = &[DEFAULT_ACCOUNT, WECOM_ACCOUNT, WEB_ACCOUNT];
+
+/// A WeCom (企业微信) self-built application answering members at
+/// `/wecom`. It shares `chat_gpt_config` and `limits` with the top level.
+#[derive(Debug, Deserialize, Clone)]
+pub struct WecomConfig {
+    pub corp_id: String,
+    pub agent_id: i64,
+    /// The application secret, needed to send the answers.
+    pub secret: String,
+    pub token: String,
+    /// The 43 character EncodingAESKey of the callback settings.
+    pub encoding_aes_key: String,
+    /// The system prompt unless one is set through the admin API.
+    pub persona: Option<String>,
+    /// Defaults to `knowledge.base`.
+    pub knowledge_base: Option<String>,
+    /// Members allowed to run admin commands, by WeCom user id.
+    #[serde(default)]
+    pub admins: Vec<String>,
+}
+
+/// The JSON channel at `/web/messages`, for a website widget or for
+/// integration tests. It shares `chat_gpt_config` and `limits` with the top
+/// level.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct WebChannel {
+    /// Accepted as `Authorization: Bearer <token>`, anybody may post without one.
+    pub token: Option<String>,
+    /// The system prompt unless one is set through the admin API.
+    pub persona: Option<String>,
+    /// Defaults to `knowledge.base`.
+    pub knowledge_base: Option<String>,
+}
+
+impl WebChannel {
+    pub fn allows(&self, bearer: Option<&str>) -> bool {
+        match (self.token.as_deref(), bearer) {
+            (None, _) => true,
+            (Some(token), Some(bearer)) => constant_time_eq(token.as_bytes(), bearer.as_bytes()),
+            (Some(_), None) => false,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -71,17 +245,1053 @@ pub struct ChatGptConfig {
     pub model: String,
 }
 
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackend {
+    #[default]
+    Memory,
+    Redis,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct CacheConfig {
+    #[serde(default)]
+    pub backend: CacheBackend,
+    pub redis_url: Option<String>,
+    #[serde(default = "default_cache_key_prefix")]
+    pub key_prefix: String,
+    #[serde(default = "default_cache_max_entries")]
+    pub max_entries: usize,
+    #[serde(default = "default_cache_ttl_secs")]
+    pub ttl_secs: u64,
+    #[serde(default = "default_cache_cleanup_secs")]
+    pub cleanup_interval_secs: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            backend: CacheBackend::default(),
+            redis_url: None,
+            key_prefix: default_cache_key_prefix(),
+            max_entries: default_cache_max_entries(),
+            ttl_secs: default_cache_ttl_secs(),
+            cleanup_interval_secs: default_cache_cleanup_secs(),
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+
+    pub fn cleanup_interval(&self) -> Duration {
+        Duration::from_secs(self.cleanup_interval_secs)
+    }
+
+    pub fn redis_url(&self) -> &str {
+        self.redis_url.as_deref().unwrap_or(DEFAULT_REDIS_URL)
+    }
+}
+
+fn default_run_migrations() -> bool {
+    true
+}
+
+fn default_cache_key_prefix() -> String {
+    "we_chat_gpt:".to_string()
+}
+
+fn default_cache_max_entries() -> usize {
+    10_000
+}
+
+fn default_cache_ttl_secs() -> u64 {
+    60
+}
+
+fn default_cache_cleanup_secs() -> u64 {
+    60
+}
+
+/// Per-follower and per-account limits. A limit of 0 means unlimited.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Limits {
+    /// Sent when a follower or the account goes over `messages_per_minute`.
+    #[serde(default = "default_rate_limited_reply")]
+    pub rate_limited_reply: String,
+    /// Sent when a follower or the account has used up `tokens_per_day`.
+    #[serde(default = "default_quota_exceeded_reply")]
+    pub quota_exceeded_reply: String,
+    /// Followers without a granted tier get this one.
+    #[serde(default = "default_tier_name")]
+    pub default_tier: String,
+    #[serde(default)]
+    pub tiers: HashMap<String, Limit>,
+    /// Shared by all followers of one official account.
+    #[serde(default)]
+    pub subscription: Limit,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+pub struct Limit {
+    #[serde(default)]
+    pub messages_per_minute: i64,
+    #[serde(default)]
+    pub tokens_per_day: i64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            rate_limited_reply: default_rate_limited_reply(),
+            quota_exceeded_reply: default_quota_exceeded_reply(),
+            default_tier: default_tier_name(),
+            tiers: HashMap::new(),
+            subscription: Limit::default(),
+        }
+    }
+}
+
+impl Limits {
+    pub fn tier(&self, name: &str) -> Limit {
+        self.tiers
+            .get(name)
+            .or_else(|| self.tiers.get(&self.default_tier))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+fn default_rate_limited_reply() -> String {
+    "消息发得太快了，请稍后再试。".to_string()
+}
+
+fn default_quota_exceeded_reply() -> String {
+    "今天的额度已经用完了，明天再来吧。".to_string()
+}
+
+fn default_tier_name() -> String {
+    "default".to_string()
+}
+
+/// Who may administer the bot: followers allowed to run commands such as
+/// `/tier`, and callers of the `/admin` HTTP API.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Admin {
+    /// Followers of the account configured at the top level; the other
+    /// accounts and WeCom list their own `admins`.
+    #[serde(default)]
+    pub openids: Vec<String>,
+    /// Accepted as `Authorization: Bearer <token>`.
+    pub token: Option<String>,
+    /// Peers let in without a token.
+    #[serde(default)]
+    pub allowed_ips: Vec<IpAddr>,
+}
+
+impl Admin {
+    /// Whether a request to the HTTP API may go through. Nothing is let in
+    /// until a token or an address is configured.
+    pub fn allows(&self, bearer: Option<&str>, peer: Option<IpAddr>) -> bool {
+        let token_matches = match (self.token.as_deref(), bearer) {
+            (Some(token), Some(bearer)) if !token.is_empty() => {
+                constant_time_eq(token.as_bytes(), bearer.as_bytes())
+            }
+            _ => false,
+        };
+        token_matches || peer.is_some_and(|ip| self.allowed_ips.contains(&ip))
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Who may talk to the bot. Admins always may.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Access {
+    /// For accounts that were not switched with `/mode` or the admin API.
+    #[serde(default)]
+    pub mode: AccessMode,
+    /// Sent to blocked followers, nothing is sent when empty.
+    #[serde(default)]
+    pub blocked_reply: String,
+    /// Sent to followers missing from the allowlist, nothing is sent when empty.
+    #[serde(default = "default_not_allowed_reply")]
+    pub not_allowed_reply: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessMode {
+    /// Everybody except blocked followers.
+    #[default]
+    Open,
+    /// Only followers on the allowlist.
+    Allowlist,
+}
+
+impl AccessMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccessMode::Open => "open",
+            AccessMode::Allowlist => "allowlist",
+        }
+    }
+
+    pub fn parse(mode: &str) -> Option<AccessMode> {
+        match mode {
+            "open" => Some(AccessMode::Open),
+            "allowlist" => Some(AccessMode::Allowlist),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Access {
+            mode: AccessMode::default(),
+            blocked_reply: String::new(),
+            not_allowed_reply: default_not_allowed_reply(),
+        }
+    }
+}
+
+fn default_not_allowed_reply() -> String {
+    "内测中，暂未开放，敬请期待。".to_string()
+}
+
+/// Checks what followers send and what the model answers before it is published.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Moderation {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Sent instead of a flagged message or answer.
+    #[serde(default = "default_blocked_reply")]
+    pub blocked_reply: String,
+    #[serde(default)]
+    pub words: Vec<String>,
+    /// A file in `./config/` with one sensitive word per line.
+    pub words_file: Option<String>,
+    /// An OpenAI compatible `/v1/moderations` endpoint, asked after the word list.
+    pub api: Option<ModerationApi>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModerationApi {
+    pub url: String,
+    /// Defaults to `chat_gpt_config.api`.
+    pub api_key: Option<String>,
+}
+
+impl Default for Moderation {
+    fn default() -> Self {
+        Moderation {
+            enabled: false,
+            blocked_reply: default_blocked_reply(),
+            words: vec![],
+            words_file: None,
+            api: None,
+        }
+    }
+}
+
+impl Moderation {
+    pub fn words_path(&self) -> Option<String> {
+        self.words_file
+            .as_ref()
+            .map(|name| format!("{}{}", CURRENT_DIR, name))
+    }
+}
+
+fn default_blocked_reply() -> String {
+    "这个话题暂时不能聊，换一个吧。".to_string()
+}
+
+/// What `/readyz` checks besides the database and the cache.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Health {
+    /// A cheap authenticated GET such as `https://api.openai.com/v1/models`.
+    pub provider_url: Option<String>,
+    /// How long each check may take before it counts as failed.
+    #[serde(default = "default_health_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            provider_url: None,
+            timeout_ms: default_health_timeout_ms(),
+        }
+    }
+}
+
+impl Health {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+fn default_health_timeout_ms() -> u64 {
+    2000
+}
+
+/// Retrieval over documents ingested with `the-world ingest`. The chunks
+/// closest to a message are put into the prompt for the model to cite.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Knowledge {
+    /// The knowledge base of the top-level account, none when unset.
+    pub base: Option<String>,
+    /// An OpenAI compatible `/v1/embeddings` endpoint.
+    #[serde(default = "default_embeddings_url")]
+    pub url: String,
+    /// Defaults to `chat_gpt_config.api`.
+    pub api_key: Option<String>,
+    #[serde(default = "default_embedding_model")]
+    pub model: String,
+    /// Chunks are cut at paragraph breaks below this many characters.
+    #[serde(default = "default_chunk_chars")]
+    pub chunk_chars: usize,
+    /// Characters repeated between chunks cut inside a paragraph.
+    #[serde(default = "default_chunk_overlap")]
+    pub chunk_overlap: usize,
+    /// How many chunks go into the prompt at most.
+    #[serde(default = "default_top_k")]
+    pub top_k: usize,
+    /// Chunks less similar to the message than this are left out.
+    #[serde(default = "default_min_score")]
+    pub min_score: f32,
+}
+
+impl Default for Knowledge {
+    fn default() -> Self {
+        Knowledge {
+            base: None,
+            url: default_embeddings_url(),
+            api_key: None,
+            model: default_embedding_model(),
+            chunk_chars: default_chunk_chars(),
+            chunk_overlap: default_chunk_overlap(),
+            top_k: default_top_k(),
+            min_score: default_min_score(),
+        }
+    }
+}
+
+fn default_embeddings_url() -> String {
+    "https://api.openai.com/v1/embeddings".to_string()
+}
+
+fn default_embedding_model() -> String {
+    "text-embedding-ada-002".to_string()
+}
+
+fn default_chunk_chars() -> usize {
+    800
+}
+
+fn default_chunk_overlap() -> usize {
+    100
+}
+
+fn default_top_k() -> usize {
+    3
+}
+
+fn default_min_score() -> f32 {
+    0.5
+}
+
+/// Functions the chat-completions models may call while answering. None is
+/// offered unless listed in `enabled`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Tools {
+    /// Any of `current_time`, `calculator`, `knowledge_search` and `http_get`.
+    #[serde(default)]
+    pub enabled: Vec<String>,
+    /// Model requests per answer at most, the last one is offered no tools.
+    #[serde(default = "default_max_rounds")]
+    pub max_rounds: usize,
+    /// URLs `http_get` may fetch below: same scheme, host and port, and a path
+    /// starting with the same segments.
+    #[serde(default)]
+    pub http_allowlist: Vec<String>,
+    #[serde(default = "default_http_timeout_ms")]
+    pub http_timeout_ms: u64,
+    /// The time zone `current_time` answers in.
+    #[serde(default = "default_utc_offset_hours")]
+    pub utc_offset_hours: i32,
+}
+
+impl Default for Tools {
+    fn default() -> Self {
+        Tools {
+            enabled: vec![],
+            max_rounds: default_max_rounds(),
+            http_allowlist: vec![],
+            http_timeout_ms: default_http_timeout_ms(),
+            utc_offset_hours: default_utc_offset_hours(),
+        }
+    }
+}
+
+/// `/draw`, answered with a picture from an OpenAI compatible
+/// `/v1/images/generations` endpoint.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Images {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_images_url")]
+    pub url: String,
+    /// Defaults to `chat_gpt_config.api`.
+    pub api_key: Option<String>,
+    #[serde(default = "default_image_model")]
+    pub model: String,
+    #[serde(default = "default_image_size")]
+    pub size: String,
+    /// Sent when no picture came out of it.
+    #[serde(default = "default_draw_failed_reply")]
+    pub failed_reply: String,
+}
+
+impl Default for Images {
+    fn default() -> Self {
+        Images {
+            enabled: false,
+            url: default_images_url(),
+            api_key: None,
+            model: default_image_model(),
+            size: default_image_size(),
+            failed_reply: default_draw_failed_reply(),
+        }
+    }
+}
+
+fn default_images_url() -> String {
+    "https://api.openai.com/v1/images/generations".to_string()
+}
+
+fn default_image_model() -> String {
+    "dall-e-3".to_string()
+}
+
+fn default_image_size() -> String {
+    "1024x1024".to_string()
+}
+
+fn default_draw_failed_reply() -> String {
+    "这幅画没画出来，稍后再试试吧。".to_string()
+}
+
+/// Answers read out for users who sent `/voice on`, through an OpenAI
+/// compatible `/v1/audio/speech` endpoint.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Speech {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_speech_url")]
+    pub url: String,
+    /// Defaults to `chat_gpt_config.api`.
+    pub api_key: Option<String>,
+    #[serde(default = "default_speech_model")]
+    pub model: String,
+    #[serde(default = "default_speech_voice")]
+    pub voice: String,
+    /// Longer answers stay text, WeChat plays 60 seconds of voice at most.
+    #[serde(default = "default_speech_max_chars")]
+    pub max_chars: usize,
+}
+
+impl Default for Speech {
+    fn default() -> Self {
+        Speech {
+            enabled: false,
+            url: default_speech_url(),
+            api_key: None,
+            model: default_speech_model(),
+            voice: default_speech_voice(),
+            max_chars: default_speech_max_chars(),
+        }
+    }
+}
+
+fn default_speech_url() -> String {
+    "https://api.openai.com/v1/audio/speech".to_string()
+}
+
+fn default_speech_model() -> String {
+    "tts-1".to_string()
+}
+
+fn default_speech_voice() -> String {
+    "alloy".to_string()
+}
+
+fn default_speech_max_chars() -> usize {
+    200
+}
+
+/// Followers' nickname and language from WeChat's `user/info`, for persona
+/// templates and the admin API.
+#[derive(Debug, Deserialize, Clone)]
+pub struct UserInfo {
+    #[serde(default)]
+    pub enabled: bool,
+    /// How long a fetched profile is used before it is fetched again.
+    #[serde(default = "default_user_info_ttl_secs")]
+    pub ttl_secs: u64,
+    /// `{nickname}` for followers WeChat gives no nickname for.
+    #[serde(default = "default_nickname_fallback")]
+    pub nickname_fallback: String,
+}
+
+impl Default for UserInfo {
+    fn default() -> Self {
+        UserInfo {
+            enabled: false,
+            ttl_secs: default_user_info_ttl_secs(),
+            nickname_fallback: default_nickname_fallback(),
+        }
+    }
+}
+
+fn default_user_info_ttl_secs() -> u64 {
+    86400
+}
+
+fn default_nickname_fallback() -> String {
+    "朋友".to_string()
+}
+
+fn default_max_rounds() -> usize {
+    4
+}
+
+fn default_http_timeout_ms() -> u64 {
+    3000
+}
+
+/// What the provider charges, used to put a cost on every turn.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Pricing {
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    /// Offset from UTC of the day boundaries used for daily/monthly spend.
+    #[serde(default = "default_utc_offset_hours")]
+    pub utc_offset_hours: i32,
+    #[serde(default)]
+    pub models: HashMap<String, ModelPrice>,
+}
+
+/// Prices per 1000 tokens.
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl Default for Pricing {
+    fn default() -> Self {
+        Pricing {
+            currency: default_currency(),
+            utc_offset_hours: default_utc_offset_hours(),
+            models: HashMap::new(),
+        }
+    }
+}
+
+impl Pricing {
+    /// Looks the model up by exact name first, then by the longest configured
+    /// prefix, so `gpt-3.5-turbo` also prices `gpt-3.5-turbo-0301`.
+    pub fn price(&self, model: &str) -> Option<&ModelPrice> {
+        self.models.get(model).or_else(|| {
+            self.models
+                .iter()
+                .filter(|(name, _)| model.starts_with(name.as_str()))
+                .max_by_key(|(name, _)| name.len())
+                .map(|(_, price)| price)
+        })
+    }
+
+    pub fn cost(&self, model: &str, usage: &TokenUsage) -> f64 {
+        match self.price(model) {
+            Some(price) => {
+                (usage.prompt_tokens as f64 * price.prompt
+                    + usage.completion_tokens as f64 * price.completion)
+                    / 1000.0
+            }
+            None => 0.0,
+        }
+    }
+}
+
+fn default_currency() -> String {
+    "USD".to_string()
+}
+
+fn default_utc_offset_hours() -> i32 {
+    8
+}
+
+const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1/";
 const CURRENT_DIR: &str = "./config/";
 const SEETING_NAME: &str = "Settings.toml";
+/// `WECHATGPT__CHAT_GPT_CONFIG__API` overrides `chat_gpt_config.api`.
+const ENV_PREFIX: &str = "WECHATGPT";
+const ENV_SEPARATOR: &str = "__";
+/// Settings that can be read from the file named by `<key>_file` instead,
+/// for secrets mounted by the orchestrator.
+const SECRET_KEYS: &[&str] = &[
+    "wechat_config.app_secret",
+    "wechat_config.token",
+    "wechat_config.encoding_aes_key",
+    "chat_gpt_config.api",
+    "database.url",
+    "database.password",
+    "cache.redis_url",
+    "admin.token",
+    "moderation.api.api_key",
+    "wecom.secret",
+    "wecom.token",
+    "wecom.encoding_aes_key",
+    "web.token",
+    "knowledge.api_key",
+    "images.api_key",
+    "speech.api_key",
+];
+/// The same for each of `[[accounts]]`.
+const ACCOUNT_SECRET_KEYS: &[&str] = &[
+    "wechat_config.app_secret",
+    "wechat_config.token",
+    "wechat_config.encoding_aes_key",
+    "chat_gpt_config.api",
+];
+
+impl Settings {
+    /// Reads `./config/Settings.toml`, then the file given with `--config`,
+    /// then `WECHATGPT__*` environment variables, each overriding the one
+    /// before. The default file may be missing when `--config` is given.
+    pub fn load(path: Option<&str>) -> Result<Self, InvalidSettings> {
+        Settings::load_files(&Settings::paths(path), path.is_none())
+    }
+
+    /// Layers `files` and the environment; the first file is optional
+    /// unless `default_required`.
+    fn load_files(files: &[String], default_required: bool) -> Result<Self, InvalidSettings> {
+        let mut builder = Config::builder();
+        for (i, file) in files.iter().enumerate() {
+            builder = builder
+                .add_source(config::File::with_name(file).required(default_required || i > 0));
+        }
+        builder = builder.add_source(
+            Environment::with_prefix(ENV_PREFIX)
+                .prefix_separator(ENV_SEPARATOR)
+                .separator(ENV_SEPARATOR)
+                .try_parsing(true),
+        );
+
+        let mut problems = vec![];
+        let builder = read_secret_files(&builder.clone().build()?, builder, &mut problems);
+        Settings::from_config(&builder.build()?, problems)
+    }
+
+    /// The config files read by [`Settings::load`], in order.
+    pub fn paths(path: Option<&str>) -> Vec<String> {
+        let mut paths = vec![format!("{}{}", CURRENT_DIR, SEETING_NAME)];
+        paths.extend(path.map(str::to_string));
+        paths
+    }
+
+    fn from_config(config: &Config, mut problems: Vec<String>) -> Result<Self, InvalidSettings> {
+        let log = required(config, "log", &mut problems);
+        let server: Option<Server> = required(config, "server", &mut problems);
+        let wechat_config: Option<WechatConfig> = required(config, "wechat_config", &mut problems);
+        let database: Option<Database> = required(config, "database", &mut problems);
+        let chat_gpt_config: Option<ChatGptConfig> =
+            required(config, "chat_gpt_config", &mut problems);
+        let limits: Limits = optional(config, "limits", &mut problems);
+        let health: Health = optional(config, "health", &mut problems);
+
+        // what deserializing cannot catch, checked on every section that parsed
+        if let Some(server) = &server {
+            if server.port == 0 || server.port > u16::MAX as u32 {
+                problems.push(format!("server.port {} is not a port", server.port));
+            }
+        }
+        if let Some(wechat_config) = &wechat_config {
+            check_wechat_config("wechat_config", wechat_config, &mut problems);
+        }
+        if chat_gpt_config.as_ref().is_some_and(|c| c.api.is_empty()) {
+            problems.push("chat_gpt_config.api is empty".to_string());
+        }
+        if let Some(database) = &database {
+            if database.max_connections == 0 {
+                problems.push("database.max_connections must be at least 1".to_string());
+            }
+            if database.url.is_none() && database.host.is_empty() {
+                problems.push("database needs a url or a host".to_string());
+            }
+        }
+        check_limits("limits", &limits, &mut problems);
+        let accounts: Vec<Account> = optional(config, "accounts", &mut problems);
+        for (i, account) in accounts.iter().enumerate() {
+            let valid_name = !account.name.is_empty()
+                && account
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid_name || RESERVED_ACCOUNTS.contains(&account.name.as_str()) {
+                problems.push(format!(
+                    "accounts[{}].name {:?} must be letters, digits, - and _ and not one of {}",
+                    i,
+                    account.name,
+                    RESERVED_ACCOUNTS.join(", ")
+                ));
+            }
+            if accounts[..i].iter().any(|other| other.name == account.name) {
+                problems.push(format!("accounts[{}].name {} is taken", i, account.name));
+            }
+            check_wechat_config(
+                &format!("accounts[{}].wechat_config", i),
+                &account.wechat_config,
+                &mut problems,
+            );
+            if let Some(limits) = &account.limits {
+                check_limits(&format!("accounts[{}].limits", i), limits, &mut problems);
+            }
+        }
+        let wecom: Option<WecomConfig> = optional(config, "wecom", &mut problems);
+        if let Some(wecom) = &wecom {
+            if wecom.token.is_empty() {
+                problems.push("wecom.token is empty".to_string());
+            }
+            if wecom.encoding_aes_key.len() != 43 {
+                problems.push("wecom.encoding_aes_key must be 43 characters".to_string());
+            }
+        }
+        let web = optional(config, "web", &mut problems);
+        let knowledge: Knowledge = optional(config, "knowledge", &mut problems);
+        if knowledge.chunk_chars == 0 || knowledge.chunk_overlap >= knowledge.chunk_chars {
+            problems.push(
+                "knowledge.chunk_chars must be above 0 and above knowledge.chunk_overlap"
+                    .to_string(),
+            );
+        }
+        if knowledge.top_k == 0 {
+            problems.push("knowledge.top_k must be at least 1".to_string());
+        }
+        let tools: Tools = optional(config, "tools", &mut problems);
+        if tools.max_rounds < 2 {
+            problems.push("tools.max_rounds must be at least 2".to_string());
+        }
+        if tools.enabled.iter().any(|name| name == "http_get") && tools.http_allowlist.is_empty() {
+            problems.push("tools.http_get needs tools.http_allowlist".to_string());
+        }
+        for prefix in &tools.http_allowlist {
+            match url::Url::parse(prefix) {
+                Ok(url)
+                    if matches!(url.scheme(), "http" | "https")
+                        && url.host().is_some()
+                        && url.username().is_empty()
+                        && url.password().is_none()
+                        && url.query().is_none()
+                        && url.fragment().is_none() => {}
+                _ => problems.push(format!(
+                    "tools.http_allowlist entry {} must be an http(s) URL without credentials, query or fragment",
+                    prefix
+                )),
+            }
+        }
+        let images = optional(config, "images", &mut problems);
+        let speech: Speech = optional(config, "speech", &mut problems);
+        if speech.max_chars == 0 {
+            problems.push("speech.max_chars must be at least 1".to_string());
+        }
+        let user_info: UserInfo = optional(config, "user_info", &mut problems);
+        if user_info.ttl_secs == 0 {
+            problems.push("user_info.ttl_secs must be at least 1".to_string());
+        }
+        if health.timeout_ms == 0 {
+            problems.push("health.timeout_ms must be at least 1".to_string());
+        }
+        let personas: BTreeMap<String, String> = optional(config, "personas", &mut problems);
+        for (name, prompt) in &personas {
+            if prompt.trim().is_empty() {
+                problems.push(format!("personas.{} is empty", name));
+            }
+        }
+
+        let cache: CacheConfig = optional(config, "cache", &mut problems);
+        if cache.max_entries == 0 {
+            problems.push("cache.max_entries must be at least 1".to_string());
+        }
+        if cache.cleanup_interval_secs == 0 {
+            problems.push("cache.cleanup_interval_secs must be at least 1".to_string());
+        }
+        let pricing = optional(config, "pricing", &mut problems);
+        let admin = optional(config, "admin", &mut problems);
+        let moderation = optional(config, "moderation", &mut problems);
+        let access = optional(config, "access", &mut problems);
+        let (Some(log), Some(server), Some(wechat_config), Some(database), Some(chat_gpt_config)) =
+            (log, server, wechat_config, database, chat_gpt_config)
+        else {
+            return Err(InvalidSettings(problems));
+        };
+        if !problems.is_empty() {
+            return Err(InvalidSettings(problems));
+        }
+        Ok(Settings {
+            log,
+            server,
+            wechat_config,
+            database,
+            chat_gpt_config,
+            cache,
+            pricing,
+            limits,
+            admin,
+            moderation,
+            access,
+            health,
+            accounts,
+            wecom,
+            web,
+            knowledge,
+            tools,
+            images,
+            speech,
+            user_info,
+            personas,
+        })
+    }
+}
 
+fn check_wechat_config(key: &str, config: &WechatConfig, problems: &mut Vec<String>) {
+    if config.token.is_empty() {
+        problems.push(format!("{}.token is empty", key));
+    }
+    if config
+        .encoding_aes_key
+        .as_ref()
+        .is_some_and(|key| key.len() != 43)
+    {
+        problems.push(format!("{}.encoding_aes_key must be 43 characters", key));
+    }
+}
+
+fn check_limits(key: &str, limits: &Limits, problems: &mut Vec<String>) {
+    if !limits.tiers.is_empty() && !limits.tiers.contains_key(&limits.default_tier) {
+        problems.push(format!(
+            "{}.default_tier {} is not one of {}.tiers",
+            key, limits.default_tier, key
+        ));
+    }
+}
+
+/// Overrides every secret that has a `<key>_file` with the trimmed content
+/// of that file.
+fn read_secret_files(
+    config: &Config,
+    mut builder: ConfigBuilder<DefaultState>,
+    problems: &mut Vec<String>,
+) -> ConfigBuilder<DefaultState> {
+    let accounts = config.get_array("accounts").map(|a| a.len()).unwrap_or(0);
+    let account_keys = (0..accounts).flat_map(|i| {
+        ACCOUNT_SECRET_KEYS
+            .iter()
+            .map(move |key| format!("accounts[{}].{}", i, key))
+    });
+    for key in SECRET_KEYS
+        .iter()
+        .map(|key| key.to_string())
+        .chain(account_keys)
+    {
+        let file_key = format!("{}_file", key);
+        let path = match config.get_string(&file_key) {
+            Ok(path) => path,
+            Err(ConfigError::NotFound(_)) => continue,
+            Err(e) => {
+                problems.push(e.to_string());
+                continue;
+            }
+        };
+        match fs::read_to_string(&path) {
+            Ok(secret) => {
+                builder = builder
+                    .set_override(key.as_str(), secret.trim())
+                    .expect("secret keys are valid paths");
+            }
+            Err(e) => problems.push(format!("{}: cannot read {}: {}", file_key, path, e)),
+        }
+    }
+    builder
+}
+
+fn required<T: DeserializeOwned>(
+    config: &Config,
+    key: &str,
+    problems: &mut Vec<String>,
+) -> Option<T> {
+    match config.get(key) {
+        Ok(section) => Some(section),
+        Err(ConfigError::NotFound(_)) => {
+            problems.push(format!("[{}] is missing", key));
+            None
+        }
+        Err(e) => {
+            problems.push(e.to_string());
+            None
+        }
+    }
+}
+
+fn optional<T: DeserializeOwned + Default>(
+    config: &Config,
+    key: &str,
+    problems: &mut Vec<String>,
+) -> T {
+    match config.get(key) {
+        Ok(section) => section,
+        Err(ConfigError::NotFound(_)) => T::default(),
+        Err(e) => {
+            problems.push(e.to_string());
+            T::default()
+        }
+    }
+}
+
+/// The smallest settings that load, for tests that must not depend on
+/// `./config/Settings.toml`.
+#[cfg(test)]
+const TEST_SETTINGS: &str = r#"
+[log]
+level = "warn"
+
+[server]
+ip = "0.0.0.0"
+port = 80
+
+[wechat_config]
+app_id = "app"
+app_secret = "secret"
+token = "token"
+
+[database]
+url = "sqlite::memory:"
+max_connections = 1
+
+[chat_gpt_config]
+api = "sk-test"
+model = "gpt-3.5-turbo"
+
+[pricing.models."gpt-3.5-turbo"]
+prompt = 0.0015
+completion = 0.002
+"#;
+
+#[cfg(test)]
 impl Settings {
-    pub fn new() -> Result<Self, ConfigError> {
-        let s = Config::builder()
-            .add_source(config::File::with_name(
-                format!("{}{}", CURRENT_DIR, SEETING_NAME).as_str(),
+    pub fn for_tests() -> Settings {
+        let config = Config::builder()
+            .add_source(config::File::from_str(
+                TEST_SETTINGS,
+                config::FileFormat::Toml,
             ))
-            .build()?;
+            .build()
+            .unwrap();
+        Settings::from_config(&config, vec![]).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loads `path` over the test settings written to `dir/base.toml`.
+    fn load_over_fixture(
+        dir: &std::path::Path,
+        path: &std::path::Path,
+    ) -> Result<Settings, InvalidSettings> {
+        Settings::load_files(
+            &[
+                dir.join("base.toml").to_str().unwrap().to_string(),
+                path.to_str().unwrap().to_string(),
+            ],
+            true,
+        )
+    }
+
+    #[test]
+    fn test_prices_dated_models() {
+        let s = Settings::for_tests();
+        let usage = TokenUsage {
+            prompt_tokens: 1000,
+            completion_tokens: 500,
+            total_tokens: 1500,
+        };
+        assert!((s.pricing.cost("gpt-3.5-turbo-0301", &usage) - 0.0025).abs() < 1e-9);
+        assert_eq!(s.pricing.cost("unknown-model", &usage), 0.0);
+    }
+
+    #[test]
+    fn test_admin_allows() {
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.1".parse().unwrap();
+        assert!(!Admin::default().allows(None, Some(local)));
+
+        let admin = Admin {
+            token: Some("secret".to_string()),
+            allowed_ips: vec![local],
+            ..Admin::default()
+        };
+        assert!(admin.allows(Some("secret"), Some(other)));
+        assert!(admin.allows(None, Some(local)));
+        assert!(!admin.allows(Some("secrets"), Some(other)));
+        assert!(!admin.allows(None, None));
+    }
+
+    #[test]
+    fn test_layers_and_validation() {
+        let dir = std::env::temp_dir().join(format!("wechatgpt-settings-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("base.toml"), TEST_SETTINGS).unwrap();
+        let secret = dir.join("api_key");
+        fs::write(&secret, "sk-from-file\n").unwrap();
+        let path = dir.join("override.toml");
+        fs::write(
+            &path,
+            format!(
+                "[server]\nport = 8080\n[chat_gpt_config]\napi_file = \"{}\"\n",
+                secret.display()
+            ),
+        )
+        .unwrap();
+        let s = load_over_fixture(&dir, &path).unwrap();
+        assert_eq!(s.server.port, 8080);
+        assert_eq!(s.server.ip, "0.0.0.0");
+        assert_eq!(s.chat_gpt_config.api, "sk-from-file");
 
-        s.try_deserialize()
+        fs::write(
+            &path,
+            "[server]\nport = 70000\n[database]\nmax_connections = \"many\"\n[chat_gpt_config]\napi = \"\"\n[cache]\nmax_entries = 0\ncleanup_interval_secs = 0\n[tools]\nhttp_allowlist = [\"http://inventory.internal/api/\", \"inventory.internal/api/\"]\n",
+        )
+        .unwrap();
+        let problems = load_over_fixture(&dir, &path).unwrap_err().0;
+        assert_eq!(problems.len(), 6, "{:?}", problems);
+        assert!(problems
+            .iter()
+            .any(|p| p.contains("entry inventory.internal/api/")));
+        assert!(problems.iter().any(|p| p.contains("cache.max_entries")));
+        assert!(problems
+            .iter()
+            .any(|p| p.contains("cache.cleanup_interval_secs")));
+        assert!(problems.iter().any(|p| p.contains("\"many\"")));
+        assert!(problems.iter().any(|p| p.contains("server.port")));
+        assert!(problems.iter().any(|p| p.contains("chat_gpt_config.api")));
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
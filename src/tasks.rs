@@ -0,0 +1,80 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use tokio::{
+    runtime::{Builder, Runtime},
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_util::task::TaskTracker;
+
+/// Work that has to outlive the request that started it, such as a model
+/// call whose answer a retry from WeChat picks up. Tasks run on a runtime of
+/// their own rather than on an HTTP worker, so they are not dropped with the
+/// workers on shutdown and can be drained instead, and rather than on the
+/// single thread `main` runs on, so they do not queue up behind each other.
+#[derive(Clone)]
+pub struct Tasks {
+    tracker: TaskTracker,
+    runtime: Arc<TaskRuntime>,
+}
+
+/// Shuts the runtime down without waiting for it, which a runtime dropped
+/// from async code would panic on.
+struct TaskRuntime(Option<Runtime>);
+
+impl Drop for TaskRuntime {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.0.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+impl Tasks {
+    pub fn new() -> Tasks {
+        let runtime = Builder::new_multi_thread()
+            .thread_name("tasks")
+            .enable_all()
+            .build()
+            .expect("cannot start the task runtime");
+        Tasks {
+            tracker: TaskTracker::new(),
+            runtime: Arc::new(TaskRuntime(Some(runtime))),
+        }
+    }
+
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let runtime = self.runtime.0.as_ref().unwrap();
+        self.tracker.spawn_on(task, runtime.handle())
+    }
+
+    /// Waits up to `grace` for the running tasks, returning how many are
+    /// still running.
+    pub async fn drain(&self, grace: Duration) -> usize {
+        self.tracker.close();
+        let _ = timeout(grace, self.tracker.wait()).await;
+        self.tracker.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::sleep;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let tasks = Tasks::new();
+        tasks.spawn(sleep(Duration::from_millis(10)));
+        assert_eq!(tasks.drain(Duration::from_secs(1)).await, 0);
+
+        let tasks = Tasks::new();
+        tasks.spawn(sleep(Duration::from_secs(60)));
+        assert_eq!(tasks.drain(Duration::from_millis(10)).await, 1);
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use sha1::{Digest, Sha1};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+use crate::{
+    error::{Error, Result},
+    settings::{Log, LogFormat},
+};
+
+static REDACT_CONTENT: AtomicBool = AtomicBool::new(true);
+
+/// Installs the subscriber for `tracing` and for the `log` macros used
+/// around the code base, which end up in the current span.
+pub fn init(log: &Log) -> Result<()> {
+    REDACT_CONTENT.store(log.redact_content, Ordering::Relaxed);
+
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&log.level))
+        .map_err(|e| Error::TelemetryError(e.to_string()))?;
+    let output = match log.format {
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .with(otlp_layer(log)?)
+        .try_init()
+        .map_err(|e| Error::TelemetryError(e.to_string()))
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_layer<S>(log: &Log) -> Result<Option<impl Layer<S>>>
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry::{trace::TracerProvider as _, KeyValue};
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+
+    let endpoint = match &log.otlp_endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(None),
+    };
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| Error::TelemetryError(e.to_string()))?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            log.service_name.clone(),
+        )]))
+        .build();
+    let tracer = provider.tracer("the-world");
+    opentelemetry::global::set_tracer_provider(provider);
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+#[cfg(not(feature = "otlp"))]
+fn otlp_layer(log: &Log) -> Result<Option<tracing_subscriber::layer::Identity>> {
+    if log.otlp_endpoint.is_some() {
+        return Err(Error::TelemetryError(
+            "log.otlp_endpoint needs a build with the otlp feature".to_string(),
+        ));
+    }
+    Ok(None)
+}
+
+/// Sends the spans still buffered for the collector.
+pub fn shutdown() {
+    #[cfg(feature = "otlp")]
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Identifies a follower in logs without writing down the openid.
+pub fn hash_openid(openid: &str) -> String {
+    let digest = Sha1::digest(openid.as_bytes());
+    hex::encode(&digest[..6])
+}
+
+/// Logs what followers and the model say only when `log.redact_content` is off.
+pub fn redact<T: Display>(content: T) -> Redacted<T> {
+    Redacted(content)
+}
+
+pub struct Redacted<T>(T);
+
+impl<T: Display> Display for Redacted<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        if REDACT_CONTENT.load(Ordering::Relaxed) {
+            write!(f, "<redacted>")
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_openid() {
+        let hashed = hash_openid("oABCD1234");
+        assert_eq!(hashed.len(), 12);
+        assert_eq!(hashed, hash_openid("oABCD1234"));
+        assert_ne!(hashed, hash_openid("oABCD1235"));
+        assert_eq!(redact("secret").to_string(), "<redacted>");
+    }
+}
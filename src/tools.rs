@@ -0,0 +1,594 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use chrono::{FixedOffset, Utc};
+use log::warn;
+use reqwest::{redirect::Policy, Client, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    database::ConversationStore,
+    error::{Error, Result},
+    knowledge::{self, ChunkCache},
+    metrics::METRICS,
+    settings::{Knowledge, Tools},
+};
+
+/// What a tool may hand back to the model at most.
+const MAX_RESULT_CHARS: usize = 4000;
+/// Longer expressions are refused rather than parsed.
+const MAX_EXPRESSION_CHARS: usize = 200;
+
+/// What the answer being worked on lends the tools.
+pub struct ToolContext<'a> {
+    pub store: &'a dyn ConversationStore,
+    pub chunks: &'a ChunkCache,
+    pub client: &'a Client,
+    pub knowledge: &'a Knowledge,
+    pub api_key: &'a str,
+    pub knowledge_base: Option<&'a str>,
+}
+
+/// A function the model may call while answering. Whatever it returns, or
+/// the error it fails with, goes back to the model as text.
+#[async_trait]
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    /// The JSON schema of the arguments.
+    fn parameters(&self) -> Value;
+
+    /// Whether the model is offered the tool for this answer.
+    fn available(&self, _context: &ToolContext<'_>) -> bool {
+        true
+    }
+
+    async fn execute(&self, context: &ToolContext<'_>, arguments: Value) -> Result<String>;
+}
+
+/// The tools enabled in `[tools]`.
+pub struct ToolRegistry {
+    max_rounds: usize,
+    tools: Vec<Box<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn new(config: &Tools) -> Result<ToolRegistry> {
+        let mut tools: Vec<Box<dyn Tool>> = vec![];
+        for name in &config.enabled {
+            let tool: Box<dyn Tool> = match name.as_str() {
+                "current_time" => Box::new(CurrentTime::new(config.utc_offset_hours)?),
+                "calculator" => Box::new(Calculator),
+                "knowledge_search" => Box::new(KnowledgeSearch),
+                "http_get" => Box::new(HttpGet::new(config)?),
+                other => return Err(Error::ArgumentError(format!("unknown tool {}", other))),
+            };
+            tools.push(tool);
+        }
+        Ok(ToolRegistry {
+            max_rounds: config.max_rounds,
+            tools,
+        })
+    }
+
+    /// The tools available for one answer.
+    pub fn with_context<'a>(&'a self, context: ToolContext<'a>) -> Toolbox<'a> {
+        Toolbox {
+            max_rounds: self.max_rounds,
+            tools: self
+                .tools
+                .iter()
+                .map(Box::as_ref)
+                .filter(|tool| tool.available(&context))
+                .collect(),
+            context,
+        }
+    }
+}
+
+/// The tools at hand while answering one message.
+pub struct Toolbox<'a> {
+    /// Model requests per answer at most, the last one is offered no tools.
+    pub max_rounds: usize,
+    tools: Vec<&'a dyn Tool>,
+    context: ToolContext<'a>,
+}
+
+impl Toolbox<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// The `tools` of a chat-completions request.
+    pub fn definitions(&self) -> Vec<Value> {
+        self.tools
+            .iter()
+            .map(|tool| {
+                json!({
+                    "type": "function",
+                    "function": {
+                        "name": tool.name(),
+                        "description": tool.description(),
+                        "parameters": tool.parameters(),
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// Runs the tool the model asked for with its JSON `arguments`. A
+    /// failure is told to the model, which may try otherwise, rather than
+    /// costing the answer.
+    pub async fn call(&self, name: &str, arguments: &str) -> String {
+        let tool = match self.tools.iter().find(|tool| tool.name() == name) {
+            Some(tool) => tool,
+            None => return format!("error: there is no tool {}", name),
+        };
+        let result = match serde_json::from_str(arguments) {
+            Ok(arguments) => tool.execute(&self.context, arguments).await,
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok(text) => {
+                METRICS.tool_calls.with_label_values(&[name, "ok"]).inc();
+                truncate(text)
+            }
+            Err(e) => {
+                warn!("tool {} failed: {}", name, e);
+                METRICS.tool_calls.with_label_values(&[name, "error"]).inc();
+                format!("error: {}", e)
+            }
+        }
+    }
+}
+
+fn truncate(text: String) -> String {
+    match text.char_indices().nth(MAX_RESULT_CHARS) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
+    }
+}
+
+struct CurrentTime {
+    offset: FixedOffset,
+}
+
+impl CurrentTime {
+    fn new(utc_offset_hours: i32) -> Result<CurrentTime> {
+        let offset = FixedOffset::east_opt(utc_offset_hours * 3600).ok_or_else(|| {
+            Error::ArgumentError(format!(
+                "tools.utc_offset_hours {} is out of range",
+                utc_offset_hours
+            ))
+        })?;
+        Ok(CurrentTime { offset })
+    }
+}
+
+#[async_trait]
+impl Tool for CurrentTime {
+    fn name(&self) -> &'static str {
+        "current_time"
+    }
+
+    fn description(&self) -> &'static str {
+        "The current date, weekday and time."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({ "type": "object", "properties": {} })
+    }
+
+    async fn execute(&self, _context: &ToolContext<'_>, _arguments: Value) -> Result<String> {
+        let now = Utc::now().with_timezone(&self.offset);
+        Ok(now.format("%Y-%m-%d %H:%M:%S %A (UTC%:z)").to_string())
+    }
+}
+
+struct Calculator;
+
+#[derive(Deserialize)]
+struct CalculatorArguments {
+    expression: String,
+}
+
+#[async_trait]
+impl Tool for Calculator {
+    fn name(&self) -> &'static str {
+        "calculator"
+    }
+
+    fn description(&self) -> &'static str {
+        "Evaluates an arithmetic expression with + - * / % ^ and parentheses."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "expression": { "type": "string", "description": "e.g. (3.5 + 2) * 4 ^ 2" }
+            },
+            "required": ["expression"]
+        })
+    }
+
+    async fn execute(&self, _context: &ToolContext<'_>, arguments: Value) -> Result<String> {
+        let arguments: CalculatorArguments = serde_json::from_value(arguments)?;
+        calculate(&arguments.expression).map(|value| value.to_string())
+    }
+}
+
+/// Evaluates `+ - * / % ^` and parentheses over decimal numbers. `^` is
+/// right associative and binds tighter than a leading minus.
+fn calculate(expression: &str) -> Result<f64> {
+    if expression.chars().count() > MAX_EXPRESSION_CHARS {
+        return Err(Error::ArgumentError(
+            "the expression is too long".to_string(),
+        ));
+    }
+    let mut parser = Parser {
+        chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+        position: 0,
+    };
+    let value = parser.sum()?;
+    if parser.position < parser.chars.len() {
+        return Err(parser.unexpected());
+    }
+    if !value.is_finite() {
+        return Err(Error::ArgumentError(format!("{} is not a number", value)));
+    }
+    Ok(value)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.chars.get(self.position) == Some(&c);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn sum(&mut self) -> Result<f64> {
+        let mut value = self.product()?;
+        loop {
+            if self.eat('+') {
+                value += self.product()?;
+            } else if self.eat('-') {
+                value -= self.product()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<f64> {
+        let mut value = self.power()?;
+        loop {
+            if self.eat('*') {
+                value *= self.power()?;
+            } else if self.eat('/') {
+                value /= self.power()?;
+            } else if self.eat('%') {
+                value %= self.power()?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn power(&mut self) -> Result<f64> {
+        if self.eat('-') {
+            return Ok(-self.power()?);
+        }
+        let base = self.atom()?;
+        if self.eat('^') {
+            return Ok(base.powf(self.power()?));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<f64> {
+        if self.eat('(') {
+            let value = self.sum()?;
+            if !self.eat(')') {
+                return Err(self.unexpected());
+            }
+            return Ok(value);
+        }
+        let start = self.position;
+        while self
+            .chars
+            .get(self.position)
+            .is_some_and(|c| c.is_ascii_digit() || *c == '.')
+        {
+            self.position += 1;
+        }
+        let number: String = self.chars[start..self.position].iter().collect();
+        number.parse().map_err(|_| {
+            self.position = start;
+            self.unexpected()
+        })
+    }
+
+    fn unexpected(&self) -> Error {
+        Error::ArgumentError(match self.chars.get(self.position) {
+            Some(c) => format!("unexpected {} at {}", c, self.position),
+            None => "unexpected end of the expression".to_string(),
+        })
+    }
+}
+
+/// Searches the knowledge base of the account, for questions the passages
+/// put into the prompt did not cover.
+struct KnowledgeSearch;
+
+#[derive(Deserialize)]
+struct KnowledgeSearchArguments {
+    query: String,
+}
+
+#[async_trait]
+impl Tool for KnowledgeSearch {
+    fn name(&self) -> &'static str {
+        "knowledge_search"
+    }
+
+    fn description(&self) -> &'static str {
+        "Searches the documents of this account and returns the closest passages."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": { "query": { "type": "string" } },
+            "required": ["query"]
+        })
+    }
+
+    fn available(&self, context: &ToolContext<'_>) -> bool {
+        context.knowledge_base.is_some()
+    }
+
+    async fn execute(&self, context: &ToolContext<'_>, arguments: Value) -> Result<String> {
+        let arguments: KnowledgeSearchArguments = serde_json::from_value(arguments)?;
+        let base = context
+            .knowledge_base
+            .ok_or_else(|| Error::ArgumentError("no knowledge base".to_string()))?;
+        let passages = knowledge::retrieve(
+            context.store,
+            context.chunks,
+            context.client,
+            context.knowledge,
+            context.api_key,
+            base,
+            &arguments.query,
+        )
+        .await?;
+        if passages.is_empty() {
+            return Ok("nothing found".to_string());
+        }
+        Ok(passages
+            .iter()
+            .map(|passage| format!("（{}）\n{}", passage.source, passage.content))
+            .collect::<Vec<_>>()
+            .join("\n\n"))
+    }
+}
+
+/// What `http_get` reads of a body at most, the rest would only fill the
+/// prompt.
+const MAX_BODY_BYTES: usize = 16 * 1024;
+
+/// GETs internal endpoints below `tools.http_allowlist`. Redirects are not
+/// followed, they could lead anywhere.
+struct HttpGet {
+    client: Client,
+    allowlist: Vec<Url>,
+}
+
+#[derive(Deserialize)]
+struct HttpGetArguments {
+    url: String,
+}
+
+impl HttpGet {
+    fn new(config: &Tools) -> Result<HttpGet> {
+        Ok(HttpGet {
+            client: Client::builder()
+                .timeout(Duration::from_millis(config.http_timeout_ms))
+                .redirect(Policy::none())
+                .build()?,
+            allowlist: config
+                .http_allowlist
+                .iter()
+                .map(|prefix| parse_url(prefix))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// `url` once normalised, if it has the scheme, host and port of one of
+    /// the allowed prefixes and its path starts with the whole segments of
+    /// that prefix.
+    fn allowed(&self, url: &str) -> Result<Url> {
+        let url = parse_url(url)?;
+        let below = |prefix: &Url| {
+            url.scheme() == prefix.scheme()
+                && url.host_str() == prefix.host_str()
+                && url.port_or_known_default() == prefix.port_or_known_default()
+                && url.username().is_empty()
+                && url.password().is_none()
+                && segments(&url).starts_with(&segments(prefix))
+        };
+        if !self.allowlist.iter().any(below) {
+            return Err(Error::ArgumentError(format!("{} is not allowed", url)));
+        }
+        Ok(url)
+    }
+}
+
+fn parse_url(url: &str) -> Result<Url> {
+    Url::parse(url).map_err(|e| Error::ArgumentError(format!("{}: {}", url, e)))
+}
+
+// without the empty segment after a trailing slash
+fn segments(url: &Url) -> Vec<&str> {
+    url.path_segments()
+        .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+#[async_trait]
+impl Tool for HttpGet {
+    fn name(&self) -> &'static str {
+        "http_get"
+    }
+
+    fn description(&self) -> &'static str {
+        "Fetches an internal URL and returns the status and body."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "url": {
+                    "type": "string",
+                    "description": format!(
+                        "must start with one of {}",
+                        self.allowlist
+                            .iter()
+                            .map(Url::as_str)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                }
+            },
+            "required": ["url"]
+        })
+    }
+
+    async fn execute(&self, _context: &ToolContext<'_>, arguments: Value) -> Result<String> {
+        let arguments: HttpGetArguments = serde_json::from_value(arguments)?;
+        let mut response = self
+            .client
+            .get(self.allowed(&arguments.url)?)
+            .send()
+            .await?;
+        let status = response.status();
+        let mut body = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() >= MAX_BODY_BYTES {
+                body.truncate(MAX_BODY_BYTES);
+                break;
+            }
+        }
+        Ok(format!(
+            "HTTP {}\n{}",
+            status.as_u16(),
+            String::from_utf8_lossy(&body)
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::memory::MemoryStore;
+
+    #[test]
+    fn test_calculate() {
+        assert_eq!(calculate("1 + 2 * 3").unwrap(), 7.0);
+        assert_eq!(calculate("(1 + 2) * 3").unwrap(), 9.0);
+        assert_eq!(calculate("-2 ^ 2").unwrap(), -4.0);
+        assert_eq!(calculate("2 ^ 3 ^ 2").unwrap(), 512.0);
+        assert_eq!(calculate("7 % 4 - 0.5").unwrap(), 2.5);
+        assert!(calculate("1 / 0").is_err());
+        assert!(calculate("(1 + 2").is_err());
+        assert!(calculate("1 + x").is_err());
+        assert!(calculate("").is_err());
+    }
+
+    #[test]
+    fn test_http_get_allowed() {
+        let tool = HttpGet::new(&Tools {
+            http_allowlist: vec!["http://inventory.internal/api/".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(tool
+            .allowed("http://inventory.internal/api/stock?sku=1")
+            .is_ok());
+        assert!(tool
+            .allowed("http://inventory.internal/api/../admin")
+            .is_err());
+        assert!(tool
+            .allowed("http://inventory.internal@evil.example/api/")
+            .is_err());
+        assert!(tool.allowed("not a url").is_err());
+        // the prefix is matched by whole path segments, on the same origin
+        assert!(tool.allowed("http://INVENTORY.internal:80/api").is_ok());
+        assert!(tool
+            .allowed("http://inventory.internal/apiary/stock")
+            .is_err());
+        assert!(tool
+            .allowed("http://inventory.internal.evil.example/api/")
+            .is_err());
+        assert!(tool.allowed("http://inventory.internal:8080/api/").is_err());
+        assert!(tool.allowed("https://inventory.internal/api/").is_err());
+        assert!(tool.allowed("http://user@inventory.internal/api/").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_call() {
+        let registry = ToolRegistry::new(&Tools {
+            enabled: vec!["calculator".to_string(), "knowledge_search".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        let store = MemoryStore::new(8);
+        let chunks = ChunkCache::default();
+        let client = Client::new();
+        let knowledge = Knowledge::default();
+        let tools = registry.with_context(ToolContext {
+            store: &store,
+            chunks: &chunks,
+            client: &client,
+            knowledge: &knowledge,
+            api_key: "",
+            knowledge_base: None,
+        });
+
+        // without a knowledge base there is nothing to search
+        let names: Vec<Value> = tools
+            .definitions()
+            .into_iter()
+            .map(|definition| definition["function"]["name"].clone())
+            .collect();
+        assert_eq!(names, vec![json!("calculator")]);
+
+        assert_eq!(
+            tools.call("calculator", r#"{"expression": "6 * 7"}"#).await,
+            "42"
+        );
+        assert!(tools.call("calculator", "{}").await.starts_with("error: "));
+        assert!(tools
+            .call("knowledge_search", r#"{"query": "x"}"#)
+            .await
+            .starts_with("error: "));
+        assert!(ToolRegistry::new(&Tools {
+            enabled: vec!["shell".to_string()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use tracing::{info_span, Instrument};
+
+use crate::{
+    admin::bearer_token,
+    engine::{BotEngine, IncomingMessage, OutgoingReply},
+    error::{Error, Result},
+    reload::Channel,
+    settings::WEB_ACCOUNT,
+    telemetry::hash_openid,
+    AppState,
+};
+
+/// A message posted to `/web/messages`.
+#[derive(Debug, Deserialize)]
+pub struct WebMessage {
+    pub user_id: String,
+    pub content: String,
+    /// Post the same id again to pick up an answer whose response got lost.
+    /// Ids only need to be unique per `user_id`; the server picks one when
+    /// this is left out.
+    pub msg_id: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebReply {
+    pub msg_id: i64,
+    /// `null` when there is nothing to say, such as for a blocked user or a
+    /// message still being answered.
+    pub reply: Option<String>,
+}
+
+/// The JSON channel: the answer comes back in the response.
+#[post("/web/messages")]
+async fn post_message(
+    req: HttpRequest,
+    message: web::Json<WebMessage>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let start = Instant::now();
+    let allowed = match data
+        .live()
+        .account(WEB_ACCOUNT)
+        .map(|account| account.channel)
+    {
+        Some(Channel::Web(web)) => web.allows(bearer_token(&req)),
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+    if !allowed {
+        return Err(Error::Unauthorized);
+    }
+    let message = message.into_inner();
+    if message.user_id.is_empty() || message.content.trim().is_empty() {
+        return Err(Error::ArgumentError(
+            "user_id and content must not be empty".to_string(),
+        ));
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let incoming = IncomingMessage {
+        account: WEB_ACCOUNT.to_string(),
+        // unique enough for one user
+        msg_id: message.msg_id.unwrap_or(now.as_nanos() as i64),
+        user_id: message.user_id,
+        subscription_id: WEB_ACCOUNT.to_string(),
+        msg_type: "text".to_string(),
+        content: message.content,
+        create_time: now.as_secs() as i64,
+    };
+    let span = info_span!(
+        "message",
+        account = WEB_ACCOUNT,
+        msg_id = incoming.msg_id,
+        openid = %hash_openid(&incoming.user_id),
+        subscription = WEB_ACCOUNT,
+    );
+    let reply = match BotEngine::new(data)
+        .handle(&incoming, start)
+        .instrument(span)
+        .await?
+    {
+        OutgoingReply::Text(reply) | OutgoingReply::Again(reply) => Some(reply),
+        // media is not offered on this channel
+        OutgoingReply::Nothing | OutgoingReply::Media(..) => None,
+    };
+    Ok(HttpResponse::Ok().json(WebReply {
+        msg_id: incoming.msg_id,
+        reply,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::header, http::StatusCode, test, App};
+
+    use super::*;
+    use crate::{database::UserList, settings::WebChannel};
+
+    #[actix_web::test]
+    async fn test_post_message() {
+        let state = AppState::for_tests();
+        let store = state.store.clone();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state.clone()))
+                .service(post_message),
+        )
+        .await;
+        let body = serde_json::json!({ "user_id": "visitor", "content": "你好", "msg_id": 1 });
+
+        let req = test::TestRequest::post()
+            .uri("/web/messages")
+            .set_json(&body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        state.update_live(|live| {
+            live.web = Some(WebChannel {
+                token: Some("secret".to_string()),
+                persona: None,
+                knowledge_base: None,
+            })
+        });
+        let req = test::TestRequest::post()
+            .uri("/web/messages")
+            .set_json(&body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        store
+            .set_keyword_rule(WEB_ACCOUNT, "你好", "hello")
+            .await
+            .unwrap();
+        let req = test::TestRequest::post()
+            .uri("/web/messages")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .set_json(&body)
+            .to_request();
+        let reply: WebReply = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(reply.msg_id, 1);
+        assert_eq!(reply.reply.as_deref(), Some("hello"));
+
+        // anyone can post any user_id, so nobody gets to export or erase one
+        store
+            .set_session_id(WEB_ACCOUNT, "visitor", "s1")
+            .await
+            .unwrap();
+        for content in ["/export", "/forget confirm"] {
+            let req = test::TestRequest::post()
+                .uri("/web/messages")
+                .insert_header((header::AUTHORIZATION, "Bearer secret"))
+                .set_json(serde_json::json!({ "user_id": "visitor", "content": content }))
+                .to_request();
+            let reply: WebReply = test::call_and_read_body_json(&app, req).await;
+            assert!(reply.reply.unwrap().contains("不能导出或删除"));
+        }
+        assert_eq!(
+            store.get_session_id(WEB_ACCOUNT, "visitor").await.unwrap(),
+            "s1"
+        );
+
+        // nor to pass for an admin
+        state.update_live(|live| live.admin.openids = vec!["admin".to_string()]);
+        let req = test::TestRequest::post()
+            .uri("/web/messages")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .set_json(serde_json::json!({ "user_id": "admin", "content": "/block visitor" }))
+            .to_request();
+        let reply: WebReply = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(reply.reply.as_deref(), Some("没有权限执行这个命令。"));
+        assert!(!store
+            .is_in_user_list(UserList::Blocked, WEB_ACCOUNT, "visitor")
+            .await
+            .unwrap());
+    }
+}